@@ -0,0 +1,40 @@
+// pairs clients into rooms and tells every member the addresses and handles
+// of the others, so that they can start a ggrs session without exchanging
+// addresses by hand.
+//
+//     cargo run --bin signaling_server -- --bind 127.0.0.1:3536
+//
+// logs at info level, RUST_LOG=warn keeps it quiet
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+
+use log::info;
+use structopt::StructOpt;
+
+#[path = "../signaling_protocol.rs"]
+mod signaling_protocol;
+#[path = "../signaling_rooms.rs"]
+mod signaling_rooms;
+use signaling_protocol::DEFAULT_SERVER_PORT;
+use signaling_rooms::Server;
+
+#[derive(StructOpt)]
+struct Opt {
+    #[structopt(short, long)]
+    bind: Option<SocketAddr>,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let opt = Opt::from_args();
+    let bind = opt
+        .bind
+        .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], DEFAULT_SERVER_PORT)));
+
+    let socket = UdpSocket::bind(bind)?;
+    socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+    info!("signaling server listening on {}", socket.local_addr()?);
+
+    Server::new(socket).run()?;
+    Ok(())
+}
@@ -0,0 +1,337 @@
+use bevy::core_pipeline::clear_color::ClearColorConfig;
+use bevy::input::mouse::MouseWheel;
+use bevy::render::camera::{ScalingMode, Viewport};
+use bevy::render::view::RenderLayers;
+use bevy::{prelude::*, window::WindowResized};
+
+use crate::controls::{Action, Bindings, ControlsMenu};
+use crate::{aim_direction, GameState, LocalPlayers, MapBounds, MatchEntity, Player};
+
+// nothing is drawn on this layer, the ui camera only renders the ui
+const UI_CAMERA_LAYER: u8 = 31;
+// above the player cameras and the minimap
+const UI_CAMERA_PRIORITY: isize = 100;
+
+// the most of the world a player can see, whatever the window size
+const VIEW_SIZE: Vec2 = Vec2::new(800.0, 600.0);
+// manual zoom can only get closer than the default view
+const ZOOM_STEPS: [f32; 3] = [0.5, 0.75, 1.0];
+const LOOK_AHEAD: f32 = 100.0;
+// how fast the camera catches up with its target, per second
+const CAMERA_EASE: f32 = 6.0;
+// camera shake at full trauma, in world units
+const MAX_SHAKE: f32 = 12.0;
+// trauma lost per second
+const SHAKE_DECAY: f32 = 1.5;
+
+pub struct CameraPlugin;
+
+impl Plugin for CameraPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_startup_system(spawn_ui_camera)
+            .add_system_set(
+                SystemSet::on_enter(GameState::Loading).with_system(spawn_player_cameras),
+            )
+            .add_system(window_resized_event)
+            .add_system(update_viewports)
+            .add_system(zoom_cameras)
+            .add_system_to_stage(CoreStage::PostUpdate, camera_follow);
+    }
+}
+
+// one per local player, follows the tank of that handle
+#[derive(Component)]
+pub struct PlayerCamera {
+    pub handle: usize,
+    // position among the local players, decides the viewport
+    pub index: usize,
+    // index in ZOOM_STEPS
+    pub zoom: usize,
+    // from 0 to 1, explosions nearby add to it
+    pub trauma: f32,
+    // offset of the shake applied last frame
+    shake: Vec2,
+}
+
+#[derive(Component)]
+pub struct UiCamera;
+
+// the ui camera outlives the matches, the menus need it too
+fn spawn_ui_camera(mut commands: Commands) {
+    // a window sized camera on top, so that the ui isn't squeezed into a
+    // single viewport
+    let mut camera = Camera2dBundle::default();
+    camera.camera.priority = UI_CAMERA_PRIORITY;
+    camera.camera_2d.clear_color = ClearColorConfig::None;
+    commands
+        .spawn_bundle(camera)
+        .insert(RenderLayers::layer(UI_CAMERA_LAYER))
+        .insert(UiCamera);
+
+    // clears the window when there is no player camera to do it
+    let mut camera = Camera2dBundle::default();
+    camera.camera.priority = -1;
+    commands
+        .spawn_bundle(camera)
+        .insert(RenderLayers::layer(UI_CAMERA_LAYER))
+        .insert(UiCameraConfig { show_ui: false });
+}
+
+fn spawn_player_cameras(mut commands: Commands, local_players: Res<LocalPlayers>) {
+    // spectators still want to look at someone
+    let handles = if local_players.0.is_empty() {
+        vec![0]
+    } else {
+        local_players.0.clone()
+    };
+
+    for (index, handle) in handles.iter().enumerate() {
+        let mut camera = Camera2dBundle::default();
+        camera.projection.scaling_mode = ScalingMode::WindowSize;
+        camera.transform = Transform::from_xyz(0.0, 0.0, 100.0);
+        camera.camera.priority = index as isize;
+        // the first camera clears the whole window for all of them
+        if index > 0 {
+            camera.camera_2d.clear_color = ClearColorConfig::None;
+        }
+        commands
+            .spawn_bundle(camera)
+            .insert(PlayerCamera {
+                handle: *handle,
+                index,
+                zoom: ZOOM_STEPS.len() - 1,
+                trauma: 0.0,
+                shake: Vec2::ZERO,
+            })
+            .insert(UiCameraConfig { show_ui: false })
+            .insert(MatchEntity);
+    }
+}
+
+fn window_resized_event(
+    mut events: EventReader<WindowResized>,
+    mut window: ResMut<WindowDescriptor>,
+) {
+    for event in events.iter() {
+        window.width = event.width.try_into().unwrap();
+        window.height = event.height.try_into().unwrap();
+    }
+}
+
+// splits the window into a grid with as many cells as players, as square as
+// it gets: halves for two players, quarters for three or four, and so on
+pub fn viewport_rect(index: usize, count: usize, window_size: UVec2) -> (UVec2, UVec2) {
+    let count = count.max(1) as u32;
+    let columns = (count as f32).sqrt().ceil() as u32;
+    let rows = (count + columns - 1) / columns;
+    let size = UVec2::new(window_size.x / columns, window_size.y / rows);
+    let cell = UVec2::new(index as u32 % columns, index as u32 / columns);
+    (cell * size, size)
+}
+
+fn update_viewports(windows: Res<Windows>, mut camera_query: Query<(&mut Camera, &PlayerCamera)>) {
+    let window = match windows.get_primary() {
+        Some(window) => window,
+        None => return,
+    };
+    let window_size = UVec2::new(window.physical_width(), window.physical_height());
+    let count = camera_query.iter().count();
+    for (mut camera, player_camera) in &mut camera_query {
+        let (position, size) = viewport_rect(player_camera.index, count, window_size);
+        if size.x == 0 || size.y == 0 {
+            continue;
+        }
+        let up_to_date = camera.viewport.as_ref().map_or(false, |v| {
+            v.physical_position == position && v.physical_size == size
+        });
+        if !up_to_date {
+            camera.viewport = Some(Viewport {
+                physical_position: position,
+                physical_size: size,
+                ..default()
+            });
+        }
+    }
+}
+
+// each player zooms their own camera with the zoom keys of their profile, the
+// wheel belongs to the profile that aims with the mouse
+fn zoom_cameras(
+    bindings: Res<Bindings>,
+    menu: Res<ControlsMenu>,
+    keyboard_input: Res<Input<KeyCode>>,
+    mouse_input: Res<Input<MouseButton>>,
+    mut wheel_events: EventReader<MouseWheel>,
+    mut camera_query: Query<&mut PlayerCamera>,
+) {
+    let mut wheel: i32 = 0;
+    for event in wheel_events.iter() {
+        wheel += event.y.signum() as i32;
+    }
+    if menu.open {
+        return;
+    }
+    for mut player_camera in &mut camera_query {
+        // cameras are in the order of the local players, like the profiles
+        let profile = match bindings.profile(player_camera.index) {
+            Some(profile) => profile,
+            None => continue,
+        };
+        let mut steps = if profile.mouse_aim { wheel } else { 0 };
+        if profile.just_pressed(Action::ZoomIn, &keyboard_input, &mouse_input) {
+            steps += 1;
+        }
+        if profile.just_pressed(Action::ZoomOut, &keyboard_input, &mouse_input) {
+            steps -= 1;
+        }
+        if steps == 0 {
+            continue;
+        }
+        // zooming in means a smaller view
+        let zoom = player_camera.zoom as i32 - steps;
+        player_camera.zoom = zoom.clamp(0, ZOOM_STEPS.len() as i32 - 1) as usize;
+    }
+}
+
+// eases the camera toward the tank and a bit ahead of where it aims, and
+// scales the view so that it never shows more than VIEW_SIZE of the world
+fn camera_follow(
+    time: Res<Time>,
+    windows: Res<Windows>,
+    bounds: Option<Res<MapBounds>>,
+    player_query: Query<(&Player, &Transform)>,
+    mut camera_query: Query<
+        (
+            &mut Transform,
+            &mut OrthographicProjection,
+            &Camera,
+            &mut PlayerCamera,
+        ),
+        Without<Player>,
+    >,
+) {
+    let window = match windows.get_primary() {
+        Some(window) => window,
+        None => return,
+    };
+    for (mut camera_transform, mut projection, camera, mut player_camera) in &mut camera_query {
+        let viewport_size = match &camera.viewport {
+            Some(viewport) => viewport.physical_size.as_vec2() / window.scale_factor() as f32,
+            None => Vec2::new(window.width(), window.height()),
+        };
+        if viewport_size.x <= 0.0 || viewport_size.y <= 0.0 {
+            continue;
+        }
+
+        let view = VIEW_SIZE * ZOOM_STEPS[player_camera.zoom];
+        let mut scale = (view.x / viewport_size.x).min(view.y / viewport_size.y);
+        if let Some(bounds) = &bounds {
+            let map_size = bounds.max - bounds.min;
+            scale = scale
+                .min(map_size.x / viewport_size.x)
+                .min(map_size.y / viewport_size.y);
+        }
+        if projection.scale != scale {
+            projection.scale = scale;
+        }
+
+        let (player, transform) = match player_query
+            .iter()
+            .find(|(p, _)| p.handle == player_camera.handle)
+        {
+            Some(found) => found,
+            None => continue,
+        };
+        let mut target = transform.translation.truncate() + aim_direction(player.aim) * LOOK_AHEAD;
+        if let Some(bounds) = &bounds {
+            let half_view = viewport_size * scale / 2.0;
+            let center = (bounds.min + bounds.max) / 2.0;
+            for axis in 0..2 {
+                target[axis] = if bounds.max[axis] - bounds.min[axis] < half_view[axis] * 2.0 {
+                    center[axis]
+                } else {
+                    target[axis].clamp(
+                        bounds.min[axis] + half_view[axis],
+                        bounds.max[axis] - half_view[axis],
+                    )
+                };
+            }
+        }
+
+        let current = camera_transform.translation.truncate() - player_camera.shake;
+        let t = 1.0 - (-CAMERA_EASE * time.delta_seconds()).exp();
+        let eased = current.lerp(target, t);
+
+        // shaking grows with the square of the trauma, so small bumps stay small
+        player_camera.trauma = (player_camera.trauma - SHAKE_DECAY * time.delta_seconds()).max(0.0);
+        let amount = player_camera.trauma * player_camera.trauma * MAX_SHAKE;
+        let seconds = time.seconds_since_startup() as f32;
+        player_camera.shake = Vec2::new(
+            (seconds * 37.0).sin() * 0.7 + (seconds * 71.0).sin() * 0.3,
+            (seconds * 43.0).cos() * 0.7 + (seconds * 67.0).cos() * 0.3,
+        ) * amount;
+
+        let position = eased + player_camera.shake;
+        camera_transform.translation.x = position.x;
+        camera_transform.translation.y = position.y;
+    }
+}
+
+// the world position under the cursor, if the cursor is inside the viewport
+// of this camera
+pub fn cursor_world_position(
+    windows: &Windows,
+    camera: &Camera,
+    camera_transform: &GlobalTransform,
+) -> Option<Vec2> {
+    let window = windows.get_primary()?;
+    // bottom left origin, in logical pixels
+    let cursor = window.cursor_position()? * window.scale_factor() as f32;
+    let window_size = Vec2::new(
+        window.physical_width() as f32,
+        window.physical_height() as f32,
+    );
+    let (position, size) = match &camera.viewport {
+        // viewports have a top left origin
+        Some(viewport) => {
+            let size = viewport.physical_size.as_vec2();
+            let top_left = viewport.physical_position.as_vec2();
+            (
+                Vec2::new(top_left.x, window_size.y - top_left.y - size.y),
+                size,
+            )
+        }
+        None => (Vec2::ZERO, window_size),
+    };
+    let local = cursor - position;
+    if local.x < 0.0 || local.y < 0.0 || local.x > size.x || local.y > size.y {
+        return None;
+    }
+    let ndc = local / size * 2.0 - Vec2::ONE;
+    let ndc_to_world = camera_transform.compute_matrix() * camera.projection_matrix().inverse();
+    Some(ndc_to_world.project_point3(ndc.extend(-1.0)).truncate())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_viewport_fits_in_the_window() {
+        let window_size = UVec2::new(1280, 720);
+        for count in 1..=9 {
+            for index in 0..count {
+                let (position, size) = viewport_rect(index, count, window_size);
+                assert!(size.x > 0 && size.y > 0);
+                let end = position + size;
+                assert!(
+                    end.x <= window_size.x && end.y <= window_size.y,
+                    "viewport {} of {} ends at {}",
+                    index,
+                    count,
+                    end
+                );
+            }
+        }
+    }
+}
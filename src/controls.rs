@@ -0,0 +1,650 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::BufReader;
+
+use bevy::prelude::*;
+use bytemuck::Zeroable;
+use ggrs::PlayerHandle;
+use serde::{Deserialize, Serialize};
+
+use crate::camera::{cursor_world_position, PlayerCamera};
+use crate::{
+    quantize_aim, BoxInput, LocalPlayers, Player, INPUT_AIM, INPUT_DOWN, INPUT_FIRE, INPUT_LEFT,
+    INPUT_RIGHT, INPUT_SECONDARY, INPUT_UP,
+};
+
+const CONTROLS_PATH: &str = "settings/controls.json";
+const STICK_DEADZONE: f32 = 0.2;
+
+pub struct ControlsPlugin;
+
+impl Plugin for ControlsPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Bindings::load())
+            .init_resource::<ControlsMenu>()
+            .add_system(toggle_controls_menu)
+            .add_system(controls_menu.after(toggle_controls_menu))
+            .add_system(update_controls_menu_text.after(controls_menu));
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Up,
+    Down,
+    Left,
+    Right,
+    AimUp,
+    AimDown,
+    AimLeft,
+    AimRight,
+    Fire,
+    Secondary,
+    ZoomIn,
+    ZoomOut,
+}
+
+pub const ACTIONS: [Action; 12] = [
+    Action::Up,
+    Action::Down,
+    Action::Left,
+    Action::Right,
+    Action::AimUp,
+    Action::AimDown,
+    Action::AimLeft,
+    Action::AimRight,
+    Action::Fire,
+    Action::Secondary,
+    Action::ZoomIn,
+    Action::ZoomOut,
+];
+
+impl Action {
+    fn label(&self) -> &'static str {
+        match self {
+            Action::Up => "move up",
+            Action::Down => "move down",
+            Action::Left => "move left",
+            Action::Right => "move right",
+            Action::AimUp => "aim up",
+            Action::AimDown => "aim down",
+            Action::AimLeft => "aim left",
+            Action::AimRight => "aim right",
+            Action::Fire => "fire",
+            Action::Secondary => "secondary",
+            Action::ZoomIn => "zoom in",
+            Action::ZoomOut => "zoom out",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Binding {
+    Key(KeyCode),
+    Mouse(MouseButton),
+}
+
+macro_rules! key_names {
+    ($($key:ident),* $(,)?) => {
+        const KEY_NAMES: &[(&str, KeyCode)] = &[$((stringify!($key), KeyCode::$key)),*];
+    };
+}
+
+key_names!(
+    A,
+    B,
+    C,
+    D,
+    E,
+    F,
+    G,
+    H,
+    I,
+    J,
+    K,
+    L,
+    M,
+    N,
+    O,
+    P,
+    Q,
+    R,
+    S,
+    T,
+    U,
+    V,
+    W,
+    X,
+    Y,
+    Z,
+    Key1,
+    Key2,
+    Key3,
+    Key4,
+    Key5,
+    Key6,
+    Key7,
+    Key8,
+    Key9,
+    Key0,
+    Up,
+    Down,
+    Left,
+    Right,
+    Space,
+    Return,
+    Tab,
+    Back,
+    LShift,
+    RShift,
+    LControl,
+    RControl,
+    LAlt,
+    RAlt,
+    Numpad0,
+    Numpad1,
+    Numpad2,
+    Numpad3,
+    Numpad4,
+    Numpad5,
+    Numpad6,
+    Numpad7,
+    Numpad8,
+    Numpad9,
+    NumpadEnter,
+    NumpadAdd,
+    NumpadSubtract,
+    NumpadMultiply,
+    NumpadDivide,
+    Comma,
+    Period,
+    Slash,
+    Semicolon,
+    Apostrophe,
+    LBracket,
+    RBracket,
+    Minus,
+    Equals,
+    Backslash,
+    Grave,
+    Insert,
+    Delete,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+);
+
+const MOUSE_NAMES: &[(&str, MouseButton)] = &[
+    ("MouseLeft", MouseButton::Left),
+    ("MouseRight", MouseButton::Right),
+    ("MouseMiddle", MouseButton::Middle),
+];
+
+impl Binding {
+    pub fn parse(name: &str) -> Option<Binding> {
+        KEY_NAMES
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, k)| Binding::Key(*k))
+            .or_else(|| {
+                MOUSE_NAMES
+                    .iter()
+                    .find(|(n, _)| *n == name)
+                    .map(|(_, b)| Binding::Mouse(*b))
+            })
+    }
+
+    pub fn name(&self) -> Option<&'static str> {
+        match self {
+            Binding::Key(key) => KEY_NAMES.iter().find(|(_, k)| k == key).map(|(n, _)| *n),
+            Binding::Mouse(button) => MOUSE_NAMES
+                .iter()
+                .find(|(_, b)| b == button)
+                .map(|(n, _)| *n),
+        }
+    }
+
+    fn pressed(&self, keyboard_input: &Input<KeyCode>, mouse_input: &Input<MouseButton>) -> bool {
+        match self {
+            Binding::Key(key) => keyboard_input.pressed(*key),
+            Binding::Mouse(button) => mouse_input.pressed(*button),
+        }
+    }
+
+    fn just_pressed(
+        &self,
+        keyboard_input: &Input<KeyCode>,
+        mouse_input: &Input<MouseButton>,
+    ) -> bool {
+        match self {
+            Binding::Key(key) => keyboard_input.just_pressed(*key),
+            Binding::Mouse(button) => mouse_input.just_pressed(*button),
+        }
+    }
+}
+
+// one profile per local player handle, bindings are stored by name so that
+// the file stays readable
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct BindingProfile {
+    #[serde(default)]
+    pub keys: BTreeMap<Action, String>,
+    #[serde(default)]
+    pub mouse_aim: bool,
+}
+
+impl BindingProfile {
+    fn with_keys(keys: &[(Action, &str)], mouse_aim: bool) -> Self {
+        Self {
+            keys: keys.iter().map(|(a, k)| (*a, k.to_string())).collect(),
+            mouse_aim,
+        }
+    }
+
+    pub fn binding(&self, action: Action) -> Option<Binding> {
+        self.keys.get(&action).and_then(|name| Binding::parse(name))
+    }
+
+    fn pressed(
+        &self,
+        action: Action,
+        keyboard_input: &Input<KeyCode>,
+        mouse_input: &Input<MouseButton>,
+    ) -> bool {
+        self.binding(action)
+            .map_or(false, |b| b.pressed(keyboard_input, mouse_input))
+    }
+
+    pub fn just_pressed(
+        &self,
+        action: Action,
+        keyboard_input: &Input<KeyCode>,
+        mouse_input: &Input<MouseButton>,
+    ) -> bool {
+        self.binding(action)
+            .map_or(false, |b| b.just_pressed(keyboard_input, mouse_input))
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Bindings {
+    pub profiles: Vec<BindingProfile>,
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        use Action::*;
+        Self {
+            profiles: vec![
+                BindingProfile::with_keys(
+                    &[
+                        (Up, "W"),
+                        (Down, "S"),
+                        (Left, "A"),
+                        (Right, "D"),
+                        (AimUp, "Up"),
+                        (AimDown, "Down"),
+                        (AimLeft, "Left"),
+                        (AimRight, "Right"),
+                        (Fire, "MouseLeft"),
+                        (Secondary, "MouseRight"),
+                        (ZoomIn, "Equals"),
+                        (ZoomOut, "Minus"),
+                    ],
+                    true,
+                ),
+                BindingProfile::with_keys(
+                    &[
+                        (Up, "I"),
+                        (Down, "K"),
+                        (Left, "J"),
+                        (Right, "L"),
+                        (AimUp, "Numpad8"),
+                        (AimDown, "Numpad5"),
+                        (AimLeft, "Numpad4"),
+                        (AimRight, "Numpad6"),
+                        (Fire, "RShift"),
+                        (Secondary, "RControl"),
+                        (ZoomIn, "NumpadAdd"),
+                        (ZoomOut, "NumpadSubtract"),
+                    ],
+                    false,
+                ),
+                BindingProfile::default(),
+                BindingProfile::default(),
+            ],
+        }
+    }
+}
+
+impl Bindings {
+    pub fn load() -> Self {
+        let file = match File::open(CONTROLS_PATH) {
+            Ok(file) => file,
+            Err(_) => return Self::default(),
+        };
+        match serde_json::from_reader::<_, Bindings>(BufReader::new(file)) {
+            Ok(bindings) if !bindings.profiles.is_empty() => bindings,
+            Ok(_) => Self::default(),
+            Err(e) => {
+                warn!("ignoring {}: {}", CONTROLS_PATH, e);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save(&self) {
+        let result = fs::create_dir_all("settings")
+            .and_then(|_| File::create(CONTROLS_PATH))
+            .map_err(|e| e.to_string())
+            .and_then(|file| serde_json::to_writer_pretty(file, self).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            warn!("failed to save {}: {}", CONTROLS_PATH, e);
+        }
+    }
+
+    pub fn profile(&self, local_index: usize) -> Option<&BindingProfile> {
+        self.profiles.get(local_index)
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn input(
+    handle: In<PlayerHandle>,
+    local_players: Res<LocalPlayers>,
+    bindings: Res<Bindings>,
+    menu: Res<ControlsMenu>,
+    keyboard_input: Res<Input<KeyCode>>,
+    mouse_input: Res<Input<MouseButton>>,
+    windows: Res<Windows>,
+    gamepads: Res<Gamepads>,
+    gamepad_axes: Res<Axis<GamepadAxis>>,
+    gamepad_buttons: Res<Input<GamepadButton>>,
+    camera_query: Query<(&Camera, &GlobalTransform, &PlayerCamera)>,
+    player_query: Query<(&Player, &Transform)>,
+) -> BoxInput {
+    let mut input = BoxInput::zeroed();
+    if menu.open {
+        return input;
+    }
+    let local_index = local_players
+        .0
+        .iter()
+        .position(|h| *h == handle.0)
+        .unwrap_or(0);
+
+    if let Some(profile) = bindings.profile(local_index) {
+        let mut cursor_aim = None;
+        if profile.mouse_aim {
+            // aim at the cursor, relative to our own tank
+            let tank = player_query
+                .iter()
+                .find(|(p, _)| p.handle == handle.0)
+                .map(|(_, t)| t.translation.truncate());
+            let cursor = camera_query
+                .iter()
+                .find(|(_, _, c)| c.handle == handle.0)
+                .and_then(|(camera, transform, _)| {
+                    cursor_world_position(&windows, camera, transform)
+                });
+            if let (Some(tank), Some(cursor)) = (tank, cursor) {
+                cursor_aim = Some(cursor - tank);
+            }
+        }
+        keyboard_mouse_input(
+            &mut input,
+            profile,
+            &keyboard_input,
+            &mouse_input,
+            cursor_aim,
+        );
+    }
+
+    let mut connected: Vec<Gamepad> = gamepads.iter().copied().collect();
+    connected.sort_by_key(|g| g.0);
+    if let Some(gamepad) = connected.get(local_index) {
+        gamepad_input(&mut input, *gamepad, &gamepad_axes, &gamepad_buttons);
+    }
+
+    input
+}
+
+fn keyboard_mouse_input(
+    input: &mut BoxInput,
+    profile: &BindingProfile,
+    keyboard_input: &Input<KeyCode>,
+    mouse_input: &Input<MouseButton>,
+    cursor_aim: Option<Vec2>,
+) {
+    let pressed = |action| profile.pressed(action, keyboard_input, mouse_input);
+
+    if pressed(Action::Up) {
+        input.inp |= INPUT_UP;
+    }
+    if pressed(Action::Left) {
+        input.inp |= INPUT_LEFT;
+    }
+    if pressed(Action::Down) {
+        input.inp |= INPUT_DOWN;
+    }
+    if pressed(Action::Right) {
+        input.inp |= INPUT_RIGHT;
+    }
+
+    if let Some(dir) = cursor_aim {
+        if dir.length_squared() > 0.0 {
+            input.aim = quantize_aim(dir);
+            input.inp |= INPUT_AIM;
+        }
+    }
+    if pressed(Action::Fire) {
+        input.inp |= INPUT_FIRE;
+    }
+    if pressed(Action::Secondary) {
+        input.inp |= INPUT_SECONDARY;
+    }
+
+    // the aim keys only turn the turret, fire is a key of its own
+    let mut dir = Vec2::ZERO;
+    if pressed(Action::AimUp) {
+        dir.y += 1.0;
+    }
+    if pressed(Action::AimDown) {
+        dir.y -= 1.0;
+    }
+    if pressed(Action::AimLeft) {
+        dir.x -= 1.0;
+    }
+    if pressed(Action::AimRight) {
+        dir.x += 1.0;
+    }
+    if dir.length_squared() > 0.0 {
+        input.aim = quantize_aim(dir);
+        input.inp |= INPUT_AIM;
+    }
+}
+
+fn gamepad_input(
+    input: &mut BoxInput,
+    gamepad: Gamepad,
+    axes: &Axis<GamepadAxis>,
+    buttons: &Input<GamepadButton>,
+) {
+    let axis = |axis_type| axes.get(GamepadAxis(gamepad, axis_type)).unwrap_or(0.0);
+
+    let left = Vec2::new(
+        axis(GamepadAxisType::LeftStickX),
+        axis(GamepadAxisType::LeftStickY),
+    );
+    if left.length() > STICK_DEADZONE {
+        let left = left.clamp_length_max(1.0);
+        input.move_x = quantize_stick(left.x);
+        input.move_y = quantize_stick(left.y);
+    }
+
+    let right = Vec2::new(
+        axis(GamepadAxisType::RightStickX),
+        axis(GamepadAxisType::RightStickY),
+    );
+    if right.length() > STICK_DEADZONE {
+        input.aim = quantize_aim(right);
+        input.inp |= INPUT_AIM;
+    }
+
+    if buttons.pressed(GamepadButton(gamepad, GamepadButtonType::RightTrigger2)) {
+        input.inp |= INPUT_FIRE;
+    }
+    if buttons.pressed(GamepadButton(gamepad, GamepadButtonType::LeftTrigger2)) {
+        input.inp |= INPUT_SECONDARY;
+    }
+}
+
+fn quantize_stick(value: f32) -> i8 {
+    (value * 127.0).round().clamp(-127.0, 127.0) as i8
+}
+
+// rebinding screen, F1 opens and closes it, the bindings are saved on close
+#[derive(Default)]
+pub struct ControlsMenu {
+    pub open: bool,
+    profile: usize,
+    selected: usize,
+    waiting: bool,
+}
+
+#[derive(Component)]
+struct ControlsMenuRoot;
+
+#[derive(Component)]
+struct ControlsMenuText;
+
+fn toggle_controls_menu(
+    mut commands: Commands,
+    keyboard_input: Res<Input<KeyCode>>,
+    asset_server: Res<AssetServer>,
+    mut menu: ResMut<ControlsMenu>,
+    bindings: Res<Bindings>,
+    root_query: Query<Entity, With<ControlsMenuRoot>>,
+) {
+    if menu.waiting || !keyboard_input.just_pressed(KeyCode::F1) {
+        return;
+    }
+    menu.open = !menu.open;
+    if menu.open {
+        commands
+            .spawn_bundle(NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+                    justify_content: JustifyContent::Center,
+                    align_items: AlignItems::Center,
+                    ..default()
+                },
+                color: Color::rgba(0.0, 0.0, 0.0, 0.8).into(),
+                ..default()
+            })
+            .insert(ControlsMenuRoot)
+            .with_children(|parent| {
+                parent
+                    .spawn_bundle(TextBundle::from_section(
+                        "",
+                        TextStyle {
+                            font: asset_server.load("fonts/DejaVuSans.ttf"),
+                            font_size: 20.0,
+                            color: Color::WHITE,
+                        },
+                    ))
+                    .insert(ControlsMenuText);
+            });
+    } else {
+        for entity in &root_query {
+            commands.entity(entity).despawn_recursive();
+        }
+        bindings.save();
+    }
+}
+
+fn controls_menu(
+    keyboard_input: Res<Input<KeyCode>>,
+    mouse_input: Res<Input<MouseButton>>,
+    mut menu: ResMut<ControlsMenu>,
+    mut bindings: ResMut<Bindings>,
+) {
+    if !menu.open {
+        return;
+    }
+    let (profile, selected) = (menu.profile, menu.selected);
+
+    if menu.waiting {
+        if keyboard_input.just_pressed(KeyCode::Escape) {
+            menu.waiting = false;
+            return;
+        }
+        let pressed = keyboard_input
+            .get_just_pressed()
+            .map(|k| Binding::Key(*k))
+            .chain(mouse_input.get_just_pressed().map(|b| Binding::Mouse(*b)))
+            .find_map(|b| b.name());
+        if let Some(name) = pressed {
+            bindings.profiles[profile]
+                .keys
+                .insert(ACTIONS[selected], name.to_string());
+            menu.waiting = false;
+        }
+        return;
+    }
+
+    if keyboard_input.just_pressed(KeyCode::Down) {
+        menu.selected = (selected + 1) % ACTIONS.len();
+    }
+    if keyboard_input.just_pressed(KeyCode::Up) {
+        menu.selected = (selected + ACTIONS.len() - 1) % ACTIONS.len();
+    }
+    if keyboard_input.just_pressed(KeyCode::Tab) {
+        menu.profile = (profile + 1) % bindings.profiles.len();
+    }
+    if keyboard_input.just_pressed(KeyCode::M) {
+        let mouse_aim = &mut bindings.profiles[profile].mouse_aim;
+        *mouse_aim = !*mouse_aim;
+    }
+    if keyboard_input.just_pressed(KeyCode::Delete) {
+        bindings.profiles[profile].keys.remove(&ACTIONS[selected]);
+    }
+    if keyboard_input.just_pressed(KeyCode::Return) {
+        menu.waiting = true;
+    }
+}
+
+fn update_controls_menu_text(
+    menu: Res<ControlsMenu>,
+    bindings: Res<Bindings>,
+    mut text_query: Query<&mut Text, With<ControlsMenuText>>,
+) {
+    if !menu.is_changed() && !bindings.is_changed() {
+        return;
+    }
+    for mut text in &mut text_query {
+        let profile = &bindings.profiles[menu.profile];
+        let mut value = format!(
+            "controls for local player {} (tab to switch)\n\n",
+            menu.profile + 1
+        );
+        for (i, action) in ACTIONS.iter().enumerate() {
+            let binding = if menu.waiting && i == menu.selected {
+                "press a key, escape to cancel"
+            } else {
+                profile.keys.get(action).map_or("-", |k| k.as_str())
+            };
+            let marker = if i == menu.selected { ">" } else { " " };
+            value += &format!("{} {}: {}\n", marker, action.label(), binding);
+        }
+        value += &format!(
+            "\nmouse aim: {} (m to toggle)\n\n",
+            if profile.mouse_aim { "on" } else { "off" }
+        );
+        value += "enter to rebind, delete to clear, F1 to save and close";
+        text.sections[0].value = value;
+    }
+}
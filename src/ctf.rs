@@ -0,0 +1,254 @@
+use bevy::prelude::*;
+use bevy_ggrs::{Rollback, RollbackIdProvider};
+
+use crate::events::{SimEvent, SimEvents};
+use crate::map::Map;
+use crate::playlist::Rotation;
+use crate::teams::{team_color, Teams};
+use crate::{Health, MatchClock, Player, Score, FPS};
+
+const FLAG_SIZE: f32 = 10.0;
+// how close a tank has to get to pick it up
+const FLAG_REACH: f32 = 8.0;
+// seconds a dropped flag waits for someone before going home
+const FLAG_RETURN_TIME: f32 = 15.0;
+// above the tanks
+const FLAG_Z: f32 = 5.0;
+// where a carried flag sits on its tank
+const CARRY_OFFSET: Vec2 = Vec2::new(0.0, 12.0);
+
+pub struct CtfPlugin;
+
+impl Plugin for CtfPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(show_flags);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlagAction {
+    Taken,
+    Dropped,
+    Returned,
+    Captured,
+}
+
+// one per team and map of the rotation, on its own rollback entity
+#[derive(Component, Default, Reflect)]
+pub struct Flag {
+    pub team: usize,
+    // see MapLayer
+    pub layer: usize,
+    pub home: Vec2,
+    // handle of the tank carrying it, -1 for nobody
+    pub carrier: i32,
+    // seconds until a dropped flag goes home, 0 when it isn't lying around
+    pub dropped: f32,
+}
+
+impl Flag {
+    pub fn at_home(&self) -> bool {
+        self.carrier < 0 && self.dropped <= 0.0
+    }
+
+    fn send_home(&mut self, transform: &mut Transform) {
+        self.carrier = -1;
+        self.dropped = 0.0;
+        transform.translation = self.home.extend(FLAG_Z);
+    }
+}
+
+// the team numbers of the map that have no flag, for the teams in play
+pub fn missing_flags(map: &Map, teams: usize) -> Vec<usize> {
+    (1..=teams)
+        .filter(|team| !map.flags.iter().any(|f| f.get(2) == Some(&(*team as i32))))
+        .collect()
+}
+
+// the map has been validated, flags of teams nobody plays for are left out
+pub fn spawn_flags(
+    commands: &mut Commands,
+    rip: &mut RollbackIdProvider,
+    map: &Map,
+    layer: usize,
+    teams: &Teams,
+) {
+    let (min, max) = map.bounds().unwrap_or_default();
+    let origin = (max - min).as_vec2();
+    for team in 0..teams.count {
+        let flag = match map.flags.iter().find(|f| f[2] == team as i32 + 1) {
+            Some(flag) => flag,
+            None => continue,
+        };
+        let home = Vec2::new(flag[0] as f32, flag[1] as f32) - origin / 2.0;
+        commands
+            .spawn_bundle(SpriteBundle {
+                transform: Transform {
+                    translation: home.extend(FLAG_Z),
+                    rotation: Quat::from_rotation_z(std::f32::consts::FRAC_PI_4),
+                    scale: Vec3::new(FLAG_SIZE, FLAG_SIZE, 1.0),
+                },
+                sprite: Sprite {
+                    color: team_color(team),
+                    ..default()
+                },
+                visibility: Visibility { is_visible: false },
+                ..default()
+            })
+            .insert(Flag {
+                team,
+                layer,
+                home,
+                carrier: -1,
+                dropped: 0.0,
+            })
+            .insert(Rollback::new(rip.next_id()));
+    }
+}
+
+// after handle_deaths, so that a tank that just died drops what it carries
+#[allow(clippy::type_complexity)]
+pub fn update_flags(
+    rotation: Res<Rotation>,
+    teams: Res<Teams>,
+    clock_query: Query<&MatchClock>,
+    mut flag_query: Query<(&mut Flag, &mut Transform), Without<Player>>,
+    mut player_query: Query<(&Player, &Health, &Transform, &mut Score), Without<Flag>>,
+    mut events: ResMut<SimEvents>,
+) {
+    let clock = match clock_query.iter().next() {
+        Some(clock) => clock,
+        None => return,
+    };
+    // a round starts with every flag at home
+    if clock.frame == 0 {
+        for (mut flag, mut transform) in &mut flag_query {
+            flag.send_home(&mut transform);
+        }
+    }
+    let round = rotation.round(clock.round);
+    if !round.mode.has_flags() || clock.expired() {
+        return;
+    }
+    let dt = 1.0 / FPS as f32;
+
+    // in handle order, the lowest one gets to a flag first
+    let mut tanks: Vec<(usize, Vec2, f32, bool)> = player_query
+        .iter()
+        .map(|(p, h, t, _)| (p.handle, t.translation.truncate(), p.radius, h.is_alive()))
+        .collect();
+    tanks.sort_by_key(|(handle, ..)| *handle);
+    let mut flags: Vec<_> = flag_query
+        .iter_mut()
+        .filter(|(flag, _)| flag.layer == round.layer)
+        .collect();
+    flags.sort_by_key(|(flag, _)| flag.team);
+
+    // carried flags follow their tank or fall where it died
+    for (flag, transform) in &mut flags {
+        if flag.carrier >= 0 {
+            let carrier = flag.carrier as usize;
+            match tanks.iter().find(|(handle, ..)| *handle == carrier) {
+                Some((_, position, _, true)) => {
+                    transform.translation = (*position + CARRY_OFFSET).extend(FLAG_Z);
+                }
+                _ => {
+                    flag.carrier = -1;
+                    flag.dropped = FLAG_RETURN_TIME;
+                    events.emit(SimEvent::Flag {
+                        handle: Some(carrier),
+                        team: flag.team,
+                        action: FlagAction::Dropped,
+                        position: transform.translation.truncate(),
+                    });
+                }
+            }
+        } else if flag.dropped > 0.0 {
+            flag.dropped -= dt;
+            if flag.dropped <= 0.0 {
+                flag.send_home(transform);
+                events.emit(SimEvent::Flag {
+                    handle: None,
+                    team: flag.team,
+                    action: FlagAction::Returned,
+                    position: flag.home,
+                });
+            }
+        }
+    }
+
+    let mut captures = Vec::new();
+    for (handle, position, radius, alive) in &tanks {
+        let team = match teams.team(*handle) {
+            Some(team) if *alive => team,
+            _ => continue,
+        };
+        for i in 0..flags.len() {
+            let flag_position = flags[i].1.translation.truncate();
+            if flags[i].0.carrier >= 0 || position.distance(flag_position) > radius + FLAG_REACH {
+                continue;
+            }
+            if flags[i].0.team != team {
+                // one flag at a time
+                if flags.iter().any(|(f, _)| f.carrier == *handle as i32) {
+                    continue;
+                }
+                let (flag, _) = &mut flags[i];
+                flag.carrier = *handle as i32;
+                flag.dropped = 0.0;
+                events.emit(SimEvent::Flag {
+                    handle: Some(*handle),
+                    team: flag.team,
+                    action: FlagAction::Taken,
+                    position: flag_position,
+                });
+            } else if !flags[i].0.at_home() {
+                let (flag, transform) = &mut flags[i];
+                flag.send_home(transform);
+                events.emit(SimEvent::Flag {
+                    handle: Some(*handle),
+                    team,
+                    action: FlagAction::Returned,
+                    position: flag_position,
+                });
+            } else if let Some(j) = flags.iter().position(|(f, _)| f.carrier == *handle as i32) {
+                // an enemy flag brought to our own, which has to be home
+                let (flag, transform) = &mut flags[j];
+                flag.send_home(transform);
+                captures.push(*handle);
+                events.emit(SimEvent::Flag {
+                    handle: Some(*handle),
+                    team: flag.team,
+                    action: FlagAction::Captured,
+                    position: flag_position,
+                });
+            }
+        }
+    }
+    for handle in captures {
+        for (player, _, _, mut score) in &mut player_query {
+            if player.handle == handle {
+                score.captures += 1;
+            }
+        }
+    }
+}
+
+// only the flags of the current map, and only when they are played for
+fn show_flags(
+    rotation: Option<Res<Rotation>>,
+    clock_query: Query<&MatchClock>,
+    mut flag_query: Query<(&Flag, &mut Visibility)>,
+) {
+    let (rotation, clock) = match (rotation, clock_query.get_single()) {
+        (Some(rotation), Ok(clock)) => (rotation, clock),
+        _ => return,
+    };
+    let round = rotation.round(clock.round);
+    for (flag, mut visibility) in &mut flag_query {
+        let visible = round.mode.has_flags() && flag.layer == round.layer;
+        if visibility.is_visible != visible {
+            visibility.is_visible = visible;
+        }
+    }
+}
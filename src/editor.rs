@@ -0,0 +1,672 @@
+use std::io;
+use std::mem;
+
+use bevy::input::mouse::MouseWheel;
+use bevy::prelude::*;
+use bevy::sprite::MaterialMesh2dBundle;
+
+use crate::camera::cursor_world_position;
+use crate::controls::ControlsMenu;
+use crate::map::{load_map, map_path, save_map, wall_color, Hive, Map, WALL_TYPES};
+use crate::mapgen::{editable_name, parse_generated};
+use crate::teams::{team_color, MAX_TEAMS};
+use crate::{GameState, MatchConfig};
+
+const GRID_SIZES: [i32; 4] = [5, 10, 20, 50];
+const SPAWN_SIZE: f32 = 12.0;
+const FLAG_SIZE: f32 = 16.0;
+const ZONE_COLOR: Color = Color::rgba(1.0, 1.0, 1.0, 0.15);
+const PAN_SPEED: f32 = 600.0;
+const ZOOM_STEP: f32 = 1.1;
+
+pub struct EditorPlugin;
+
+impl Plugin for EditorPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set(SystemSet::on_enter(GameState::Editor).with_system(enter_editor))
+            .add_system_set(
+                SystemSet::on_update(GameState::Editor)
+                    .with_system(move_editor_camera)
+                    .with_system(editor_input)
+                    .with_system(update_editor_shapes.after(editor_input))
+                    .with_system(update_editor_preview.after(editor_input))
+                    .with_system(update_editor_text.after(editor_input)),
+            )
+            .add_system_set(SystemSet::on_exit(GameState::Editor).with_system(exit_editor));
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Tool {
+    Wall,
+    Spawn,
+    Hive,
+    Flag,
+    Zone,
+}
+
+struct Editor {
+    name: String,
+    map: Map,
+    // whole maps, they are small
+    undo: Vec<Map>,
+    redo: Vec<Map>,
+    saved: bool,
+    tool: Tool,
+    wall_type: i32,
+    // 0 for anyone, see SpawnPoints
+    spawn_team: i32,
+    // from 1
+    flag_team: i32,
+    snap: bool,
+    // index in GRID_SIZES
+    grid: usize,
+    drag_start: Option<IVec2>,
+    status: String,
+    circle: Handle<Mesh>,
+    hive_material: Handle<ColorMaterial>,
+}
+
+impl Editor {
+    // every change goes through here, so that it can be undone
+    fn edit(&mut self) -> &mut Map {
+        self.undo.push(self.map.clone());
+        self.redo.clear();
+        self.saved = false;
+        &mut self.map
+    }
+
+    fn undo(&mut self) {
+        if let Some(map) = self.undo.pop() {
+            self.redo.push(mem::replace(&mut self.map, map));
+            self.saved = false;
+        }
+    }
+
+    fn redo(&mut self) {
+        if let Some(map) = self.redo.pop() {
+            self.undo.push(mem::replace(&mut self.map, map));
+            self.saved = false;
+        }
+    }
+
+    fn grid_size(&self) -> i32 {
+        GRID_SIZES[self.grid]
+    }
+
+    fn snap(&self, point: Vec2) -> IVec2 {
+        if self.snap {
+            let grid = self.grid_size() as f32;
+            ((point / grid).round() * grid).as_ivec2()
+        } else {
+            point.round().as_ivec2()
+        }
+    }
+
+    // the topmost thing under the point, for deleting it
+    fn remove_at(&mut self, point: Vec2) -> bool {
+        let map = &self.map;
+        if let Some(i) = map.flags.iter().rposition(|f| {
+            (Vec2::new(f[0] as f32, f[1] as f32) - point)
+                .abs()
+                .max_element()
+                <= FLAG_SIZE / 2.0
+        }) {
+            self.edit().flags.remove(i);
+        } else if let Some(i) = map.lives.iter().rposition(|l| {
+            (Vec2::new(l[0] as f32, l[1] as f32) - point)
+                .abs()
+                .max_element()
+                <= SPAWN_SIZE / 2.0
+        }) {
+            self.edit().lives.remove(i);
+        } else if let Some(i) = map.hives.iter().rposition(|h| {
+            Vec2::new(h.pos[0] as f32, h.pos[1] as f32).distance(point) <= h.radius as f32
+        }) {
+            self.edit().hives.remove(i);
+        } else if let Some(i) = wall_at(map, point) {
+            self.edit().walls.remove(i);
+        } else if let Some(i) = map.zones.iter().rposition(|z| {
+            point.x >= z[0] as f32
+                && point.x <= z[2] as f32
+                && point.y >= z[1] as f32
+                && point.y <= z[3] as f32
+        }) {
+            self.edit().zones.remove(i);
+        } else {
+            return false;
+        }
+        true
+    }
+}
+
+fn wall_at(map: &Map, point: Vec2) -> Option<usize> {
+    map.walls.iter().rposition(|w| {
+        point.x >= w[0] as f32
+            && point.x <= w[2] as f32
+            && point.y >= w[1] as f32
+            && point.y <= w[3] as f32
+    })
+}
+
+#[derive(Component)]
+struct EditorEntity;
+
+#[derive(Component)]
+struct EditorCamera;
+
+// redrawn from the map whenever it changes
+#[derive(Component)]
+struct EditorShape;
+
+#[derive(Component)]
+struct EditorPreview;
+
+#[derive(Component)]
+struct EditorText;
+
+// a map that doesn't exist yet starts out empty, a generated one is saved
+// under its editable name
+fn open_map(name: &str) -> (Map, String) {
+    let path = map_path(&editable_name(name));
+    match load_map(name) {
+        Ok(map) if parse_generated(name).is_some() => {
+            (map, format!("generated {}, saves to {}", name, path))
+        }
+        Ok(map) => (map, format!("opened {}", path)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (
+            Map {
+                name: name.to_lowercase(),
+                ..default()
+            },
+            format!("new map, saves to {}", path),
+        ),
+        Err(e) => (
+            Map {
+                name: name.to_lowercase(),
+                ..default()
+            },
+            format!("can't open {}: {}, saving overwrites it", path, e),
+        ),
+    }
+}
+
+fn enter_editor(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    config: Res<MatchConfig>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+) {
+    let (map, status) = open_map(&config.map);
+
+    // look at the middle of the walls
+    let center = if map.walls.is_empty() {
+        Vec2::ZERO
+    } else {
+        let sum = map.walls.iter().fold(Vec2::ZERO, |sum, w| {
+            sum + Vec2::new((w[0] + w[2]) as f32, (w[1] + w[3]) as f32) / 2.0
+        });
+        sum / map.walls.len() as f32
+    };
+    let mut camera = Camera2dBundle::default();
+    camera.transform = Transform::from_xyz(center.x, center.y, 100.0);
+    commands
+        .spawn_bundle(camera)
+        .insert(UiCameraConfig { show_ui: false })
+        .insert(EditorCamera)
+        .insert(EditorEntity);
+
+    commands
+        .spawn_bundle(SpriteBundle::default())
+        .insert(EditorPreview)
+        .insert(EditorEntity);
+
+    commands
+        .spawn_bundle(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    left: Val::Px(10.0),
+                    top: Val::Px(10.0),
+                    ..default()
+                },
+                ..default()
+            },
+            text: Text::from_section(
+                "",
+                TextStyle {
+                    font: asset_server.load("fonts/DejaVuSans.ttf"),
+                    font_size: 16.0,
+                    color: Color::WHITE,
+                },
+            ),
+            ..default()
+        })
+        .insert(EditorText)
+        .insert(EditorEntity);
+
+    commands.insert_resource(Editor {
+        name: editable_name(&config.map),
+        map,
+        undo: Vec::new(),
+        redo: Vec::new(),
+        saved: true,
+        tool: Tool::Wall,
+        wall_type: 0,
+        spawn_team: 0,
+        flag_team: 1,
+        snap: true,
+        grid: 1,
+        drag_start: None,
+        status,
+        circle: meshes.add(Mesh::from(shape::Circle::new(1.0))),
+        hive_material: materials.add(ColorMaterial::from(Color::rgba(0.6, 0.2, 0.8, 0.4))),
+    });
+}
+
+fn exit_editor(mut commands: Commands, entity_query: Query<Entity, With<EditorEntity>>) {
+    for entity in &entity_query {
+        commands.entity(entity).despawn_recursive();
+    }
+    commands.remove_resource::<Editor>();
+}
+
+fn move_editor_camera(
+    time: Res<Time>,
+    keyboard_input: Res<Input<KeyCode>>,
+    mut wheel_events: EventReader<MouseWheel>,
+    mut camera_query: Query<(&mut Transform, &mut OrthographicProjection), With<EditorCamera>>,
+) {
+    let control =
+        keyboard_input.pressed(KeyCode::LControl) || keyboard_input.pressed(KeyCode::RControl);
+    let mut direction = Vec2::ZERO;
+    if !control {
+        for (keys, step) in [
+            ([KeyCode::Left, KeyCode::A], -Vec2::X),
+            ([KeyCode::Right, KeyCode::D], Vec2::X),
+            ([KeyCode::Down, KeyCode::S], -Vec2::Y),
+            ([KeyCode::Up, KeyCode::W], Vec2::Y),
+        ] {
+            if keyboard_input.any_pressed(keys) {
+                direction += step;
+            }
+        }
+    }
+    let zoom: f32 = wheel_events.iter().map(|e| e.y.signum()).sum();
+
+    for (mut transform, mut projection) in &mut camera_query {
+        if zoom != 0.0 {
+            projection.scale = (projection.scale * ZOOM_STEP.powf(-zoom)).clamp(0.1, 10.0);
+        }
+        let delta = direction * PAN_SPEED * projection.scale * time.delta_seconds();
+        transform.translation += delta.extend(0.0);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn editor_input(
+    keyboard_input: Res<Input<KeyCode>>,
+    mouse_input: Res<Input<MouseButton>>,
+    windows: Res<Windows>,
+    controls_menu: Res<ControlsMenu>,
+    mut editor: ResMut<Editor>,
+    mut state: ResMut<State<GameState>>,
+    camera_query: Query<(&Camera, &GlobalTransform), With<EditorCamera>>,
+) {
+    if controls_menu.open {
+        return;
+    }
+    let control =
+        keyboard_input.pressed(KeyCode::LControl) || keyboard_input.pressed(KeyCode::RControl);
+    let shift = keyboard_input.pressed(KeyCode::LShift) || keyboard_input.pressed(KeyCode::RShift);
+
+    if keyboard_input.just_pressed(KeyCode::Escape) {
+        state.set(GameState::MainMenu).unwrap();
+        return;
+    }
+    if control {
+        if keyboard_input.just_pressed(KeyCode::Z) {
+            if shift {
+                editor.redo();
+            } else {
+                editor.undo();
+            }
+        }
+        if keyboard_input.just_pressed(KeyCode::Y) {
+            editor.redo();
+        }
+        if keyboard_input.just_pressed(KeyCode::S) {
+            let name = editor.name.clone();
+            editor.status = match save_map(&editor.map, &name) {
+                Ok(()) => {
+                    editor.saved = true;
+                    format!("saved {}", map_path(&name))
+                }
+                Err(e) => format!("can't save {}: {}", map_path(&name), e),
+            };
+        }
+    } else {
+        if keyboard_input.just_pressed(KeyCode::Key1) {
+            editor.tool = Tool::Wall;
+        }
+        if keyboard_input.just_pressed(KeyCode::Key2) {
+            editor.tool = Tool::Spawn;
+        }
+        if keyboard_input.just_pressed(KeyCode::Key3) {
+            editor.tool = Tool::Hive;
+        }
+        if keyboard_input.just_pressed(KeyCode::Key4) {
+            editor.tool = Tool::Flag;
+        }
+        if keyboard_input.just_pressed(KeyCode::Key5) {
+            editor.tool = Tool::Zone;
+        }
+        if keyboard_input.just_pressed(KeyCode::T) {
+            match editor.tool {
+                Tool::Spawn => editor.spawn_team = (editor.spawn_team + 1) % (MAX_TEAMS as i32 + 1),
+                Tool::Flag => editor.flag_team = editor.flag_team % MAX_TEAMS as i32 + 1,
+                _ => editor.wall_type = (editor.wall_type + 1) % WALL_TYPES,
+            }
+        }
+        if keyboard_input.just_pressed(KeyCode::G) {
+            editor.snap = !editor.snap;
+        }
+        if keyboard_input.just_pressed(KeyCode::LBracket) && editor.grid > 0 {
+            editor.grid -= 1;
+        }
+        if keyboard_input.just_pressed(KeyCode::RBracket) && editor.grid + 1 < GRID_SIZES.len() {
+            editor.grid += 1;
+        }
+    }
+
+    let cursor = match camera_query
+        .get_single()
+        .ok()
+        .and_then(|(camera, transform)| cursor_world_position(&windows, camera, transform))
+    {
+        Some(cursor) => cursor,
+        None => return,
+    };
+    let point = editor.snap(cursor);
+
+    if mouse_input.just_pressed(MouseButton::Right) {
+        editor.remove_at(cursor);
+        return;
+    }
+    match editor.tool {
+        Tool::Wall => {
+            if mouse_input.just_pressed(MouseButton::Left) {
+                editor.drag_start = Some(point);
+            }
+            if mouse_input.just_released(MouseButton::Left) {
+                let start = match editor.drag_start.take() {
+                    Some(start) => start,
+                    None => return,
+                };
+                let (min, max) = (start.min(point), start.max(point));
+                if min.x < max.x && min.y < max.y {
+                    let wall_type = editor.wall_type;
+                    editor
+                        .edit()
+                        .walls
+                        .push(vec![min.x, min.y, max.x, max.y, wall_type]);
+                } else if let Some(i) = wall_at(&editor.map, cursor) {
+                    // a click on a wall gives it the current type
+                    if editor.map.walls[i][4] != editor.wall_type {
+                        let wall_type = editor.wall_type;
+                        editor.edit().walls[i][4] = wall_type;
+                    }
+                }
+            }
+        }
+        Tool::Spawn => {
+            if mouse_input.just_pressed(MouseButton::Left) {
+                let team = editor.spawn_team;
+                editor.edit().lives.push(vec![point.x, point.y, team]);
+            }
+        }
+        Tool::Hive => {
+            if mouse_input.just_pressed(MouseButton::Left) {
+                editor.edit().hives.push(Hive::new(vec![point.x, point.y]));
+            }
+        }
+        Tool::Flag => {
+            if mouse_input.just_pressed(MouseButton::Left) {
+                // one flag per team, placing it again moves it
+                let team = editor.flag_team;
+                let map = editor.edit();
+                map.flags.retain(|f| f.get(2) != Some(&team));
+                map.flags.push(vec![point.x, point.y, team]);
+            }
+        }
+        Tool::Zone => {
+            if mouse_input.just_pressed(MouseButton::Left) {
+                editor.drag_start = Some(point);
+            }
+            if mouse_input.just_released(MouseButton::Left) {
+                let start = match editor.drag_start.take() {
+                    Some(start) => start,
+                    None => return,
+                };
+                let (min, max) = (start.min(point), start.max(point));
+                if min.x < max.x && min.y < max.y {
+                    editor.edit().zones.push(vec![min.x, min.y, max.x, max.y]);
+                }
+            }
+        }
+    }
+}
+
+fn spawn_color(team: i32) -> Color {
+    if team > 0 && team <= MAX_TEAMS as i32 {
+        team_color(team as usize - 1)
+    } else {
+        Color::WHITE
+    }
+}
+
+fn rect_sprite(min: Vec2, max: Vec2, z: f32, color: Color) -> SpriteBundle {
+    SpriteBundle {
+        transform: Transform {
+            translation: ((min + max) / 2.0).extend(z),
+            scale: (max - min).extend(1.0),
+            ..default()
+        },
+        sprite: Sprite { color, ..default() },
+        ..default()
+    }
+}
+
+// same look as setup_map, in map coordinates
+fn update_editor_shapes(
+    mut commands: Commands,
+    editor: Res<Editor>,
+    shape_query: Query<Entity, With<EditorShape>>,
+) {
+    if !editor.is_changed() {
+        return;
+    }
+    for entity in &shape_query {
+        commands.entity(entity).despawn();
+    }
+    let mut spawn = |bundle: SpriteBundle| {
+        commands
+            .spawn_bundle(bundle)
+            .insert(EditorShape)
+            .insert(EditorEntity);
+    };
+
+    for wall in &editor.map.walls {
+        let min = Vec2::new(wall[0] as f32, wall[1] as f32);
+        let max = Vec2::new(wall[2] as f32, wall[3] as f32);
+        let z = if wall[4] == 2 { -1.0 } else { 0.0 };
+        spawn(rect_sprite(
+            min - Vec2::splat(1.5),
+            max + Vec2::splat(1.5),
+            z - 0.1,
+            Color::BLACK,
+        ));
+        spawn(rect_sprite(min, max, z, wall_color(wall[4])));
+    }
+    for life in &editor.map.lives {
+        let position = Vec2::new(life[0] as f32, life[1] as f32);
+        let half = Vec2::splat(SPAWN_SIZE / 2.0);
+        spawn(rect_sprite(
+            position - half,
+            position + half,
+            2.0,
+            spawn_color(life.get(2).copied().unwrap_or(0)),
+        ));
+    }
+    for zone in &editor.map.zones {
+        spawn(rect_sprite(
+            Vec2::new(zone[0] as f32, zone[1] as f32),
+            Vec2::new(zone[2] as f32, zone[3] as f32),
+            -2.0,
+            ZONE_COLOR,
+        ));
+    }
+    for flag in &editor.map.flags {
+        let position = Vec2::new(flag[0] as f32, flag[1] as f32);
+        let half = Vec2::splat(FLAG_SIZE / 2.0);
+        spawn(rect_sprite(
+            position - half - Vec2::splat(1.5),
+            position + half + Vec2::splat(1.5),
+            2.9,
+            Color::BLACK,
+        ));
+        spawn(rect_sprite(
+            position - half,
+            position + half,
+            3.0,
+            spawn_color(flag.get(2).copied().unwrap_or(0)),
+        ));
+    }
+
+    // the grid, around what is there already
+    if editor.snap && !editor.map.walls.is_empty() {
+        let grid = editor.grid_size();
+        let margin = grid * 10;
+        let min_x = editor.map.walls.iter().map(|w| w[0]).min().unwrap_or(0) - margin;
+        let min_y = editor.map.walls.iter().map(|w| w[1]).min().unwrap_or(0) - margin;
+        let max_x = editor.map.walls.iter().map(|w| w[2]).max().unwrap_or(0) + margin;
+        let max_y = editor.map.walls.iter().map(|w| w[3]).max().unwrap_or(0) + margin;
+        let color = Color::rgba(1.0, 1.0, 1.0, 0.05);
+        let (min, max) = (
+            Vec2::new(min_x as f32, min_y as f32),
+            Vec2::new(max_x as f32, max_y as f32),
+        );
+        for x in (min_x.div_euclid(grid)..=max_x.div_euclid(grid)).map(|i| (i * grid) as f32) {
+            spawn(rect_sprite(
+                Vec2::new(x - 0.5, min.y),
+                Vec2::new(x + 0.5, max.y),
+                -5.0,
+                color,
+            ));
+        }
+        for y in (min_y.div_euclid(grid)..=max_y.div_euclid(grid)).map(|i| (i * grid) as f32) {
+            spawn(rect_sprite(
+                Vec2::new(min.x, y - 0.5),
+                Vec2::new(max.x, y + 0.5),
+                -5.0,
+                color,
+            ));
+        }
+    }
+
+    for hive in &editor.map.hives {
+        let position = Vec2::new(hive.pos[0] as f32, hive.pos[1] as f32);
+        commands
+            .spawn_bundle(MaterialMesh2dBundle {
+                mesh: editor.circle.clone().into(),
+                material: editor.hive_material.clone(),
+                transform: Transform {
+                    translation: position.extend(1.0),
+                    scale: Vec3::new(hive.radius as f32, hive.radius as f32, 1.0),
+                    ..default()
+                },
+                ..default()
+            })
+            .insert(EditorShape)
+            .insert(EditorEntity);
+    }
+}
+
+// the wall being dragged out, or the snapped cursor
+fn update_editor_preview(
+    windows: Res<Windows>,
+    editor: Res<Editor>,
+    camera_query: Query<(&Camera, &GlobalTransform), With<EditorCamera>>,
+    mut preview_query: Query<(&mut Transform, &mut Sprite, &mut Visibility), With<EditorPreview>>,
+) {
+    let cursor = camera_query
+        .get_single()
+        .ok()
+        .and_then(|(camera, transform)| cursor_world_position(&windows, camera, transform));
+    for (mut transform, mut sprite, mut visibility) in &mut preview_query {
+        let cursor = match cursor {
+            Some(cursor) => cursor,
+            None => {
+                visibility.is_visible = false;
+                continue;
+            }
+        };
+        visibility.is_visible = true;
+        let point = editor.snap(cursor).as_vec2();
+        let (min, max, color) = match (editor.tool, editor.drag_start) {
+            (Tool::Wall, Some(start)) => {
+                let start = start.as_vec2();
+                let mut color = wall_color(editor.wall_type);
+                color.set_a(0.5);
+                (start.min(point), start.max(point), color)
+            }
+            (Tool::Zone, Some(start)) => {
+                let start = start.as_vec2();
+                (start.min(point), start.max(point), ZONE_COLOR)
+            }
+            _ => (
+                point - Vec2::splat(2.0),
+                point + Vec2::splat(2.0),
+                Color::WHITE,
+            ),
+        };
+        transform.translation = ((min + max) / 2.0).extend(10.0);
+        transform.scale = (max - min).max(Vec2::ONE).extend(1.0);
+        sprite.color = color;
+    }
+}
+
+fn update_editor_text(editor: Res<Editor>, mut text_query: Query<&mut Text, With<EditorText>>) {
+    if !editor.is_changed() {
+        return;
+    }
+    let tool = match editor.tool {
+        Tool::Wall => format!("walls of type {}", editor.wall_type),
+        Tool::Spawn if editor.spawn_team == 0 => "spawn points for anyone".to_string(),
+        Tool::Spawn => format!("spawn points of team {}", editor.spawn_team),
+        Tool::Hive => "hives".to_string(),
+        Tool::Flag => format!("the flag of team {}", editor.flag_team),
+        Tool::Zone => "zones".to_string(),
+    };
+    let grid = if editor.snap {
+        editor.grid_size().to_string()
+    } else {
+        "off".to_string()
+    };
+    let value = format!(
+        "editing {}{}, placing {}, grid {}\n\
+         1 walls, 2 spawn points, 3 hives, 4 flags, 5 zones, T wall type or team, G grid\n\
+         [ ] grid size, drag to draw a wall or zone, click a wall to set its type\n\
+         right click deletes, arrows or WASD to move, wheel to zoom\n\
+         ctrl+z undo, ctrl+y redo, ctrl+s save, escape back to the menu\n\n{}",
+        editor.name,
+        if editor.saved { "" } else { " *" },
+        tool,
+        grid,
+        editor.status
+    );
+    for mut text in &mut text_query {
+        text.sections[0].value = value.clone();
+    }
+}
@@ -0,0 +1,191 @@
+use bevy::prelude::*;
+use ggrs::P2PSession;
+
+use crate::ctf::FlagAction;
+use crate::{GGRSConfig, MatchClock};
+
+// what the rollback systems report, for sounds and effects to follow
+#[derive(Clone, Debug)]
+pub enum SimEvent {
+    Shot {
+        handle: usize,
+        weapon: u8,
+        position: Vec2,
+        direction: Vec2,
+    },
+    WallHit {
+        owner: usize,
+        weapon: u8,
+        position: Vec2,
+        wall: Entity,
+    },
+    TankHit {
+        owner: usize,
+        victim: usize,
+        weapon: u8,
+        position: Vec2,
+    },
+    // a bullet's fuse ran out, whether it hit something or not
+    Explosion {
+        owner: usize,
+        weapon: u8,
+        position: Vec2,
+    },
+    Kill {
+        killer: Option<usize>,
+        victim: usize,
+        weapon: u8,
+        position: Vec2,
+        // by a teammate in a team round
+        teamkill: bool,
+    },
+    // the flag of team, handle is who did it, if anyone
+    Flag {
+        handle: Option<usize>,
+        team: usize,
+        action: FlagAction,
+        position: Vec2,
+    },
+    // a hive enemy, killer is the handle of the tank that shot it
+    EnemyKilled {
+        killer: usize,
+        kind: usize,
+        position: Vec2,
+    },
+}
+
+impl SimEvent {
+    // resimulating a frame can move things around a bit, what happened to
+    // whom stays the same
+    fn key(&self) -> (u8, usize) {
+        match self {
+            SimEvent::Shot { handle, .. } => (0, *handle),
+            SimEvent::WallHit { owner, .. } => (1, *owner),
+            SimEvent::TankHit { victim, .. } => (2, *victim),
+            SimEvent::Explosion { owner, .. } => (3, *owner),
+            SimEvent::Kill { victim, .. } => (4, *victim),
+            SimEvent::Flag { team, action, .. } => (5 + *action as u8, *team),
+            SimEvent::EnemyKilled { killer, .. } => (9, *killer),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FramedEvent {
+    // MatchClock::tick, the GGRS frame
+    pub frame: u32,
+    // how many events with the same key came before it in the frame, so
+    // that two hits on the same tank stay two
+    pub occurrence: usize,
+    pub event: SimEvent,
+}
+
+impl FramedEvent {
+    fn same(&self, other: &FramedEvent) -> bool {
+        self.frame == other.frame
+            && self.occurrence == other.occurrence
+            && self.event.key() == other.event.key()
+    }
+}
+
+// written by the rollback schedule, not rolled back itself: every frame
+// drops what an earlier run of the same frame emitted
+#[derive(Default)]
+pub struct SimEvents {
+    frame: u32,
+    // not confirmed yet
+    events: Vec<FramedEvent>,
+}
+
+impl SimEvents {
+    pub fn emit(&mut self, event: SimEvent) {
+        let key = event.key();
+        let occurrence = self
+            .events
+            .iter()
+            .filter(|e| e.frame == self.frame && e.event.key() == key)
+            .count();
+        self.events.push(FramedEvent {
+            frame: self.frame,
+            occurrence,
+            event,
+        });
+    }
+}
+
+// first thing in every rollback frame. the tick, unlike the frame of the
+// round, never goes back when a new round starts
+pub fn rewind_events(clock_query: Query<&MatchClock>, mut events: ResMut<SimEvents>) {
+    let frame = clock_query.iter().next().map_or(0, |c| c.tick);
+    events.frame = frame;
+    events.events.retain(|e| e.frame < frame);
+}
+
+// play it now, it may still be cancelled
+pub struct PlayEvent(pub SimEvent);
+
+// a rollback took back an event that was played
+pub struct CancelEvent(pub SimEvent);
+
+// no rollback can touch this one anymore
+pub struct ConfirmedEvent(pub SimEvent);
+
+pub struct EventsPlugin;
+
+impl Plugin for EventsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SimEvents>()
+            .init_resource::<PlayedEvents>()
+            .add_event::<PlayEvent>()
+            .add_event::<CancelEvent>()
+            .add_event::<ConfirmedEvent>()
+            .add_system(present_events);
+    }
+}
+
+// played and not confirmed yet
+#[derive(Default)]
+struct PlayedEvents(Vec<FramedEvent>);
+
+fn present_events(
+    sess: Option<Res<P2PSession<GGRSConfig>>>,
+    mut events: ResMut<SimEvents>,
+    mut played: ResMut<PlayedEvents>,
+    mut play_writer: EventWriter<PlayEvent>,
+    mut cancel_writer: EventWriter<CancelEvent>,
+    mut confirmed_writer: EventWriter<ConfirmedEvent>,
+) {
+    // the same counter as the ticks the events are tagged with
+    let confirmed = match sess {
+        Some(sess) => sess.confirmed_frame() as i64,
+        None => {
+            // the match is over, whatever is left goes with it
+            events.events.clear();
+            played.0.clear();
+            return;
+        }
+    };
+
+    for event in &events.events {
+        if !played.0.iter().any(|p| p.same(event)) {
+            play_writer.send(PlayEvent(event.event.clone()));
+            played.0.push(event.clone());
+        }
+    }
+    played.0.retain(|p| {
+        let kept = events.events.iter().any(|e| e.same(p));
+        if !kept {
+            cancel_writer.send(CancelEvent(p.event.clone()));
+        }
+        kept
+    });
+
+    events.events.retain(|e| {
+        let is_confirmed = e.frame as i64 <= confirmed;
+        if is_confirmed {
+            confirmed_writer.send(ConfirmedEvent(e.event.clone()));
+        }
+        !is_confirmed
+    });
+    played.0.retain(|p| p.frame as i64 > confirmed);
+}
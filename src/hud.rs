@@ -0,0 +1,314 @@
+use bevy::prelude::*;
+
+use crate::camera::viewport_rect;
+use crate::koth::{add_hill_scores, HillScore};
+use crate::playlist::Rotation;
+use crate::survival::Survival;
+use crate::teams::{team_color, Teams};
+use crate::{
+    Arsenal, GameState, Health, LocalPlayers, MatchClock, MatchEntity, Player, Score, FPS, MAX_HP,
+    SHELL_AMMO, WEAPONS,
+};
+
+const PANEL_MARGIN: f32 = 10.0;
+const PANEL_WIDTH: f32 = 180.0;
+const HEALTH_BAR_HEIGHT: f32 = 8.0;
+const FONT_SIZE: f32 = 16.0;
+
+pub struct HudPlugin;
+
+impl Plugin for HudPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set(SystemSet::on_enter(GameState::Loading).with_system(spawn_hud))
+            .add_system(update_hud_layout)
+            .add_system(update_hud_panels)
+            .add_system(update_hud_timer);
+    }
+}
+
+// the hud only ever reads the rollback components, so a rollback simply
+// shows up as a corrected number on the next frame
+
+#[derive(Component)]
+struct HudPanel {
+    // position among the local players, decides the viewport
+    index: usize,
+}
+
+#[derive(Component)]
+struct HudText {
+    handle: usize,
+}
+
+#[derive(Component)]
+struct HudHealthBar {
+    handle: usize,
+}
+
+#[derive(Component)]
+struct HudTimer;
+
+fn spawn_hud(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    local_players: Res<LocalPlayers>,
+    teams: Res<Teams>,
+) {
+    let font = asset_server.load("fonts/DejaVuSans.ttf");
+
+    for (index, handle) in local_players.0.iter().enumerate() {
+        let color = teams.color(*handle);
+        commands
+            .spawn_bundle(NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    flex_direction: FlexDirection::ColumnReverse,
+                    size: Size::new(Val::Px(PANEL_WIDTH), Val::Auto),
+                    padding: UiRect::all(Val::Px(6.0)),
+                    ..default()
+                },
+                color: Color::rgba(0.0, 0.0, 0.0, 0.5).into(),
+                ..default()
+            })
+            .insert(HudPanel { index })
+            .insert(MatchEntity)
+            .with_children(|panel| {
+                panel
+                    .spawn_bundle(TextBundle::from_sections((0..3).map(|_| TextSection {
+                        value: String::new(),
+                        style: TextStyle {
+                            font: font.clone(),
+                            font_size: FONT_SIZE,
+                            color: Color::WHITE,
+                        },
+                    })))
+                    .insert(HudText { handle: *handle });
+
+                // health bar
+                panel
+                    .spawn_bundle(NodeBundle {
+                        style: Style {
+                            size: Size::new(Val::Percent(100.0), Val::Px(HEALTH_BAR_HEIGHT)),
+                            margin: UiRect {
+                                top: Val::Px(4.0),
+                                ..default()
+                            },
+                            ..default()
+                        },
+                        color: Color::rgb(0.2, 0.2, 0.2).into(),
+                        ..default()
+                    })
+                    .with_children(|bar| {
+                        bar.spawn_bundle(NodeBundle {
+                            style: Style {
+                                size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+                                ..default()
+                            },
+                            color: color.into(),
+                            ..default()
+                        })
+                        .insert(HudHealthBar { handle: *handle });
+                    });
+            });
+    }
+
+    // match timer, top center of the window
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    left: Val::Px(0.0),
+                    top: Val::Px(PANEL_MARGIN),
+                    ..default()
+                },
+                size: Size::new(Val::Percent(100.0), Val::Auto),
+                justify_content: JustifyContent::Center,
+                ..default()
+            },
+            color: Color::NONE.into(),
+            ..default()
+        })
+        .insert(MatchEntity)
+        .with_children(|parent| {
+            parent
+                .spawn_bundle(TextBundle::from_section(
+                    "",
+                    TextStyle {
+                        font,
+                        font_size: FONT_SIZE * 1.5,
+                        color: Color::WHITE,
+                    },
+                ))
+                .insert(HudTimer);
+        });
+}
+
+// keeps each panel in the bottom left corner of its player's viewport
+fn update_hud_layout(
+    windows: Res<Windows>,
+    local_players: Res<LocalPlayers>,
+    mut panel_query: Query<(&HudPanel, &mut Style)>,
+) {
+    let window = match windows.get_primary() {
+        Some(window) => window,
+        None => return,
+    };
+    let scale = window.scale_factor() as f32;
+    let window_size = UVec2::new(window.physical_width(), window.physical_height());
+    for (panel, mut style) in &mut panel_query {
+        let (position, size) = viewport_rect(panel.index, local_players.0.len(), window_size);
+        let left = Val::Px(position.x as f32 / scale + PANEL_MARGIN);
+        let bottom = Val::Px(
+            window_size.y.saturating_sub(position.y + size.y) as f32 / scale + PANEL_MARGIN,
+        );
+        if style.position.left != left || style.position.bottom != bottom {
+            style.position = UiRect {
+                left,
+                bottom,
+                ..default()
+            };
+        }
+    }
+}
+
+fn update_hud_panels(
+    clock_query: Query<&MatchClock>,
+    rotation: Option<Res<Rotation>>,
+    survival_query: Query<&Survival>,
+    player_query: Query<(&Player, &Health, &Arsenal, &Score)>,
+    mut text_query: Query<(&HudText, &mut Text)>,
+    mut bar_query: Query<(&HudHealthBar, &mut Style)>,
+) {
+    let coop = match (rotation, clock_query.get_single()) {
+        (Some(rotation), Ok(clock)) => rotation.round(clock.round).mode.is_coop(),
+        _ => false,
+    };
+    let out_of_lives = coop && survival_query.iter().any(|s| s.lives <= 0);
+    for (hud_text, mut text) in &mut text_query {
+        let (_, health, arsenal, score) = match player_query
+            .iter()
+            .find(|(p, ..)| p.handle == hud_text.handle)
+        {
+            Some(found) => found,
+            None => continue,
+        };
+
+        let status = if health.is_alive() {
+            format!("HP {:.0}/{:.0}\n", health.hp, MAX_HP)
+        } else if out_of_lives && health.respawn <= 1.0 / FPS as f32 {
+            "out of lives\n".to_string()
+        } else {
+            format!("respawn in {:.1}s\n", health.respawn)
+        };
+        let weapon = WEAPONS[arsenal.weapon as usize].name;
+        let cooldown = if arsenal.cooldown > 0.0 {
+            format!("{:.1}s", arsenal.cooldown)
+        } else {
+            "ready".to_string()
+        };
+        let mut weapons = format!(
+            "{} {}  shells {}/{}",
+            weapon, cooldown, arsenal.ammo, SHELL_AMMO
+        );
+        if arsenal.ammo < SHELL_AMMO {
+            weapons += &format!(" ({:.1}s)", arsenal.reload);
+        }
+        weapons += "\n";
+        let score = format!("kills {}  deaths {}", score.kills, score.deaths);
+
+        for (section, value) in text.sections.iter_mut().zip([status, weapons, score]) {
+            if section.value != value {
+                section.value = value;
+            }
+        }
+    }
+
+    for (bar, mut style) in &mut bar_query {
+        let hp = match player_query.iter().find(|(p, ..)| p.handle == bar.handle) {
+            Some((_, health, ..)) => health.hp,
+            None => continue,
+        };
+        let width = Val::Percent((hp / MAX_HP).clamp(0.0, 1.0) * 100.0);
+        if style.size.width != width {
+            style.size.width = width;
+        }
+    }
+}
+
+// with the team scores below it in a team round, and the waves instead of
+// the time left in a survival round
+fn update_hud_timer(
+    clock_query: Query<&MatchClock>,
+    rotation: Option<Res<Rotation>>,
+    teams: Res<Teams>,
+    player_query: Query<(&Player, &Score)>,
+    hill_query: Query<&HillScore>,
+    survival_query: Query<&Survival>,
+    mut text_query: Query<&mut Text, With<HudTimer>>,
+) {
+    let clock = match clock_query.get_single() {
+        Ok(clock) => clock,
+        Err(_) => return,
+    };
+    let mode = rotation.map(|r| r.round(clock.round).mode);
+    let survival = survival_query
+        .get_single()
+        .ok()
+        .filter(|_| mode.map_or(false, |m| m.is_coop()));
+    let time = match survival {
+        Some(_) => clock.frame.min(clock.duration) / FPS as u32,
+        None => clock.time_left().ceil() as u32,
+    };
+    let mut values = vec![(format!("{}:{:02}", time / 60, time % 60), Color::WHITE)];
+    if let Some(survival) = survival {
+        let wave = if survival.pause > 0 {
+            let seconds = (survival.pause + FPS as u32 - 1) / FPS as u32;
+            format!("\nwave {} in {}s", survival.wave + 1, seconds)
+        } else {
+            format!("\nwave {}", survival.wave)
+        };
+        values.push((wave, Color::WHITE));
+        values.push((format!("  lives {}", survival.lives.max(0)), Color::WHITE));
+    }
+    if let Some(mode) = mode.filter(|m| m.has_teams()) {
+        // kills, captures or points for holding zones
+        let mut scores = teams.scores(
+            player_query
+                .iter()
+                .map(|(p, s)| (p.handle, mode.team_score(s))),
+        );
+        if mode.has_zones() {
+            add_hill_scores(&mut scores, &hill_query);
+        }
+        for (team, score) in scores.iter().enumerate() {
+            let separator = if team == 0 { "\n" } else { "  " };
+            values.push((
+                format!("{}T{} {}", separator, team + 1, score),
+                team_color(team),
+            ));
+        }
+    }
+    for mut text in &mut text_query {
+        let unchanged = text.sections.len() == values.len()
+            && text
+                .sections
+                .iter()
+                .zip(&values)
+                .all(|(section, (value, _))| section.value == *value);
+        if unchanged {
+            continue;
+        }
+        let style = text.sections[0].style.clone();
+        text.sections = values
+            .iter()
+            .map(|(value, color)| TextSection {
+                value: value.clone(),
+                style: TextStyle {
+                    color: *color,
+                    ..style.clone()
+                },
+            })
+            .collect();
+    }
+}
@@ -0,0 +1,223 @@
+use bevy::prelude::*;
+use bevy_ggrs::{Rollback, RollbackIdProvider};
+
+use crate::map::Map;
+use crate::playlist::Rotation;
+use crate::teams::{team_color, Teams};
+use crate::{Health, MatchClock, MatchEntity, Player, FPS};
+
+// seconds a team has to hold a zone on its own for a point
+const ZONE_TIME: f32 = 3.0;
+// seconds a contested zone takes to lose all of its progress
+const ZONE_DECAY_TIME: f32 = 2.0;
+// under the walls
+const ZONE_Z: f32 = -2.0;
+const BAR_HEIGHT: f32 = 4.0;
+
+pub struct KothPlugin;
+
+impl Plugin for KothPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(show_zones);
+    }
+}
+
+// a rectangle of the map, on its own rollback entity
+#[derive(Component, Default, Reflect)]
+pub struct Zone {
+    // see MapLayer
+    pub layer: usize,
+    pub min: Vec2,
+    pub max: Vec2,
+    // the team the progress is for, -1 for nobody
+    pub team: i32,
+    // towards the next point, from 0 to 1
+    pub progress: f32,
+}
+
+impl Zone {
+    fn contains(&self, point: Vec2) -> bool {
+        point.cmpge(self.min).all() && point.cmple(self.max).all()
+    }
+}
+
+// the points of a team, on its own rollback entity since no player owns them
+#[derive(Component, Default, Reflect)]
+pub struct HillScore {
+    pub team: usize,
+    pub points: i32,
+}
+
+// the progress bar on top of a zone
+#[derive(Component)]
+struct ZoneBar(Entity);
+
+// the map has been validated
+pub fn spawn_zones(commands: &mut Commands, rip: &mut RollbackIdProvider, map: &Map, layer: usize) {
+    let (min, max) = map.bounds().unwrap_or_default();
+    let origin = (max - min).as_vec2();
+    for zone in &map.zones {
+        let min = Vec2::new(zone[0] as f32, zone[1] as f32) - origin / 2.0;
+        let max = Vec2::new(zone[2] as f32, zone[3] as f32) - origin / 2.0;
+        let entity = commands
+            .spawn_bundle(SpriteBundle {
+                transform: Transform {
+                    translation: ((min + max) / 2.0).extend(ZONE_Z),
+                    scale: (max - min).extend(1.0),
+                    ..default()
+                },
+                visibility: Visibility { is_visible: false },
+                ..default()
+            })
+            .insert(Zone {
+                layer,
+                min,
+                max,
+                team: -1,
+                progress: 0.0,
+            })
+            .insert(Rollback::new(rip.next_id()))
+            .id();
+        commands
+            .spawn_bundle(SpriteBundle {
+                visibility: Visibility { is_visible: false },
+                ..default()
+            })
+            .insert(ZoneBar(entity))
+            .insert(MatchEntity);
+    }
+}
+
+pub fn spawn_hill_scores(commands: &mut Commands, rip: &mut RollbackIdProvider, teams: &Teams) {
+    for team in 0..teams.count {
+        commands
+            .spawn()
+            .insert(HillScore { team, points: 0 })
+            .insert(Rollback::new(rip.next_id()));
+    }
+}
+
+// who is in every zone of the current map, from where the tanks are now
+pub fn update_zones(
+    rotation: Res<Rotation>,
+    teams: Res<Teams>,
+    clock_query: Query<&MatchClock>,
+    player_query: Query<(&Player, &Health, &Transform)>,
+    mut zone_query: Query<&mut Zone>,
+    mut score_query: Query<&mut HillScore>,
+) {
+    let clock = match clock_query.iter().next() {
+        Some(clock) => clock,
+        None => return,
+    };
+    let round = rotation.round(clock.round);
+    // a round starts with every zone neutral, and maybe from zero
+    if clock.frame == 0 {
+        for mut zone in &mut zone_query {
+            zone.team = -1;
+            zone.progress = 0.0;
+        }
+        if round.reset_scores {
+            for mut score in &mut score_query {
+                score.points = 0;
+            }
+        }
+    }
+    if !round.mode.has_zones() || clock.expired() {
+        return;
+    }
+    let dt = 1.0 / FPS as f32;
+
+    let mut points = vec![0; teams.count];
+    for mut zone in &mut zone_query {
+        if zone.layer != round.layer {
+            continue;
+        }
+        let mut inside: Vec<usize> = player_query
+            .iter()
+            .filter(|(_, health, transform)| {
+                health.is_alive() && zone.contains(transform.translation.truncate())
+            })
+            .filter_map(|(player, ..)| teams.team(player.handle))
+            .collect();
+        inside.sort_unstable();
+        inside.dedup();
+
+        match inside[..] {
+            [] => {}
+            [team] if zone.team == team as i32 || zone.team < 0 => {
+                zone.team = team as i32;
+                zone.progress += dt / ZONE_TIME;
+                if zone.progress >= 1.0 {
+                    zone.progress = 0.0;
+                    points[team] += 1;
+                }
+            }
+            // the progress of another team has to go before this one's starts
+            [team] => {
+                zone.progress -= dt / ZONE_DECAY_TIME;
+                if zone.progress <= 0.0 {
+                    zone.progress = 0.0;
+                    zone.team = team as i32;
+                }
+            }
+            _ => zone.progress = (zone.progress - dt / ZONE_DECAY_TIME).max(0.0),
+        }
+    }
+    for mut score in &mut score_query {
+        if let Some(points) = points.get(score.team) {
+            score.points += points;
+        }
+    }
+}
+
+pub fn add_hill_scores(scores: &mut [i32], hill_query: &Query<&HillScore>) {
+    for hill in hill_query {
+        if let Some(score) = scores.get_mut(hill.team) {
+            *score += hill.points;
+        }
+    }
+}
+
+// the zones of the current map when they are played for, in the color of
+// the team making progress
+#[allow(clippy::type_complexity)]
+fn show_zones(
+    rotation: Option<Res<Rotation>>,
+    clock_query: Query<&MatchClock>,
+    mut zone_query: Query<(&Zone, &mut Sprite, &mut Visibility), Without<ZoneBar>>,
+    mut bar_query: Query<(&ZoneBar, &mut Transform, &mut Sprite, &mut Visibility)>,
+) {
+    let (rotation, clock) = match (rotation, clock_query.get_single()) {
+        (Some(rotation), Ok(clock)) => (rotation, clock),
+        _ => return,
+    };
+    let round = rotation.round(clock.round);
+    let color = |zone: &Zone| {
+        if zone.team >= 0 {
+            team_color(zone.team as usize)
+        } else {
+            Color::WHITE
+        }
+    };
+    for (zone, mut sprite, mut visibility) in &mut zone_query {
+        visibility.is_visible = round.mode.has_zones() && zone.layer == round.layer;
+        let mut zone_color = color(zone);
+        zone_color.set_a(0.15);
+        sprite.color = zone_color;
+    }
+    for (bar, mut transform, mut sprite, mut visibility) in &mut bar_query {
+        let zone = match zone_query.get(bar.0) {
+            Ok((zone, _, zone_visibility)) if zone_visibility.is_visible => zone,
+            _ => {
+                visibility.is_visible = false;
+                continue;
+            }
+        };
+        let width = (zone.max.x - zone.min.x) * zone.progress;
+        visibility.is_visible = width > 0.0;
+        transform.translation = Vec3::new(zone.min.x + width / 2.0, zone.max.y + BAR_HEIGHT, 10.0);
+        transform.scale = Vec3::new(width.max(1.0), BAR_HEIGHT, 1.0);
+        sprite.color = color(zone);
+    }
+}
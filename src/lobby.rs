@@ -0,0 +1,1049 @@
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::map::{map_hash, map_path, MAPS_DIR};
+use crate::mapgen::parse_generated;
+use crate::playlist::{parse_playlist, MatchSettings, Playlist};
+use crate::signaling_protocol::{self, MapFile, MapInfo, SignalingMessage};
+use crate::MatchConfig;
+
+// the host broadcasts its match to this port, clients listen on it
+pub const LAN_DISCOVERY_PORT: u16 = 40400;
+
+const LOBBY_MAGIC: u32 = 0x74616e6b;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_millis(500);
+const RESEND_INTERVAL: Duration = Duration::from_millis(200);
+// a client keeps acking the start message for a while, so that the host
+// doesn't keep resending it to the socket that ggrs is about to use
+const START_LINGER: Duration = Duration::from_millis(1000);
+// map files go over in pieces this big, well under the largest datagram
+const MAP_CHUNK: usize = 1024;
+// chunks asked for at once, and how often
+const MAP_WINDOW: usize = 16;
+const MAP_REQUEST_INTERVAL: Duration = Duration::from_millis(50);
+// nobody is sending us a bigger map than this
+const MAX_MAP_SIZE: usize = 1 << 20;
+// of a serialized MapInfo, the start messages carry it along with the
+// addresses of every player and have to fit in a datagram
+const MAX_MAP_INFO: usize = 32 * 1024;
+
+// what every peer needs to create the ggrs session: the player list in the
+// same format as the --players flag, the map to load and the settings and
+// playlist of the host, with the maps under their local names
+#[derive(Clone, Debug)]
+pub struct LobbyOutcome {
+    pub players: Vec<String>,
+    pub map: String,
+    pub settings: Option<MatchSettings>,
+    pub playlist: Option<Playlist>,
+}
+
+impl LobbyOutcome {
+    fn new(players: Vec<String>, map: &MapInfo) -> io::Result<Self> {
+        let settings = match &map.settings {
+            Some(json) => Some(
+                serde_json::from_str(json)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            ),
+            None => None,
+        };
+        let playlist = match &map.playlist {
+            Some(json) => Some(parse_playlist(json)?),
+            None => None,
+        };
+        Ok(Self {
+            players,
+            map: map.name.clone(),
+            settings,
+            playlist,
+        })
+    }
+}
+
+pub trait Lobby {
+    // non blocking, returns the outcome once everyone agreed on the match
+    fn poll(&mut self) -> io::Result<Option<LobbyOutcome>>;
+    fn status(&self) -> String;
+}
+
+#[derive(Serialize, Deserialize)]
+struct Packet {
+    magic: u32,
+    message: LobbyMessage,
+}
+
+#[derive(Serialize, Deserialize)]
+enum LobbyMessage {
+    Announce {
+        map: String,
+        num_players: usize,
+        joined: usize,
+    },
+    Join,
+    Joined {
+        handle: usize,
+        joined: usize,
+        num_players: usize,
+    },
+    // peers holds the addresses of handles 1.., the host is handle 0
+    Start {
+        handle: usize,
+        peers: Vec<SocketAddr>,
+        map: MapInfo,
+    },
+    // sent once the map is there, to the host or to handle 0 of a room
+    StartAck,
+    MapRequest {
+        hash: u64,
+        chunk: usize,
+    },
+    MapChunk {
+        hash: u64,
+        chunk: usize,
+        data: Vec<u8>,
+    },
+}
+
+fn send(socket: &UdpSocket, addr: SocketAddr, message: LobbyMessage) -> io::Result<()> {
+    let packet = Packet {
+        magic: LOBBY_MAGIC,
+        message,
+    };
+    let bytes = bincode::serialize(&packet).expect("lobby message serialization failed");
+    match socket.send_to(&bytes, addr) {
+        Ok(_) => Ok(()),
+        // udp is lossy anyway, the message will be resent
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+fn decode(bytes: &[u8]) -> Option<LobbyMessage> {
+    match bincode::deserialize::<Packet>(bytes) {
+        Ok(packet) if packet.magic == LOBBY_MAGIC => Some(packet.message),
+        _ => None,
+    }
+}
+
+fn receive(socket: &UdpSocket) -> io::Result<Option<(SocketAddr, LobbyMessage)>> {
+    let mut buffer = [0u8; signaling_protocol::MAX_DATAGRAM];
+    loop {
+        match socket.recv_from(&mut buffer) {
+            Ok((len, addr)) => match decode(&buffer[..len]) {
+                Some(message) => return Ok(Some((addr, message))),
+                // not for us, skip it
+                None => continue,
+            },
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+            // on some platforms an icmp port unreachable surfaces here
+            Err(e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn bind(port: u16) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port))?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+// lets every client on this machine listen for the same announcements
+fn bind_shared(port: u16) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into())?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+fn due(last: Option<Instant>, interval: Duration) -> bool {
+    last.map_or(true, |t| t.elapsed() >= interval)
+}
+
+// the contents of map files by hash
+type MapFiles = Vec<(u64, Vec<u8>)>;
+
+// the maps a host offers, with the files for peers that don't have them
+fn map_offer(config: &MatchConfig) -> io::Result<(MapInfo, MapFiles)> {
+    let mut info = map_info(config);
+    let mut files = Vec::new();
+    if let Some((hash, data)) = read_map_file(&info.name)? {
+        info.hash = Some(hash);
+        info.size = data.len();
+        files.push((hash, data));
+    }
+    for other in &mut info.others {
+        if let Some((hash, data)) = read_map_file(&other.name)? {
+            other.hash = Some(hash);
+            other.size = data.len();
+            files.push((hash, data));
+        }
+    }
+    check_map_info(&info)?;
+    Ok((info, files))
+}
+
+fn check_map_info(info: &MapInfo) -> io::Result<()> {
+    let size = bincode::serialized_size(info).expect("map info serialization failed") as usize;
+    if size > MAX_MAP_INFO {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "the maps and settings of the match take {} bytes, more than the {} that fit \
+                 in a lobby message, try a shorter playlist",
+                size, MAX_MAP_INFO
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// generated maps are built from their name and need no file
+fn read_map_file(name: &str) -> io::Result<Option<(u64, Vec<u8>)>> {
+    if parse_generated(name).is_some() {
+        return Ok(None);
+    }
+    let path = map_path(name);
+    let data = fs::read(&path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+    Ok(Some((map_hash(&data), data)))
+}
+
+// without the files
+fn map_info(config: &MatchConfig) -> MapInfo {
+    let settings =
+        serde_json::to_string(&MatchSettings::of(config)).expect("settings serialization failed");
+    let playlist = config
+        .playlist
+        .as_ref()
+        .map(|p| serde_json::to_string(p).expect("playlist serialization failed"));
+    let mut others: Vec<MapFile> = Vec::new();
+    for entry in config.playlist.iter().flat_map(|p| &p.entries) {
+        if entry.map != config.map && !others.iter().any(|o| o.name == entry.map) {
+            others.push(MapFile {
+                name: entry.map.clone(),
+                hash: None,
+                size: 0,
+            });
+        }
+    }
+    MapInfo {
+        name: config.map.clone(),
+        hash: None,
+        size: 0,
+        settings: Some(settings),
+        playlist,
+        others,
+    }
+}
+
+// the first map and the others of the playlist
+fn match_files(info: &MapInfo) -> Vec<MapFile> {
+    let first = MapFile {
+        name: info.name.clone(),
+        hash: info.hash,
+        size: info.size,
+    };
+    std::iter::once(first)
+        .chain(info.others.iter().cloned())
+        .collect()
+}
+
+fn send_map_chunk(
+    socket: &UdpSocket,
+    addr: SocketAddr,
+    files: &[(u64, Vec<u8>)],
+    hash: u64,
+    chunk: usize,
+) -> io::Result<()> {
+    let data = match files.iter().find(|(h, _)| *h == hash) {
+        Some((_, data)) => data,
+        None => return Ok(()),
+    };
+    if chunk * MAP_CHUNK >= data.len() {
+        return Ok(());
+    }
+    let end = (chunk * MAP_CHUNK + MAP_CHUNK).min(data.len());
+    send(
+        socket,
+        addr,
+        LobbyMessage::MapChunk {
+            hash,
+            chunk,
+            data: data[chunk * MAP_CHUNK..end].to_vec(),
+        },
+    )
+}
+
+// the names a received map may be kept under in assets/maps: its own unless
+// a different map has it already. the name comes from the network, so it
+// doesn't get near a path unless it is a plain one
+fn cache_names(name: &str, hash: u64) -> Vec<String> {
+    let plain = !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if plain {
+        vec![name.to_string(), format!("{}-{:016x}", name, hash)]
+    } else {
+        vec![format!("map-{:016x}", hash)]
+    }
+}
+
+// the local name of the map, if we have it already. a map without a hash
+// has to be a generated one
+fn cached_map(file: &MapFile) -> Option<String> {
+    let hash = match file.hash {
+        Some(hash) => hash,
+        None => return parse_generated(&file.name).map(|_| file.name.clone()),
+    };
+    cache_names(&file.name, hash)
+        .into_iter()
+        .find(|name| fs::read(map_path(name)).map_or(false, |data| map_hash(&data) == hash))
+}
+
+fn cache_map(file: &MapFile, hash: u64, data: &[u8]) -> io::Result<String> {
+    let names = cache_names(&file.name, hash);
+    let name = names
+        .iter()
+        .find(|name| !Path::new(&map_path(name)).exists())
+        .unwrap_or(&names[names.len() - 1]);
+    fs::create_dir_all(MAPS_DIR)?;
+    fs::write(map_path(name), data)?;
+    Ok(name.clone())
+}
+
+// fetches the maps of a match the peer doesn't have from the one that has
+// them, one after the other
+struct MapDownload {
+    // still to come, the first one is on its way
+    files: Vec<MapFile>,
+    from: SocketAddr,
+    chunks: Vec<Option<Vec<u8>>>,
+    last_request: Option<Instant>,
+    // the local name of every map of the match, by the name the host gave it
+    names: Vec<(String, String)>,
+    outcome: LobbyOutcome,
+}
+
+impl MapDownload {
+    fn new(info: &MapInfo, from: SocketAddr, outcome: LobbyOutcome) -> io::Result<Self> {
+        let mut files = Vec::new();
+        let mut names = Vec::new();
+        for file in match_files(info) {
+            if let Some(name) = cached_map(&file) {
+                names.push((file.name, name));
+                continue;
+            }
+            match file.hash {
+                Some(_) if file.size > 0 && file.size <= MAX_MAP_SIZE => files.push(file),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("can't download map {} of {} bytes", file.name, file.size),
+                    ))
+                }
+            }
+        }
+        let mut download = Self {
+            files,
+            from,
+            chunks: Vec::new(),
+            last_request: None,
+            names,
+            outcome,
+        };
+        download.next_file();
+        Ok(download)
+    }
+
+    fn next_file(&mut self) {
+        let size = self.files.first().map_or(0, |file| file.size);
+        self.chunks = vec![None; (size + MAP_CHUNK - 1) / MAP_CHUNK];
+        self.last_request = None;
+    }
+
+    // the outcome once every map is here, under its local name
+    fn finished(&self) -> Option<LobbyOutcome> {
+        if !self.files.is_empty() {
+            return None;
+        }
+        let local = |name: &String| {
+            self.names
+                .iter()
+                .find(|(host, _)| host == name)
+                .map_or(name.clone(), |(_, local)| local.clone())
+        };
+        let mut outcome = self.outcome.clone();
+        outcome.map = local(&outcome.map);
+        if let Some(playlist) = &mut outcome.playlist {
+            for entry in &mut playlist.entries {
+                entry.map = local(&entry.map);
+            }
+        }
+        Some(outcome)
+    }
+
+    fn request(&mut self, socket: &UdpSocket) -> io::Result<()> {
+        let hash = match self.files.first().and_then(|file| file.hash) {
+            Some(hash) => hash,
+            None => return Ok(()),
+        };
+        if !due(self.last_request, MAP_REQUEST_INTERVAL) {
+            return Ok(());
+        }
+        self.last_request = Some(Instant::now());
+        let missing = self.chunks.iter().enumerate().filter(|(_, c)| c.is_none());
+        for (chunk, _) in missing.take(MAP_WINDOW) {
+            send(socket, self.from, LobbyMessage::MapRequest { hash, chunk })?;
+        }
+        Ok(())
+    }
+
+    // the outcome once the last map is here and checks out
+    fn receive(
+        &mut self,
+        hash: u64,
+        chunk: usize,
+        data: Vec<u8>,
+    ) -> io::Result<Option<LobbyOutcome>> {
+        let file = match self.files.first() {
+            Some(file) if file.hash == Some(hash) => file,
+            _ => return Ok(None),
+        };
+        let expected = MAP_CHUNK.min(file.size.saturating_sub(chunk * MAP_CHUNK));
+        if chunk >= self.chunks.len() || data.len() != expected {
+            return Ok(None);
+        }
+        self.chunks[chunk] = Some(data);
+        if self.chunks.iter().any(|c| c.is_none()) {
+            return Ok(None);
+        }
+        let data: Vec<u8> = self.chunks.iter().flatten().flatten().copied().collect();
+        if map_hash(&data) != hash {
+            // something got mangled on the way, start over
+            self.chunks.iter_mut().for_each(|c| *c = None);
+            return Ok(None);
+        }
+        let name = cache_map(file, hash, &data)?;
+        let file = self.files.remove(0);
+        self.names.push((file.name, name));
+        self.next_file();
+        Ok(self.finished())
+    }
+
+    fn status(&self) -> String {
+        let file = match self.files.first() {
+            Some(file) => file,
+            None => return "maps downloaded".to_string(),
+        };
+        let received = self.chunks.iter().filter(|c| c.is_some()).count();
+        let left = if self.files.len() > 1 {
+            format!(", {} more maps after it", self.files.len() - 1)
+        } else {
+            String::new()
+        };
+        format!(
+            "downloading {}, {}/{} kB{}",
+            file.name,
+            (received * MAP_CHUNK).min(file.size) / 1024,
+            file.size / 1024,
+            left
+        )
+    }
+}
+
+// handle 0, waits for num_players - 1 clients and then assigns their handles
+// in join order
+pub struct LanHost {
+    socket: UdpSocket,
+    map: MapInfo,
+    // the map files by hash, for clients that don't have them
+    map_files: MapFiles,
+    num_players: usize,
+    peers: Vec<SocketAddr>,
+    acked: Vec<bool>,
+    last_send: Option<Instant>,
+}
+
+impl LanHost {
+    pub fn new(local_port: u16, num_players: usize, config: &MatchConfig) -> io::Result<Self> {
+        let (map, map_files) = map_offer(config)?;
+        let socket = bind(local_port)?;
+        socket.set_broadcast(true)?;
+        Ok(Self {
+            socket,
+            map,
+            map_files,
+            num_players,
+            peers: Vec::new(),
+            acked: Vec::new(),
+            last_send: None,
+        })
+    }
+
+    fn is_full(&self) -> bool {
+        self.peers.len() + 1 >= self.num_players
+    }
+}
+
+impl Lobby for LanHost {
+    fn poll(&mut self) -> io::Result<Option<LobbyOutcome>> {
+        while let Some((addr, message)) = receive(&self.socket)? {
+            match message {
+                LobbyMessage::Join => {
+                    if !self.peers.contains(&addr) && !self.is_full() {
+                        self.peers.push(addr);
+                        self.acked.push(false);
+                    }
+                    if let Some(i) = self.peers.iter().position(|p| *p == addr) {
+                        send(
+                            &self.socket,
+                            addr,
+                            LobbyMessage::Joined {
+                                handle: i + 1,
+                                joined: self.peers.len() + 1,
+                                num_players: self.num_players,
+                            },
+                        )?;
+                    }
+                }
+                LobbyMessage::StartAck => {
+                    if let Some(i) = self.peers.iter().position(|p| *p == addr) {
+                        self.acked[i] = true;
+                    }
+                }
+                LobbyMessage::MapRequest { hash, chunk } if self.peers.contains(&addr) => {
+                    send_map_chunk(&self.socket, addr, &self.map_files, hash, chunk)?;
+                }
+                _ => {}
+            }
+        }
+
+        if !self.is_full() {
+            if due(self.last_send, ANNOUNCE_INTERVAL) {
+                self.last_send = Some(Instant::now());
+                let broadcast = SocketAddrV4::new(Ipv4Addr::BROADCAST, LAN_DISCOVERY_PORT);
+                send(
+                    &self.socket,
+                    broadcast.into(),
+                    LobbyMessage::Announce {
+                        map: self.map.name.clone(),
+                        num_players: self.num_players,
+                        joined: self.peers.len() + 1,
+                    },
+                )?;
+            }
+            return Ok(None);
+        }
+
+        if self.acked.iter().all(|a| *a) {
+            let mut players = vec!["localhost".to_string()];
+            players.extend(self.peers.iter().map(|p| p.to_string()));
+            return Ok(Some(LobbyOutcome::new(players, &self.map)?));
+        }
+
+        if due(self.last_send, RESEND_INTERVAL) {
+            self.last_send = Some(Instant::now());
+            for (i, peer) in self.peers.iter().enumerate() {
+                if !self.acked[i] {
+                    send(
+                        &self.socket,
+                        *peer,
+                        LobbyMessage::Start {
+                            handle: i + 1,
+                            peers: self.peers.clone(),
+                            map: self.map.clone(),
+                        },
+                    )?;
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn status(&self) -> String {
+        if self.is_full() {
+            format!("starting {} on {} players", self.map.name, self.num_players)
+        } else {
+            format!(
+                "hosting {}, {}/{} players joined",
+                self.map.name,
+                self.peers.len() + 1,
+                self.num_players
+            )
+        }
+    }
+}
+
+// joins the first match it hears about on the lan
+pub struct LanClient {
+    socket: UdpSocket,
+    discovery: Option<UdpSocket>,
+    host: Option<SocketAddr>,
+    joined: Option<(usize, usize, usize)>,
+    last_send: Option<Instant>,
+    download: Option<MapDownload>,
+    started: Option<(Instant, LobbyOutcome)>,
+}
+
+impl LanClient {
+    pub fn new(local_port: u16) -> io::Result<Self> {
+        Ok(Self {
+            socket: bind(local_port)?,
+            discovery: Some(bind_shared(LAN_DISCOVERY_PORT)?),
+            host: None,
+            joined: None,
+            last_send: None,
+            download: None,
+            started: None,
+        })
+    }
+}
+
+impl Lobby for LanClient {
+    fn poll(&mut self) -> io::Result<Option<LobbyOutcome>> {
+        if let Some(discovery) = &self.discovery {
+            while let Some((addr, message)) = receive(discovery)? {
+                if let LobbyMessage::Announce { .. } = message {
+                    self.host = Some(addr);
+                }
+            }
+            if self.host.is_some() {
+                self.discovery = None;
+            }
+        }
+        let host = match self.host {
+            Some(host) => host,
+            None => return Ok(None),
+        };
+
+        while let Some((addr, message)) = receive(&self.socket)? {
+            if addr != host {
+                continue;
+            }
+            match message {
+                LobbyMessage::Joined {
+                    handle,
+                    joined,
+                    num_players,
+                } => self.joined = Some((handle, joined, num_players)),
+                LobbyMessage::Start { handle, peers, map } => {
+                    if self.started.is_none() && self.download.is_none() {
+                        let mut players = vec![host.to_string()];
+                        players.extend(peers.iter().map(|p| p.to_string()));
+                        if handle >= players.len() {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                format!("handle {} of {} players", handle, players.len()),
+                            ));
+                        }
+                        players[handle] = "localhost".to_string();
+                        let outcome = LobbyOutcome::new(players, &map)?;
+                        let download = MapDownload::new(&map, host, outcome)?;
+                        match download.finished() {
+                            Some(outcome) => self.started = Some((Instant::now(), outcome)),
+                            None => self.download = Some(download),
+                        }
+                    }
+                    // not before the maps are here
+                    if self.started.is_some() {
+                        send(&self.socket, host, LobbyMessage::StartAck)?;
+                    }
+                }
+                LobbyMessage::MapChunk { hash, chunk, data } => {
+                    if let Some(download) = &mut self.download {
+                        if let Some(outcome) = download.receive(hash, chunk, data)? {
+                            self.download = None;
+                            self.started = Some((Instant::now(), outcome));
+                            send(&self.socket, host, LobbyMessage::StartAck)?;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(download) = &mut self.download {
+            download.request(&self.socket)?;
+        } else if let Some((since, outcome)) = &self.started {
+            if since.elapsed() >= START_LINGER {
+                return Ok(Some(outcome.clone()));
+            }
+        } else if due(self.last_send, ANNOUNCE_INTERVAL) {
+            self.last_send = Some(Instant::now());
+            send(&self.socket, host, LobbyMessage::Join)?;
+        }
+        Ok(None)
+    }
+
+    fn status(&self) -> String {
+        if let Some(download) = &self.download {
+            return download.status();
+        }
+        match (&self.host, &self.joined, &self.started) {
+            (None, _, _) => "looking for a lan match".to_string(),
+            (Some(host), None, _) => format!("joining {}", host),
+            (Some(host), Some((handle, joined, num_players)), None) => format!(
+                "joined {} as player {}, {}/{} players",
+                host,
+                handle + 1,
+                joined,
+                num_players
+            ),
+            (Some(_), Some(_), Some((_, outcome))) => format!("starting {}", outcome.map),
+        }
+    }
+}
+
+// asks a signaling server for the other members of a room. handle 0 is the
+// peer that created the room, the others fetch its map from it directly if
+// they need to and tell it once they have it
+pub struct SignalingClient {
+    socket: UdpSocket,
+    server: SocketAddr,
+    room: String,
+    num_players: usize,
+    map: MapInfo,
+    map_files: MapFiles,
+    waiting: Option<(usize, usize)>,
+    last_send: Option<Instant>,
+    // handle and addresses of everyone, once the server started the room
+    handle: usize,
+    players: Vec<SocketAddr>,
+    // by handle, only handle 0 keeps track
+    acked: Vec<bool>,
+    download: Option<MapDownload>,
+    started: Option<(Instant, LobbyOutcome)>,
+}
+
+impl SignalingClient {
+    pub fn new(
+        local_port: u16,
+        server: SocketAddr,
+        room: &str,
+        num_players: usize,
+        config: &MatchConfig,
+    ) -> io::Result<Self> {
+        // the room may be on other maps already, in which case we don't
+        // need to have these. a match too big to describe is no use either
+        // way
+        let (map, map_files) = match map_offer(config) {
+            Ok(offer) => offer,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => return Err(e),
+            Err(_) => (map_info(config), Vec::new()),
+        };
+        Ok(Self {
+            socket: bind(local_port)?,
+            server,
+            room: room.to_string(),
+            num_players,
+            map,
+            map_files,
+            waiting: None,
+            last_send: None,
+            handle: 0,
+            players: Vec::new(),
+            acked: Vec::new(),
+            download: None,
+            started: None,
+        })
+    }
+
+    fn send(&self, message: SignalingMessage) -> io::Result<()> {
+        match self
+            .socket
+            .send_to(&signaling_protocol::encode(message), self.server)
+        {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn start(&mut self, handle: usize, players: Vec<SocketAddr>, map: MapInfo) -> io::Result<()> {
+        if handle >= players.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("handle {} of {} players", handle, players.len()),
+            ));
+        }
+        let mut names: Vec<String> = players.iter().map(|p| p.to_string()).collect();
+        names[handle] = "localhost".to_string();
+        let outcome = LobbyOutcome::new(names, &map)?;
+        self.handle = handle;
+        self.acked = (0..players.len()).map(|h| h == handle).collect();
+        self.players = players;
+        if handle == 0 {
+            // also opens our side of a nat for their requests
+            for player in &self.players[1..] {
+                send(&self.socket, *player, LobbyMessage::StartAck)?;
+            }
+        }
+        let download = MapDownload::new(&map, self.players[0], outcome)?;
+        match download.finished() {
+            Some(outcome) => self.started = Some((Instant::now(), outcome)),
+            None => self.download = Some(download),
+        }
+        Ok(())
+    }
+
+    fn receive_from_peer(&mut self, addr: SocketAddr, message: LobbyMessage) -> io::Result<()> {
+        match message {
+            // whoever knows the hash is in the room
+            LobbyMessage::MapRequest { hash, chunk } => {
+                send_map_chunk(&self.socket, addr, &self.map_files, hash, chunk)?;
+            }
+            LobbyMessage::StartAck => {
+                if let Some(i) = self.players.iter().position(|p| *p == addr) {
+                    self.acked[i] = true;
+                }
+            }
+            LobbyMessage::MapChunk { hash, chunk, data } => {
+                if let Some(download) = &mut self.download {
+                    if let Some(outcome) = download.receive(hash, chunk, data)? {
+                        self.download = None;
+                        self.started = Some((Instant::now(), outcome));
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl Lobby for SignalingClient {
+    fn poll(&mut self) -> io::Result<Option<LobbyOutcome>> {
+        let mut buffer = [0u8; signaling_protocol::MAX_DATAGRAM];
+        loop {
+            let (len, addr) = match self.socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(e) => return Err(e),
+            };
+            if addr != self.server {
+                if let Some(message) = decode(&buffer[..len]) {
+                    self.receive_from_peer(addr, message)?;
+                }
+                continue;
+            }
+            match signaling_protocol::decode(&buffer[..len]) {
+                Some(SignalingMessage::Waiting {
+                    joined,
+                    num_players,
+                    ..
+                }) => self.waiting = Some((joined, num_players)),
+                Some(SignalingMessage::Start {
+                    handle,
+                    players,
+                    map,
+                }) => {
+                    self.send(SignalingMessage::StartAck {
+                        room: self.room.clone(),
+                    })?;
+                    if self.players.is_empty() {
+                        self.start(handle, players, map)?;
+                    }
+                }
+                Some(SignalingMessage::Rejected { reason }) => {
+                    return Err(io::Error::new(io::ErrorKind::Other, reason))
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(download) = &mut self.download {
+            download.request(&self.socket)?;
+        } else if let Some((since, outcome)) = &self.started {
+            let lingered = since.elapsed() >= START_LINGER;
+            if self.handle == 0 {
+                // the others may still be fetching the map from us
+                if lingered && self.acked.iter().all(|a| *a) {
+                    return Ok(Some(outcome.clone()));
+                }
+            } else {
+                if lingered {
+                    return Ok(Some(outcome.clone()));
+                }
+                if due(self.last_send, RESEND_INTERVAL) {
+                    self.last_send = Some(Instant::now());
+                    send(&self.socket, self.players[0], LobbyMessage::StartAck)?;
+                }
+            }
+        } else if self.players.is_empty() && due(self.last_send, ANNOUNCE_INTERVAL) {
+            self.last_send = Some(Instant::now());
+            self.send(SignalingMessage::Join {
+                room: self.room.clone(),
+                num_players: self.num_players,
+                map: self.map.clone(),
+            })?;
+        }
+        Ok(None)
+    }
+
+    fn status(&self) -> String {
+        if let Some(download) = &self.download {
+            return download.status();
+        }
+        match (&self.waiting, &self.started) {
+            (_, Some((since, _))) if self.handle == 0 && since.elapsed() >= START_LINGER => {
+                "waiting for the others to get the map".to_string()
+            }
+            (_, Some((_, outcome))) => format!("starting {}", outcome.map),
+            (Some((joined, num_players)), None) => format!(
+                "waiting in room {}, {}/{} players",
+                self.room, joined, num_players
+            ),
+            (None, None) => format!("joining room {} on {}", self.room, self.server),
+        }
+    }
+}
+
+// the lobby being waited on in the lobby state, taken out to close its
+// socket before the GGRS session binds the same port
+pub struct PendingLobby(pub Option<Box<dyn Lobby + Send + Sync>>);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapgen::{generated_name, MapStyle};
+    use crate::playlist::GameMode;
+    use crate::signaling_rooms::Server;
+
+    #[test]
+    fn signaling_server_introduces_the_members_of_a_room() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let server = socket.local_addr().unwrap();
+        std::thread::spawn(move || Server::new(socket).run());
+
+        // a generated map, nothing to download
+        let config = MatchConfig {
+            map: generated_name(MapStyle::Maze, 1, false),
+            fog_of_war: false,
+            time_limit: 0,
+            playlist: None,
+            mode: GameMode::Deathmatch,
+            teams: 0,
+            friendly_fire: false,
+        };
+        let mut clients: Vec<SignalingClient> = (0..2)
+            .map(|_| SignalingClient::new(0, server, "test", 2, &config).unwrap())
+            .collect();
+        let mut outcomes = vec![None, None];
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while outcomes.iter().any(|o| o.is_none()) {
+            assert!(Instant::now() < deadline, "the lobby never started");
+            for (client, outcome) in clients.iter_mut().zip(&mut outcomes) {
+                if outcome.is_none() {
+                    *outcome = client.poll().unwrap();
+                }
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        // the server saw them on the loopback address
+        let addresses: Vec<String> = clients
+            .iter()
+            .map(|c| {
+                let port = c.socket.local_addr().unwrap().port();
+                SocketAddr::from(([127, 0, 0, 1], port)).to_string()
+            })
+            .collect();
+        let mut handles: Vec<usize> = clients.iter().map(|c| c.handle).collect();
+        for (i, outcome) in outcomes.into_iter().enumerate() {
+            let outcome = outcome.unwrap();
+            let other = 1 - i;
+            assert_eq!(outcome.players.len(), 2);
+            assert_eq!(outcome.players[clients[i].handle], "localhost");
+            assert_eq!(outcome.players[clients[other].handle], addresses[other]);
+            assert_eq!(outcome.map, config.map);
+        }
+        handles.sort_unstable();
+        assert_eq!(handles, vec![0, 1]);
+    }
+
+    #[test]
+    fn lan_clients_on_one_machine_all_find_the_host() {
+        let config = MatchConfig {
+            map: generated_name(MapStyle::Maze, 1, false),
+            fog_of_war: false,
+            time_limit: 0,
+            playlist: None,
+            mode: GameMode::Deathmatch,
+            teams: 0,
+            friendly_fire: false,
+        };
+        // both clients listen on the discovery port at once
+        let mut clients: Vec<LanClient> = (0..2).map(|_| LanClient::new(0).unwrap()).collect();
+        let mut host = LanHost::new(0, 3, &config).unwrap();
+        let mut host_outcome = None;
+        let mut outcomes = vec![None, None];
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while host_outcome.is_none() || outcomes.iter().any(|o| o.is_none()) {
+            assert!(Instant::now() < deadline, "the lobby never started");
+            if host_outcome.is_none() {
+                host_outcome = host.poll().unwrap();
+            }
+            for (client, outcome) in clients.iter_mut().zip(&mut outcomes) {
+                if outcome.is_none() {
+                    *outcome = client.poll().unwrap();
+                }
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let host_outcome = host_outcome.unwrap();
+        assert_eq!(host_outcome.players.len(), 3);
+        assert_eq!(host_outcome.players[0], "localhost");
+        let mut handles = vec![0];
+        for (client, outcome) in clients.iter().zip(outcomes) {
+            let outcome = outcome.unwrap();
+            let (handle, _, _) = client.joined.unwrap();
+            assert_eq!(outcome.players.len(), 3);
+            assert_eq!(outcome.players[handle], "localhost");
+            assert_eq!(outcome.map, config.map);
+            handles.push(handle);
+        }
+        handles.sort_unstable();
+        assert_eq!(handles, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn a_match_too_big_for_a_lobby_message_is_refused() {
+        let entries: Vec<String> = (0..2000)
+            .map(|seed| {
+                format!(
+                    "{{\"map\": \"{}\"}}",
+                    generated_name(MapStyle::Maze, seed, false)
+                )
+            })
+            .collect();
+        let playlist =
+            parse_playlist(&format!("{{\"entries\": [{}]}}", entries.join(", "))).unwrap();
+        let config = MatchConfig {
+            map: generated_name(MapStyle::Maze, 0, false),
+            fog_of_war: false,
+            time_limit: 0,
+            playlist: Some(playlist),
+            mode: GameMode::Deathmatch,
+            teams: 0,
+            friendly_fire: false,
+        };
+        let error = map_offer(&config).expect_err("the offer fit");
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
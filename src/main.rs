@@ -1,20 +1,65 @@
-use std::fs::File;
-use std::io::BufReader;
-
-use bevy::sprite::MaterialMesh2dBundle;
-use bevy::{prelude::*, render::camera::ScalingMode, window::WindowResized};
+use bevy::prelude::*;
 
 use bevy_ggrs::{GGRSPlugin, Rollback, RollbackIdProvider, SessionType};
 use ggrs::{
-    Config, InputStatus, P2PSession, PlayerHandle, PlayerType, SessionBuilder, SpectatorSession,
-    SyncTestSession, UdpNonBlockingSocket,
+    Config, InputStatus, P2PSession, PlayerHandle, PlayerType, SessionBuilder, SessionState,
+    SpectatorSession, SyncTestSession, UdpNonBlockingSocket,
 };
 
 use bytemuck::{Pod, Zeroable};
-use std::net::SocketAddr;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
 
 use structopt::StructOpt;
 
+mod camera;
+mod controls;
+mod ctf;
+mod editor;
+mod events;
+mod hud;
+mod koth;
+mod lobby;
+mod map;
+mod mapgen;
+mod menu;
+mod minimap;
+mod particles;
+mod playlist;
+mod scoreboard;
+mod signaling_protocol;
+#[cfg(test)]
+mod signaling_rooms;
+mod sound;
+mod survival;
+mod tank;
+mod teams;
+use camera::CameraPlugin;
+use controls::{input, ControlsPlugin};
+use ctf::{missing_flags, spawn_flags, update_flags, CtfPlugin, Flag};
+use editor::EditorPlugin;
+use events::{rewind_events, EventsPlugin, SimEvent, SimEvents};
+use hud::HudPlugin;
+use koth::{spawn_hill_scores, spawn_zones, update_zones, HillScore, KothPlugin, Zone};
+use lobby::{LanClient, LanHost, Lobby, PendingLobby, SignalingClient};
+use map::{load_playable_map, map_path, save_map, validate_map_file, wall_color, Map, MergedWall};
+use mapgen::{editable_name, generate_map, generated_name, parse_generated, MapStyle};
+use menu::{MenuPlugin, MenuState};
+use minimap::MinimapPlugin;
+use particles::ParticlesPlugin;
+use playlist::{
+    advance_round, current_layer, load_playlist, match_entries, GameMode, Playlist, PlaylistPlugin,
+    Rotation, Round, ScoreRule,
+};
+use scoreboard::ScoreboardPlugin;
+use sound::SoundPlugin;
+use survival::{
+    move_enemies, spawn_hives, spawn_survival, update_waves, Enemy, HiveSpawner, Survival,
+    SURVIVAL_DURATION,
+};
+use tank::TankPlugin;
+use teams::Teams;
+
 #[derive(Debug)]
 pub struct GGRSConfig;
 impl Config for GGRSConfig {
@@ -24,54 +69,199 @@ impl Config for GGRSConfig {
 }
 
 const FPS: usize = 60;
+const ROLLBACK_EVENTS: &str = "rollback_events";
 const ROLLBACK_CORE: &str = "rollback_core";
 const ROLLBACK_MOVE_PLAYERS: &str = "rollback_move_players";
+const ROLLBACK_ENEMIES: &str = "rollback_enemies";
 const ROLLBACK_MOVE_BULLETS: &str = "rollback_move_bullets";
+const ROLLBACK_DAMAGE: &str = "rollback_damage";
+const ROLLBACK_FLAGS: &str = "rollback_flags";
+const ROLLBACK_ZONES: &str = "rollback_zones";
+const ROLLBACK_WAVES: &str = "rollback_waves";
 const ROLLBACK_FUSE: &str = "rollback_fuse";
+const ROLLBACK_CLOCK: &str = "rollback_clock";
+const ROLLBACK_ROUND: &str = "rollback_round";
 
 // structopt will read command line parameters for u
 #[derive(StructOpt)]
 struct Opt {
-    #[structopt(short, long)]
+    #[structopt(short, long, default_value = "7000")]
     local_port: u16,
     #[structopt(short, long)]
     players: Vec<String>,
     #[structopt(short, long)]
     spectators: Vec<SocketAddr>,
+    #[structopt(short, long, default_value = "NAME")]
+    map: String,
+    // host a lan match for this many players
+    #[structopt(long)]
+    lan_host: Option<usize>,
+    #[structopt(long)]
+    lan_join: bool,
+    // find the other players through a signaling server
+    #[structopt(long)]
+    server: Option<String>,
+    #[structopt(long, default_value = "tanks")]
+    room: String,
+    #[structopt(long, default_value = "2")]
+    room_size: usize,
+    // hide other players on the minimap unless they are close
+    #[structopt(long)]
+    fog_of_war: bool,
+    // match length in seconds
+    #[structopt(long, default_value = "300")]
+    time_limit: u32,
+    // play the maps of assets/playlists/<name>.json one after the other
+    #[structopt(long)]
+    playlist: Option<String>,
+    // deathmatch, team_deathmatch, capture_the_flag, king_of_the_hill or survival
+    #[structopt(long, default_value = "deathmatch")]
+    mode: String,
+    // for the team modes, handles are dealt out to them in turn
+    #[structopt(long, default_value = "2")]
+    teams: usize,
+    #[structopt(long)]
+    friendly_fire: bool,
+    // open the map in the editor instead of playing
+    #[structopt(long)]
+    edit: bool,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    // check a map file and list its problems
+    ValidateMap {
+        file: PathBuf,
+    },
+    // write a generated map to assets/maps, it can be played as gen-<style>-<seed> too
+    GenerateMap {
+        // maze, rooms or arena
+        style: String,
+        seed: u64,
+        #[structopt(long)]
+        hives: bool,
+        // file name in assets/maps, <style>-<seed> by default
+        #[structopt(long)]
+        out: Option<String>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum GameState {
+    MainMenu,
+    // waiting for the other players to show up
+    Lobby,
+    // session started, waiting for it to synchronize
+    Loading,
+    InGame,
+    RoundEnd,
+    Results,
+    Editor,
+}
+
+pub struct MatchConfig {
+    pub map: String,
+    pub fog_of_war: bool,
+    pub time_limit: u32,
+    pub playlist: Option<Playlist>,
+    // of a match without a playlist
+    pub mode: GameMode,
+    pub teams: usize,
+    pub friendly_fire: bool,
+}
+
+pub struct SessionConfig {
+    pub local_port: u16,
+    pub spectators: Vec<SocketAddr>,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     // read cmd line arguments
     let opt = Opt::from_args();
-    let num_players = opt.players.len();
-    assert!(num_players > 0);
-
-    // create a GGRS session
-    let mut sess_build = SessionBuilder::<GGRSConfig>::new()
-        .with_num_players(num_players)
-        .with_max_prediction_window(12) // (optional) set max prediction window
-        .with_input_delay(2); // (optional) set input delay for the local player
-
-    // add players
-    for (i, player_addr) in opt.players.iter().enumerate() {
-        // local player
-        if player_addr == "localhost" {
-            sess_build = sess_build.add_player(PlayerType::Local, i)?;
-        } else {
-            // remote players
-            let remote_addr: SocketAddr = player_addr.parse()?;
-            sess_build = sess_build.add_player(PlayerType::Remote(remote_addr), i)?;
+    match &opt.command {
+        Some(Command::ValidateMap { file }) => {
+            std::process::exit(if validate_map_file(file) { 0 } else { 1 });
         }
+        Some(Command::GenerateMap {
+            style,
+            seed,
+            hives,
+            out,
+        }) => {
+            let style = MapStyle::from_name(style).ok_or("the style is maze, rooms or arena")?;
+            let name = match out {
+                Some(out) if parse_generated(out).is_some() => {
+                    return Err(format!("{} would be generated again when loaded", out).into())
+                }
+                Some(out) => out.clone(),
+                None => editable_name(&generated_name(style, *seed, *hives)),
+            };
+            save_map(&generate_map(style, *seed, *hives), &name)?;
+            let ok = validate_map_file(Path::new(&map_path(&name)));
+            std::process::exit(if ok { 0 } else { 1 });
+        }
+        None => {}
     }
+    let playlist = match &opt.playlist {
+        Some(name) => Some(load_playlist(name)?),
+        None => None,
+    };
+    // the lobby offers the first map
+    let map = playlist
+        .as_ref()
+        .map_or(opt.map.clone(), |p| p.entries[0].map.clone());
+    let mode = GameMode::from_name(&opt.mode).ok_or(
+        "the mode is deathmatch, team_deathmatch, capture_the_flag, king_of_the_hill or survival",
+    )?;
+    let config = MatchConfig {
+        map,
+        fog_of_war: opt.fog_of_war,
+        time_limit: opt.time_limit,
+        playlist,
+        mode,
+        teams: opt.teams,
+        friendly_fire: opt.friendly_fire,
+    };
+    let session_config = SessionConfig {
+        local_port: opt.local_port,
+        spectators: opt.spectators.clone(),
+    };
 
-    // optionally, add spectators
-    for (i, spec_addr) in opt.spectators.iter().enumerate() {
-        sess_build = sess_build.add_player(PlayerType::Spectator(*spec_addr), num_players + i)?;
-    }
-
-    // start the GGRS session
-    let socket = UdpNonBlockingSocket::bind_to_port(opt.local_port)?;
-    let sess = sess_build.start_p2p_session(socket)?;
+    // the network flags skip the main menu
+    let lobby: Option<Box<dyn Lobby + Send + Sync>> = if let Some(num_players) = opt.lan_host {
+        Some(Box::new(LanHost::new(
+            opt.local_port,
+            num_players,
+            &config,
+        )?))
+    } else if opt.lan_join {
+        Some(Box::new(LanClient::new(opt.local_port)?))
+    } else if let Some(server) = &opt.server {
+        let server = resolve_server(server)?;
+        Some(Box::new(SignalingClient::new(
+            opt.local_port,
+            server,
+            &opt.room,
+            opt.room_size,
+            &config,
+        )?))
+    } else {
+        None
+    };
+    let (initial_state, sess) = if lobby.is_some() {
+        (GameState::Lobby, None)
+    } else if !opt.players.is_empty() {
+        (
+            GameState::Loading,
+            Some(start_session(&opt.players, &session_config)?),
+        )
+    } else if opt.edit {
+        (GameState::Editor, None)
+    } else {
+        (GameState::MainMenu, None)
+    };
 
     let mut app = App::new();
     GGRSPlugin::<GGRSConfig>::new()
@@ -82,6 +272,16 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .register_rollback_type::<Fuse>()
         .register_rollback_type::<Player>()
         .register_rollback_type::<Bullet>()
+        .register_rollback_type::<Health>()
+        .register_rollback_type::<Arsenal>()
+        .register_rollback_type::<Score>()
+        .register_rollback_type::<MatchClock>()
+        .register_rollback_type::<Flag>()
+        .register_rollback_type::<Zone>()
+        .register_rollback_type::<HillScore>()
+        .register_rollback_type::<Enemy>()
+        .register_rollback_type::<HiveSpawner>()
+        .register_rollback_type::<Survival>()
         .with_rollback_schedule(
             Schedule::default()
                 .with_stage(
@@ -90,6 +290,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .with_system(movement)
                         .with_system(shoot),
                 )
+                .with_stage_before(
+                    ROLLBACK_CORE,
+                    ROLLBACK_EVENTS,
+                    SystemStage::single(rewind_events),
+                )
                 .with_stage_after(
                     ROLLBACK_CORE,
                     ROLLBACK_MOVE_PLAYERS,
@@ -97,13 +302,48 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 )
                 .with_stage_after(
                     ROLLBACK_MOVE_PLAYERS,
+                    ROLLBACK_ENEMIES,
+                    SystemStage::single(move_enemies),
+                )
+                .with_stage_after(
+                    ROLLBACK_ENEMIES,
                     ROLLBACK_MOVE_BULLETS,
                     SystemStage::single(move_bullets),
                 )
                 .with_stage_after(
                     ROLLBACK_MOVE_BULLETS,
+                    ROLLBACK_DAMAGE,
+                    SystemStage::single(handle_deaths),
+                )
+                .with_stage_after(
+                    ROLLBACK_DAMAGE,
+                    ROLLBACK_FLAGS,
+                    SystemStage::single(update_flags),
+                )
+                .with_stage_after(
+                    ROLLBACK_FLAGS,
+                    ROLLBACK_ZONES,
+                    SystemStage::single(update_zones),
+                )
+                .with_stage_after(
+                    ROLLBACK_ZONES,
+                    ROLLBACK_WAVES,
+                    SystemStage::single(update_waves),
+                )
+                .with_stage_after(
+                    ROLLBACK_WAVES,
                     ROLLBACK_FUSE,
                     SystemStage::single(clean_fuses),
+                )
+                .with_stage_after(
+                    ROLLBACK_FUSE,
+                    ROLLBACK_CLOCK,
+                    SystemStage::single(tick_clock),
+                )
+                .with_stage_after(
+                    ROLLBACK_CLOCK,
+                    ROLLBACK_ROUND,
+                    SystemStage::single(advance_round),
                 ),
         )
         .build(&mut app);
@@ -114,56 +354,154 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         ..Default::default()
     })
     .add_plugins(DefaultPlugins)
-    .add_startup_system(setup)
-    .add_startup_system(spawn_camera)
+    .add_state(initial_state)
+    .add_plugin(ControlsPlugin)
+    .add_plugin(CameraPlugin)
+    .add_plugin(MinimapPlugin)
+    .add_plugin(TankPlugin)
+    .add_plugin(HudPlugin)
+    .add_plugin(ScoreboardPlugin)
+    .add_plugin(MenuPlugin)
+    .add_plugin(EventsPlugin)
+    .add_plugin(SoundPlugin)
+    .add_plugin(ParticlesPlugin)
+    .add_plugin(EditorPlugin)
+    .add_plugin(PlaylistPlugin)
+    .add_plugin(CtfPlugin)
+    .add_plugin(KothPlugin)
+    .add_system_set(SystemSet::on_enter(GameState::Loading).with_system(setup))
+    .add_system_set(SystemSet::on_enter(GameState::MainMenu).with_system(cleanup_match))
+    .insert_resource(session_config)
+    .insert_resource(PendingLobby(lobby));
+
     // add your GGRS session
-    .insert_resource(sess)
-    .insert_resource(SessionType::P2PSession)
-    .add_system_to_stage(CoreStage::PostUpdate, camera_follow)
-    .add_system(window_resized_event)
-    .run();
+    match sess {
+        Some(sess) => {
+            app.insert_resource(Teams::for_match(sess.num_players(), &config))
+                .insert_resource(LocalPlayers(sess.local_player_handles()))
+                .insert_resource(sess)
+                .insert_resource(SessionType::P2PSession);
+        }
+        None => {
+            app.insert_resource(Teams::default())
+                .insert_resource(LocalPlayers(Vec::new()));
+        }
+    }
+    app.insert_resource(config);
+
+    app.run();
 
     Ok(())
 }
 
-fn window_resized_event(
-    mut events: EventReader<WindowResized>,
-    mut window: ResMut<WindowDescriptor>,
-) {
-    for event in events.iter() {
-        window.width = event.width.try_into().unwrap();
-        window.height = event.height.try_into().unwrap();
+// builds a GGRS session for the players a lobby agreed on, "localhost"
+// being us
+pub fn start_session(
+    players: &[String],
+    session_config: &SessionConfig,
+) -> Result<P2PSession<GGRSConfig>, Box<dyn std::error::Error>> {
+    let num_players = players.len();
+    assert!(num_players > 0);
+
+    // create a GGRS session
+    let mut sess_build = SessionBuilder::<GGRSConfig>::new()
+        .with_num_players(num_players)
+        .with_max_prediction_window(12) // (optional) set max prediction window
+        .with_input_delay(2); // (optional) set input delay for the local player
+
+    // add players
+    for (i, player_addr) in players.iter().enumerate() {
+        // local player
+        if player_addr == "localhost" {
+            sess_build = sess_build.add_player(PlayerType::Local, i)?;
+        } else {
+            // remote players
+            let remote_addr: SocketAddr = player_addr.parse()?;
+            sess_build = sess_build.add_player(PlayerType::Remote(remote_addr), i)?;
+        }
+    }
+
+    // optionally, add spectators
+    for (i, spec_addr) in session_config.spectators.iter().enumerate() {
+        sess_build = sess_build.add_player(PlayerType::Spectator(*spec_addr), num_players + i)?;
     }
+
+    // start the GGRS session
+    let socket = UdpNonBlockingSocket::bind_to_port(session_config.local_port)?;
+    Ok(sess_build.start_p2p_session(socket)?)
 }
 
-fn camera_follow(
-    player_query: Query<(&Player, &Transform)>,
-    mut camera_query: Query<&mut Transform, (Without<Player>, With<Camera>)>,
-    p2p_session: Option<Res<P2PSession<GGRSConfig>>>,
+// what main does for a session from the cmd line, for the menus
+pub fn insert_session(commands: &mut Commands, sess: P2PSession<GGRSConfig>, config: &MatchConfig) {
+    commands.insert_resource(Teams::for_match(sess.num_players(), config));
+    commands.insert_resource(LocalPlayers(sess.local_player_handles()));
+    commands.insert_resource(sess);
+    commands.insert_resource(SessionType::P2PSession);
+}
+
+// a session without remote players is there right away
+pub fn session_running(sess: Option<&P2PSession<GGRSConfig>>) -> bool {
+    sess.map_or(true, |s| s.current_state() == SessionState::Running)
+}
+
+// everything a match spawned or inserted, so that the next one starts clean
+fn cleanup_match(
+    mut commands: Commands,
+    entity_query: Query<Entity, Or<(With<MatchEntity>, With<Rollback>)>>,
 ) {
-    let handles = p2p_session.unwrap().local_player_handles();
-    if handles.len() > 0 {
-        if let Some((_, transform)) = player_query.iter().find(|(p, _)| p.handle == handles[0]) {
-            let mut camera_transform = camera_query.single_mut();
-            camera_transform.translation.x = transform.translation.x;
-            camera_transform.translation.y = transform.translation.y;
-        };
+    for entity in &entity_query {
+        commands.entity(entity).despawn_recursive();
     }
+    commands.remove_resource::<SessionType>();
+    commands.remove_resource::<P2PSession<GGRSConfig>>();
+    commands.remove_resource::<Rotation>();
+    commands.remove_resource::<MapBounds>();
+    commands.insert_resource(LocalPlayers(Vec::new()));
+    commands.insert_resource(Teams::default());
 }
 
-fn spawn_camera(mut commands: Commands) {
-    let mut camera = Camera2dBundle::default();
-    camera.projection.scaling_mode = ScalingMode::WindowSize;
-    camera.transform = Transform::from_xyz(0.0, 0.0, 100.0);
-    commands.spawn_bundle(camera);
+// accepts host or host:port
+fn resolve_server(server: &str) -> std::io::Result<SocketAddr> {
+    let with_port = if server.contains(':') {
+        server.to_string()
+    } else {
+        format!("{}:{}", server, signaling_protocol::DEFAULT_SERVER_PORT)
+    };
+    with_port.to_socket_addrs()?.next().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("can't resolve {}", server),
+        )
+    })
 }
 
 #[derive(Component, Default, Reflect)]
-pub struct Bullet;
+pub struct Bullet {
+    pub owner: usize,
+    pub weapon: u8,
+}
 
 #[derive(Component)]
 pub struct Wall;
 
+// on the first wall of a merged wall, the walls of the map it stands for by
+// their index in the map and the rectangle around them, see
+// collide_circle_walls
+#[derive(Component)]
+pub struct WallPieces {
+    pub bounds: Transform,
+    pub pieces: Vec<(usize, Transform)>,
+}
+
+// which map of the rotation a wall belongs to, only the walls of the current
+// round are shown and collide
+#[derive(Component)]
+pub struct MapLayer(pub usize);
+
+// despawned when leaving the match, along with the rollback entities
+#[derive(Component)]
+pub struct MatchEntity;
+
 #[derive(Component, Default, Reflect)]
 pub struct Fuse {
     lit: bool,
@@ -175,71 +513,196 @@ pub struct Player {
     pub handle: usize,
     pub speed: f32,
     pub radius: f32,
+    // last aim angle in radians
+    pub aim: f32,
 }
 
+// a player is dead while hp is 0, until respawn runs out
+#[derive(Component, Default, Reflect)]
+pub struct Health {
+    pub hp: f32,
+    pub respawn: f32,
+    // handle of the last player that hit us, -1 for nobody
+    pub last_hit_by: i32,
+    pub last_hit_weapon: u8,
+}
+
+impl Health {
+    pub fn full() -> Self {
+        Self {
+            hp: MAX_HP,
+            respawn: 0.0,
+            last_hit_by: -1,
+            last_hit_weapon: 0,
+        }
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.hp > 0.0
+    }
+}
+
+#[derive(Component, Default, Reflect)]
+pub struct Arsenal {
+    // the weapon fired last
+    pub weapon: u8,
+    pub cooldown: f32,
+    pub ammo: u32,
+    pub reload: f32,
+}
+
+impl Arsenal {
+    pub fn full() -> Self {
+        Self {
+            weapon: WEAPON_CANNON,
+            cooldown: 0.0,
+            ammo: SHELL_AMMO,
+            reload: 0.0,
+        }
+    }
+}
+
+#[derive(Component, Default, Reflect)]
+pub struct Score {
+    pub kills: i32,
+    pub deaths: i32,
+    // enemy flags brought home
+    pub captures: i32,
+}
+
+// lives on its own rollback entity
+#[derive(Component, Default, Reflect)]
+pub struct MatchClock {
+    // since the start of the round
+    pub frame: u32,
+    pub duration: u32,
+    // counts the rounds of a playlist
+    pub round: u32,
+    // since the start of the match, never reset: the clock is spawned before
+    // the session runs, so this is the GGRS frame being simulated. events
+    // are tagged with it, see SimEvents
+    pub tick: u32,
+}
+
+impl MatchClock {
+    pub fn expired(&self) -> bool {
+        self.frame >= self.duration
+    }
+
+    pub fn time_left(&self) -> f32 {
+        self.duration.saturating_sub(self.frame) as f32 / FPS as f32
+    }
+}
+
+pub const MAX_HP: f32 = 100.0;
+pub const TANK_RADIUS: f32 = 10.0;
+const RESPAWN_TIME: f32 = 3.0;
+
+pub struct WeaponStats {
+    pub name: &'static str,
+    pub damage: f32,
+    pub cooldown: f32,
+    pub speed: f32,
+    pub size: Vec2,
+}
+
+pub const WEAPON_CANNON: u8 = 0;
+pub const WEAPON_SHELL: u8 = 1;
+pub const WEAPONS: [WeaponStats; 2] = [
+    WeaponStats {
+        name: "cannon",
+        damage: 10.0,
+        cooldown: 0.2,
+        speed: 10.0,
+        size: Vec2::new(5.0, 2.0),
+    },
+    WeaponStats {
+        name: "shell",
+        damage: 40.0,
+        cooldown: 0.8,
+        speed: 7.0,
+        size: Vec2::new(8.0, 4.0),
+    },
+];
+// shells are limited and come back one at a time
+pub const SHELL_AMMO: u32 = 3;
+pub const SHELL_RELOAD: f32 = 3.0;
+
 #[derive(Component, Default, Reflect)]
 pub struct Rigidbody {
     pub vel: Vec2,
     pub friction: f32,
 }
 
+const PLAYER_COLORS: [Color; 8] = [
+    Color::rgb(0.9, 0.2, 0.2),
+    Color::rgb(0.2, 0.5, 0.95),
+    Color::rgb(0.95, 0.85, 0.2),
+    Color::rgb(0.3, 0.85, 0.3),
+    Color::rgb(0.85, 0.4, 0.9),
+    Color::rgb(0.2, 0.85, 0.85),
+    Color::rgb(1.0, 0.6, 0.2),
+    Color::rgb(0.9, 0.9, 0.9),
+];
+
+pub fn player_color(handle: usize) -> Color {
+    PLAYER_COLORS[handle % PLAYER_COLORS.len()]
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, PartialEq, Pod, Zeroable)]
 pub struct BoxInput {
     pub inp: u8,
-    pub sx: u8,
-    pub sy: u8,
+    // aim angle, a full turn is quantized in 256 steps
+    pub aim: u8,
+    // analog movement, only used when no direction bit is set
+    pub move_x: i8,
+    pub move_y: i8,
 }
 
 const INPUT_UP: u8 = 1 << 0;
 const INPUT_DOWN: u8 = 1 << 1;
 const INPUT_LEFT: u8 = 1 << 2;
 const INPUT_RIGHT: u8 = 1 << 3;
+const INPUT_FIRE: u8 = 1 << 4;
+// set when aim holds a direction, otherwise the tank keeps its last aim
+const INPUT_AIM: u8 = 1 << 5;
+const INPUT_SECONDARY: u8 = 1 << 6;
 
-pub fn input(_handle: In<PlayerHandle>, keyboard_input: Res<Input<KeyCode>>) -> BoxInput {
-    let mut input: u8 = 0;
-
-    if keyboard_input.pressed(KeyCode::W) {
-        input |= INPUT_UP;
-    }
-    if keyboard_input.pressed(KeyCode::A) {
-        input |= INPUT_LEFT;
-    }
-    if keyboard_input.pressed(KeyCode::S) {
-        input |= INPUT_DOWN;
-    }
-    if keyboard_input.pressed(KeyCode::D) {
-        input |= INPUT_RIGHT;
-    }
+pub fn quantize_aim(dir: Vec2) -> u8 {
+    let turns = dir.y.atan2(dir.x) / std::f32::consts::TAU;
+    ((turns * 256.0).round() as i32).rem_euclid(256) as u8
+}
 
-    let mut x: u8 = 127;
-    let mut y: u8 = 127;
-    if keyboard_input.pressed(KeyCode::Up) {
-        y = 255;
-    }
-    if keyboard_input.pressed(KeyCode::Down) {
-        y = 0;
-    }
-    if keyboard_input.pressed(KeyCode::Left) {
-        x = 0;
-    }
-    if keyboard_input.pressed(KeyCode::Right) {
-        x = 255;
-    }
+pub fn aim_angle(aim: u8) -> f32 {
+    aim as f32 / 256.0 * std::f32::consts::TAU
+}
 
-    BoxInput {
-        inp: input,
-        sx: x,
-        sy: y,
-    }
+pub fn aim_direction(angle: f32) -> Vec2 {
+    Vec2::new(angle.cos(), angle.sin())
 }
 
+// the local player handles of the session, in order. the n-th local player
+// uses the n-th binding profile and the n-th gamepad
+pub struct LocalPlayers(pub Vec<PlayerHandle>);
+
 fn movement(
-    mut player_query: Query<(&mut Player, &mut Rigidbody)>,
+    mut player_query: Query<(&mut Player, &mut Rigidbody, &Health)>,
+    clock_query: Query<&MatchClock>,
     inputs: Res<Vec<(BoxInput, InputStatus)>>,
 ) {
-    for (player, mut rb) in player_query.iter_mut() {
+    // tanks stop when the round is over
+    if clock_query.iter().any(|c| c.expired()) {
+        return;
+    }
+    for (mut player, mut rb, health) in player_query.iter_mut() {
+        if !health.is_alive() {
+            continue;
+        }
         let input = inputs[player.handle as usize].0.inp;
+        if input & INPUT_AIM != 0 {
+            player.aim = aim_angle(inputs[player.handle as usize].0.aim);
+        }
         let mut acc = Vec2::new(0.0, 0.0);
         if input & INPUT_UP != 0 && input & INPUT_DOWN == 0 {
             acc.y += 1.0;
@@ -255,52 +718,101 @@ fn movement(
         }
         if acc.length_squared() > 0.0 {
             acc /= acc.length();
+        } else {
+            let analog = inputs[player.handle as usize].0;
+            acc = Vec2::new(analog.move_x as f32, analog.move_y as f32) / 127.0;
+            acc = acc.clamp_length_max(1.0);
         }
         rb.vel += acc * player.speed;
     }
 }
 
 fn shoot(
-    player_query: Query<(&Player, &Transform, &Rigidbody)>,
+    mut player_query: Query<(&Player, &Transform, &Health, &mut Arsenal)>,
     inputs: Res<Vec<(BoxInput, InputStatus)>>,
     mut commands: Commands,
     mut rip: ResMut<RollbackIdProvider>,
+    clock_query: Query<&MatchClock>,
+    mut events: ResMut<SimEvents>,
 ) {
-    for (player, player_transform, _rb_vels) in player_query.iter() {
+    if clock_query.iter().any(|c| c.expired()) {
+        return;
+    }
+    let dt = 1.0 / (FPS as f32);
+    for (player, player_transform, health, mut arsenal) in player_query.iter_mut() {
+        arsenal.cooldown = (arsenal.cooldown - dt).max(0.0);
+        if arsenal.ammo < SHELL_AMMO {
+            arsenal.reload -= dt;
+            if arsenal.reload <= 0.0 {
+                arsenal.ammo += 1;
+                arsenal.reload = SHELL_RELOAD;
+            }
+        }
+        if !health.is_alive() || arsenal.cooldown > 0.0 {
+            continue;
+        }
+
         let input = inputs[player.handle as usize].0;
-        let sx: f32 = ((input.sx as f32) - 127.0) / 256.0;
-        let sy: f32 = ((input.sy as f32) - 127.0) / 256.0;
-        let mut acc = Vec2::new(sx, sy);
-        if acc.length_squared() > 0.0 {
-            // TODO: don't shoot when inside wall
-            acc /= acc.length();
-            let head = Vec3::new(acc.x, acc.y, 0.0) * (2.0 + player.radius);
-            let angle = Vec2::angle_between(-Vec2::X, acc);
-            commands
-                .spawn()
-                .insert_bundle(SpriteBundle {
-                    transform: Transform {
-                        translation: player_transform.translation + head,
-                        rotation: Quat::from_euler(EulerRot::XYZ, 0.0, 0.0, angle),
-                        scale: Vec3::new(5.0, 2.0, 1.0),
-                    },
-                    sprite: Sprite {
-                        color: Color::WHITE,
-                        ..default()
-                    },
-                    ..default()
-                })
-                .insert(Bullet)
-                .insert(Fuse {
-                    lit: true,
-                    timeleft: 2.0,
-                })
-                .insert(Rigidbody {
-                    vel: acc * 10.0,
-                    friction: 0.0,
-                })
-                .insert(Rollback::new(rip.next_id()));
+        let weapon = if input.inp & INPUT_SECONDARY != 0 && arsenal.ammo > 0 {
+            WEAPON_SHELL
+        } else if input.inp & INPUT_FIRE != 0 {
+            WEAPON_CANNON
+        } else {
+            continue;
+        };
+        let stats = &WEAPONS[weapon as usize];
+        arsenal.weapon = weapon;
+        arsenal.cooldown = stats.cooldown;
+        if weapon == WEAPON_SHELL {
+            if arsenal.ammo == SHELL_AMMO {
+                arsenal.reload = SHELL_RELOAD;
+            }
+            arsenal.ammo -= 1;
         }
+
+        // movement updates the last aim in parallel, so read it here too
+        let angle = if input.inp & INPUT_AIM != 0 {
+            aim_angle(input.aim)
+        } else {
+            player.aim
+        };
+        let acc = aim_direction(angle);
+        // TODO: don't shoot when inside wall
+        let head = Vec3::new(acc.x, acc.y, 0.0) * (2.0 + player.radius);
+        let angle = Vec2::angle_between(-Vec2::X, acc);
+        events.emit(SimEvent::Shot {
+            handle: player.handle,
+            weapon,
+            position: (player_transform.translation + head).truncate(),
+            direction: acc,
+        });
+        commands
+            .spawn()
+            .insert_bundle(SpriteBundle {
+                transform: Transform {
+                    translation: player_transform.translation + head,
+                    rotation: Quat::from_euler(EulerRot::XYZ, 0.0, 0.0, angle),
+                    scale: stats.size.extend(1.0),
+                },
+                sprite: Sprite {
+                    color: Color::WHITE,
+                    ..default()
+                },
+                ..default()
+            })
+            .insert(Bullet {
+                owner: player.handle,
+                weapon,
+            })
+            .insert(Fuse {
+                lit: true,
+                timeleft: 2.0,
+            })
+            .insert(Rigidbody {
+                vel: acc * stats.speed,
+                friction: 0.0,
+            })
+            .insert(Rollback::new(rip.next_id()));
     }
 }
 
@@ -314,7 +826,7 @@ fn intersect_segment_segment(a: Vec3, b: Vec3, c: Vec3, d: Vec3) -> bool {
 }
 
 // https://stackoverflow.com/questions/1073336
-fn intersect_segment_circle(e: Vec3, l: Vec3, c: Vec3, r: f32) -> bool {
+pub fn intersect_segment_circle(e: Vec3, l: Vec3, c: Vec3, r: f32) -> bool {
     if (e + l - c).length_squared() < r * r {
         return true;
     }
@@ -431,29 +943,118 @@ fn collision_player_wall(
     (false, (pos, vel))
 }
 
+// where a circle moving by vel ends up against a wall, and how fast
+fn collide_circle_wall(pos: Vec3, vel: Vec2, radius: f32, wall: &Transform) -> (Vec3, Vec2) {
+    let center = wall.translation;
+    let halfsize = wall.scale * 0.5;
+    let bottomright = center + Vec3::new(-halfsize.x, -halfsize.y, 0.0);
+    let topleft = center + Vec3::new(halfsize.x, halfsize.y, 0.0);
+    let (_has_collided, (pos, vel)) =
+        collision_player_wall(pos, vel.extend(0.0), radius, topleft, bottomright);
+    (pos, vel.truncate())
+}
+
+// whether a bullet moving by vel crosses one of the edges of a wall
+fn bullet_hits_wall(pos: Vec3, vel: Vec2, wall: &Transform) -> bool {
+    let hi = pos;
+    let lo = pos + vel.extend(0.0);
+    let center = wall.translation;
+    let halfsize = wall.scale * 0.5;
+    let bottomleft = center + Vec3::new(halfsize.x, -halfsize.y, 0.0);
+    let bottomright = center + Vec3::new(-halfsize.x, -halfsize.y, 0.0);
+    let topright = center + Vec3::new(-halfsize.x, halfsize.y, 0.0);
+    let topleft = center + Vec3::new(halfsize.x, halfsize.y, 0.0);
+    intersect_segment_segment(hi, lo, bottomleft, bottomright)
+        || intersect_segment_segment(hi, lo, bottomright, topright)
+        || intersect_segment_segment(hi, lo, topright, topleft)
+        || intersect_segment_segment(hi, lo, topleft, bottomleft)
+}
+
+// whether a point is less than reach away from a wall
+fn within_reach(wall: &Transform, pos: Vec3, reach: f32) -> bool {
+    let half = wall.scale.truncate() / 2.0;
+    let outside = ((pos - wall.translation).truncate().abs() - half).max(Vec2::ZERO);
+    outside.length() < reach
+}
+
+// the pieces of the walls within reach, in map order, from piece next on
+fn pieces_within_reach<'a, T>(
+    walls: &[(T, &'a WallPieces)],
+    pos: Vec3,
+    reach: f32,
+    next: usize,
+) -> Vec<&'a (usize, Transform)> {
+    let mut pieces: Vec<&(usize, Transform)> = walls
+        .iter()
+        .filter(|(_, wall)| within_reach(&wall.bounds, pos, reach))
+        .flat_map(|(_, wall)| wall.pieces.iter())
+        .filter(|(i, _)| *i >= next)
+        .collect();
+    pieces.sort_unstable_by_key(|(i, _)| *i);
+    pieces
+}
+
+// where a circle moving by vel ends up against the walls of a layer, the same
+// as against every wall of the map one after the other in map order. a wall
+// only moves a circle that is less than its radius and speed away, so the
+// merged walls out of reach are skipped, and after every push the reach is
+// looked at again
+pub fn collide_circle_walls<T>(
+    walls: &[(T, &WallPieces)],
+    mut pos: Vec3,
+    mut vel: Vec2,
+    radius: f32,
+) -> (Vec3, Vec2) {
+    let mut next = 0;
+    loop {
+        let reach = radius + vel.length() + 1.0;
+        let mut pushed = false;
+        for (i, piece) in pieces_within_reach(walls, pos, reach, next) {
+            next = i + 1;
+            let (new_pos, new_vel) = collide_circle_wall(pos, vel, radius, piece);
+            if (new_pos, new_vel) != (pos, vel) {
+                (pos, vel) = (new_pos, new_vel);
+                pushed = true;
+                break;
+            }
+        }
+        if !pushed {
+            return (pos, vel);
+        }
+    }
+}
+
+// the merged wall with the first piece in map order that a bullet moving by
+// vel hits, a bullet only crosses walls less than its speed away
+pub fn bullet_wall_hit<T: Copy>(walls: &[(T, &WallPieces)], pos: Vec3, vel: Vec2) -> Option<T> {
+    let (first, _) = pieces_within_reach(walls, pos, vel.length() + 1.0, 0)
+        .into_iter()
+        .find(|(_, piece)| bullet_hits_wall(pos, vel, piece))?;
+    walls
+        .iter()
+        .find(|(_, wall)| wall.pieces.iter().any(|(i, _)| i == first))
+        .map(|(wall, _)| *wall)
+}
+
 fn move_players(
     mut player_query: Query<
         (&mut Transform, &Player, &mut Rigidbody),
         (With<Player>, Without<Wall>),
     >,
-    wall_query: Query<&Transform, (With<Wall>, Without<Player>)>,
+    wall_query: Query<(&WallPieces, &MapLayer), With<Wall>>,
+    clock_query: Query<&MatchClock>,
+    rotation: Res<Rotation>,
 ) {
+    let layer = current_layer(&rotation, &clock_query);
+    let walls: Vec<_> = wall_query
+        .iter()
+        .filter(|(.., wall_layer)| wall_layer.0 == layer)
+        .map(|(wall, _)| ((), wall))
+        .collect();
     for (mut player_tr, player, mut rb) in player_query.iter_mut() {
-        for wall_tr in &wall_query {
-            let center = wall_tr.translation;
-            let halfsize = wall_tr.scale * 0.5;
-            let bottomright = center + Vec3::new(-halfsize.x, -halfsize.y, 0.0);
-            let topleft = center + Vec3::new(halfsize.x, halfsize.y, 0.0);
-            let (_has_collided, (pos, vel)) = collision_player_wall(
-                player_tr.translation,
-                Vec3::new(rb.vel.x, rb.vel.y, 0.0),
-                player.radius,
-                topleft,
-                bottomright,
-            );
-            player_tr.translation = pos;
-            rb.vel = Vec2::new(vel.x, vel.y);
-        }
+        let (pos, vel) = collide_circle_walls(&walls, player_tr.translation, rb.vel, player.radius);
+        player_tr.translation = pos;
+        rb.vel = vel;
         player_tr.translation.x += rb.vel.x;
         player_tr.translation.y += rb.vel.y;
         let friction = rb.friction;
@@ -463,14 +1064,44 @@ fn move_players(
 
 fn move_bullets(
     mut bullet_query: Query<
-        (&mut Transform, &mut Rigidbody, &mut Fuse),
-        (With<Bullet>, Without<Player>, Without<Wall>),
+        (&mut Transform, &mut Rigidbody, &mut Fuse, &Bullet),
+        (Without<Player>, Without<Wall>),
+    >,
+    mut player_query: Query<
+        (&Transform, &Player, &mut Health),
+        (With<Player>, Without<Bullet>, Without<Wall>),
+    >,
+    wall_query: Query<
+        (Entity, &WallPieces, &MapLayer),
+        (With<Wall>, Without<Bullet>, Without<Player>),
     >,
-    player_query: Query<(&Transform, &Player), (With<Player>, Without<Bullet>, Without<Wall>)>,
-    wall_query: Query<&Transform, (With<Wall>, Without<Bullet>, Without<Player>)>,
+    clock_query: Query<&MatchClock>,
+    rotation: Res<Rotation>,
+    teams: Res<Teams>,
+    mut events: ResMut<SimEvents>,
 ) {
-    for (mut bullet_tr, mut rb, mut fuse) in &mut bullet_query {
-        for (player_tr, player) in &player_query {
+    let layer = current_layer(&rotation, &clock_query);
+    let round = clock_query.iter().next().map_or(0, |c| c.round);
+    let team_play = rotation.team_play(round);
+    let coop = rotation.round(round).mode.is_coop();
+    let walls: Vec<_> = wall_query
+        .iter()
+        .filter(|(.., wall_layer)| wall_layer.0 == layer)
+        .map(|(entity, wall, _)| (entity, wall))
+        .collect();
+    // bullets go through teammates, and everyone is on the same side in co-op
+    let spared = |owner: usize, handle: usize| {
+        !teams.friendly_fire && (coop || (team_play && teams.allies(owner, handle)))
+    };
+    for (mut bullet_tr, mut rb, mut fuse, bullet) in &mut bullet_query {
+        // it hit an enemy, see move_enemies
+        if fuse.lit && fuse.timeleft <= 0.0 {
+            continue;
+        }
+        for (player_tr, player, mut health) in &mut player_query {
+            if !health.is_alive() || spared(bullet.owner, player.handle) {
+                continue;
+            }
             if intersect_segment_circle(
                 bullet_tr.translation,
                 Vec3::new(rb.vel.x, rb.vel.y, 0.0),
@@ -479,25 +1110,29 @@ fn move_bullets(
             ) {
                 fuse.timeleft = 0.0;
                 fuse.lit = true;
+                health.hp = (health.hp - WEAPONS[bullet.weapon as usize].damage).max(0.0);
+                health.last_hit_by = bullet.owner as i32;
+                health.last_hit_weapon = bullet.weapon;
+                events.emit(SimEvent::TankHit {
+                    owner: bullet.owner,
+                    victim: player.handle,
+                    weapon: bullet.weapon,
+                    position: bullet_tr.translation.truncate(),
+                });
+                // one tank per bullet
+                break;
             }
         }
-        for wall_tr in &wall_query {
-            let hi = bullet_tr.translation;
-            let lo = bullet_tr.translation + Vec3::new(rb.vel.x, rb.vel.y, 0.0);
-            let center = wall_tr.translation;
-            let halfsize = wall_tr.scale * 0.5;
-            let bottomleft = center + Vec3::new(halfsize.x, -halfsize.y, 0.0);
-            let bottomright = center + Vec3::new(-halfsize.x, -halfsize.y, 0.0);
-            let topright = center + Vec3::new(-halfsize.x, halfsize.y, 0.0);
-            let topleft = center + Vec3::new(halfsize.x, halfsize.y, 0.0);
-            if intersect_segment_segment(hi, lo, bottomleft, bottomright)
-                || intersect_segment_segment(hi, lo, bottomright, topright)
-                || intersect_segment_segment(hi, lo, topright, topleft)
-                || intersect_segment_segment(hi, lo, topleft, bottomleft)
-            {
-                fuse.timeleft = 0.0;
-                fuse.lit = true;
-            }
+        // the sparks come from the first wall
+        if let Some(wall) = bullet_wall_hit(&walls, bullet_tr.translation, rb.vel) {
+            fuse.timeleft = 0.0;
+            fuse.lit = true;
+            events.emit(SimEvent::WallHit {
+                owner: bullet.owner,
+                weapon: bullet.weapon,
+                position: bullet_tr.translation.truncate(),
+                wall,
+            });
         }
         bullet_tr.translation.x += rb.vel.x;
         bullet_tr.translation.y += rb.vel.y;
@@ -506,129 +1141,404 @@ fn move_bullets(
     }
 }
 
-fn clean_fuses(mut commands: Commands, mut fuse_query: Query<(Entity, &mut Fuse)>) {
-    for (entity, mut fuse) in &mut fuse_query {
+fn handle_deaths(
+    mut player_query: Query<(
+        &Player,
+        &mut Health,
+        &mut Score,
+        &mut Transform,
+        &mut Rigidbody,
+    )>,
+    clock_query: Query<&MatchClock>,
+    mut survival_query: Query<&mut Survival>,
+    rotation: Res<Rotation>,
+    teams: Res<Teams>,
+    mut events: ResMut<SimEvents>,
+) {
+    let round = clock_query.iter().next().map_or(0, |c| c.round);
+    let spawn_points = rotation.spawn_points(round);
+    let team_play = rotation.team_play(round);
+    // everyone shares the lives of a co-op round
+    let mut survival = survival_query
+        .iter_mut()
+        .next()
+        .filter(|_| rotation.round(round).mode.is_coop());
+    let mut killers = Vec::new();
+    for (player, mut health, mut score, mut transform, mut rb) in &mut player_query {
+        if health.hp <= 0.0 && health.respawn <= 0.0 {
+            // just died
+            score.deaths += 1;
+            health.respawn = RESPAWN_TIME;
+            rb.vel = Vec2::ZERO;
+            let killer = if health.last_hit_by >= 0 && health.last_hit_by as usize != player.handle
+            {
+                Some(health.last_hit_by as usize)
+            } else {
+                None
+            };
+            let teamkill = killer.map_or(false, |k| {
+                survival.is_some() || (team_play && teams.allies(k, player.handle))
+            });
+            if let Some(killer) = killer {
+                killers.push((killer, teamkill));
+            }
+            events.emit(SimEvent::Kill {
+                killer,
+                victim: player.handle,
+                weapon: health.last_hit_weapon,
+                position: transform.translation.truncate(),
+                teamkill,
+            });
+        } else if health.respawn > 0.0 {
+            health.respawn -= 1.0 / (FPS as f32);
+            if health.respawn <= 0.0 {
+                if let Some(survival) = &mut survival {
+                    // out of lives, until a wave is beaten
+                    if survival.lives <= 0 {
+                        health.respawn = f32::MIN_POSITIVE;
+                        continue;
+                    }
+                    survival.lives -= 1;
+                }
+                health.respawn = 0.0;
+                health.hp = MAX_HP;
+                health.last_hit_by = -1;
+                let team = teams.team(player.handle).filter(|_| team_play);
+                let spawn = spawn_points.position(player.handle, score.deaths as usize, team);
+                transform.translation = spawn.extend(transform.translation.z);
+            }
+        }
+    }
+    for (killer, teamkill) in killers {
+        for (player, _, mut score, _, _) in &mut player_query {
+            if player.handle == killer {
+                // a teamkill costs the team a kill
+                score.kills += if teamkill { -1 } else { 1 };
+            }
+        }
+    }
+}
+
+fn tick_clock(mut clock_query: Query<&mut MatchClock>) {
+    for mut clock in &mut clock_query {
+        clock.frame += 1;
+        clock.tick += 1;
+    }
+}
+
+fn clean_fuses(
+    mut commands: Commands,
+    mut fuse_query: Query<(Entity, &mut Fuse, &Transform, Option<&Bullet>)>,
+    mut events: ResMut<SimEvents>,
+) {
+    for (entity, mut fuse, transform, bullet) in &mut fuse_query {
         if fuse.lit {
             fuse.timeleft -= 1.0 / (FPS as f32);
             if fuse.timeleft <= 0.0 {
+                if let Some(bullet) = bullet {
+                    events.emit(SimEvent::Explosion {
+                        owner: bullet.owner,
+                        weapon: bullet.weapon,
+                        position: transform.translation.truncate(),
+                    });
+                }
                 commands.entity(entity).despawn();
             }
         }
     }
 }
 
-#[derive(serde::Deserialize)]
-struct Map {
-    name: String,
-    walls: Vec<Vec<i32>>,
-    hives: Vec<i32>,
-    lives: Vec<Vec<i32>>,
+// the world space rectangle covered by the walls of the current map
+#[derive(Clone)]
+pub struct MapBounds {
+    pub min: Vec2,
+    pub max: Vec2,
+}
+
+// where tanks (re)spawn, from the lives of the map. the third value of a
+// life is 0 for anyone or the team number, starting at 1
+pub struct SpawnPoints(pub Vec<(Vec2, usize)>);
+
+impl SpawnPoints {
+    // cycles through the spawn points, so that a tank doesn't come back
+    // where it died. a team without spawn points of its own uses them all
+    pub fn position(&self, handle: usize, deaths: usize, team: Option<usize>) -> Vec2 {
+        let own: Vec<Vec2> = match team {
+            Some(team) => self
+                .0
+                .iter()
+                .filter(|(_, t)| *t == team + 1)
+                .map(|(p, _)| *p)
+                .collect(),
+            None => Vec::new(),
+        };
+        let points = if own.is_empty() {
+            self.0.iter().map(|(p, _)| *p).collect()
+        } else {
+            own
+        };
+        if points.is_empty() {
+            return Vec2::new((handle as f32) * 20.0, 0.0);
+        }
+        points[(handle + deaths) % points.len()]
+    }
 }
 
-fn setup_map(mut commands: Commands) {
-    let file = File::open("assets/maps/NAME.txt").expect("No map file found");
-    let map: Map = serde_json::from_reader(BufReader::new(file)).unwrap();
+// where the collision code finds a wall of the map, origin is the size of
+// the map
+pub fn wall_transform(wall: &[i32], origin: Vec3) -> Transform {
+    let upleft = Vec3::new(wall[0] as f32, wall[1] as f32, 0.0);
+    let downright = Vec3::new(wall[2] as f32, wall[3] as f32, 0.0);
+    let center = (upleft + downright - origin) / 2.0;
+    let size = Vec3::new((wall[2] - wall[0]) as f32, (wall[3] - wall[1]) as f32, 1.0);
+    Transform {
+        translation: center - Vec3::new(0.0, 0.0, if wall[4] == 2 { 1.0 } else { 0.0 }),
+        scale: size,
+        ..default()
+    }
+}
 
-    let minx = map.walls.iter().map(|w| w[0]).min().unwrap() as f32;
-    let maxx = map.walls.iter().map(|w| w[2]).max().unwrap() as f32;
-    let miny = map.walls.iter().map(|w| w[1]).min().unwrap() as f32;
-    let maxy = map.walls.iter().map(|w| w[3]).max().unwrap() as f32;
+// the map has been validated, see load_playable_map. the walls of every
+// layer but the first start hidden
+fn setup_map(
+    commands: &mut Commands,
+    map: &Map,
+    merged: &[MergedWall],
+    layer: usize,
+) -> (SpawnPoints, MapBounds) {
+    let (min, max) = map.bounds().unwrap_or_default();
+    let (minx, miny) = (min.x as f32, min.y as f32);
+    let (maxx, maxy) = (max.x as f32, max.y as f32);
     let origin = Vec3::new(maxx - minx, maxy - miny, 0.0);
+    let bounds = MapBounds {
+        min: Vec2::new(minx, miny) - origin.truncate() / 2.0,
+        max: Vec2::new(maxx, maxy) - origin.truncate() / 2.0,
+    };
+    let spawn_points = SpawnPoints(
+        map.lives
+            .iter()
+            .map(|l| {
+                let position = Vec2::new(l[0] as f32, l[1] as f32) - origin.truncate() / 2.0;
+                (position, l.get(2).copied().unwrap_or(0).max(0) as usize)
+            })
+            .collect(),
+    );
+    let visibility = Visibility {
+        is_visible: layer == 0,
+    };
 
-    for wall in &map.walls {
-        let upleft = Vec3::new(wall[0] as f32, wall[1] as f32, 0.0);
-        let downright = Vec3::new(wall[2] as f32, wall[3] as f32, 0.0);
-        let center = (upleft + downright - origin) / 2.0;
-        let size = Vec3::new((wall[2] - wall[0]) as f32, (wall[3] - wall[1]) as f32, 1.0);
-        let color = match wall[4] {
-            1 => Color::rgba(0.7, 0.2, 0.0, 1.0),
-            2 => Color::rgba(0.15, 0.4, 0.03, 1.0),
-            3 => Color::rgba(0.4, 0.4, 0.4, 1.0),
-            _ => Color::rgba(1.0, 0.4, 0.03, 1.0),
-        };
-        let movecenter = center - Vec3::new(0.0, 0.0, if wall[4] == 2 { 1.0 } else { 0.0 });
-
-        commands.spawn_bundle(SpriteBundle {
-            transform: Transform {
-                translation: movecenter,
-                scale: Vec3::new(
-                    (wall[2] - wall[0] + 3) as f32,
-                    (wall[3] - wall[1] + 3) as f32,
-                    1.0,
-                ),
-                ..default()
-            },
-            sprite: Sprite {
-                color: Color::BLACK,
-                ..default()
-            },
-            ..default()
+    for merged_wall in merged {
+        // the collision code finds them all on the first wall drawn
+        let mut pieces = Some(WallPieces {
+            bounds: wall_transform(&merged_wall.bounds(), origin),
+            pieces: merged_wall
+                .pieces
+                .iter()
+                .map(|i| (*i, wall_transform(&map.walls[*i], origin)))
+                .collect(),
         });
+        for wall in &merged_wall.rects {
+            let transform = wall_transform(wall, origin);
+            let movecenter = transform.translation;
+            let color = wall_color(wall[4]);
 
-        let entity = commands
-            .spawn_bundle(SpriteBundle {
-                transform: Transform {
-                    translation: movecenter,
-                    scale: size,
-                    ..default()
-                },
-                sprite: Sprite { color, ..default() },
-                ..default()
-            })
-            .insert(Wall)
-            .id();
-        /*
-        if wall[4] == 1 {
-            commands
-                .entity(entity)
-                .insert(CollisionGroups::new(0b100, 0b111));
-        } else {
             commands
-                .entity(entity)
-                .insert(CollisionGroups::new(0b100, 0b101));
+                .spawn_bundle(SpriteBundle {
+                    transform: Transform {
+                        translation: movecenter,
+                        scale: Vec3::new(
+                            (wall[2] - wall[0] + 3) as f32,
+                            (wall[3] - wall[1] + 3) as f32,
+                            1.0,
+                        ),
+                        ..default()
+                    },
+                    sprite: Sprite {
+                        color: Color::BLACK,
+                        ..default()
+                    },
+                    visibility: visibility.clone(),
+                    ..default()
+                })
+                .insert(MapLayer(layer))
+                .insert(MatchEntity);
+
+            let entity = commands
+                .spawn_bundle(SpriteBundle {
+                    transform,
+                    sprite: Sprite { color, ..default() },
+                    visibility: visibility.clone(),
+                    ..default()
+                })
+                .insert(Wall)
+                .insert(MapLayer(layer))
+                .insert(MatchEntity)
+                .id();
+            if let Some(pieces) = pieces.take() {
+                commands.entity(entity).insert(pieces);
+            }
+            /*
+            if wall[4] == 1 {
+                commands
+                    .entity(entity)
+                    .insert(CollisionGroups::new(0b100, 0b111));
+            } else {
+                commands
+                    .entity(entity)
+                    .insert(CollisionGroups::new(0b100, 0b101));
+            }
+            */
         }
-        */
     }
+    (spawn_points, bounds)
 }
 
+// loads every map of the rotation, each once. the lobby made sure every
+// peer has the same files
+fn setup_rotation(
+    commands: &mut Commands,
+    rip: &mut RollbackIdProvider,
+    config: &MatchConfig,
+    teams: &Teams,
+) -> Result<Rotation, String> {
+    let mut rotation = Rotation {
+        rounds: Vec::new(),
+        maps: Vec::new(),
+        spawn_points: Vec::new(),
+        bounds: Vec::new(),
+        advances: config.playlist.is_some(),
+    };
+    let default_scores = config
+        .playlist
+        .as_ref()
+        .map_or(ScoreRule::Reset, |p| p.scores);
+    let mut maps = Vec::new();
+    for entry in match_entries(config) {
+        let layer = match rotation.maps.iter().position(|m| *m == entry.map) {
+            Some(layer) => layer,
+            None => {
+                let (map, merged) = load_playable_map(&entry.map)?;
+                let layer = rotation.maps.len();
+                let (spawn_points, bounds) = setup_map(commands, &map, &merged, layer);
+                spawn_flags(commands, rip, &map, layer, teams);
+                spawn_zones(commands, rip, &map, layer);
+                spawn_hives(commands, rip, &map, layer);
+                rotation.maps.push(entry.map.clone());
+                rotation.spawn_points.push(spawn_points);
+                rotation.bounds.push(bounds);
+                maps.push(map);
+                layer
+            }
+        };
+        if entry.mode.has_flags() {
+            let missing = missing_flags(&maps[layer], teams.count);
+            if !missing.is_empty() {
+                let missing: Vec<String> = missing.iter().map(|t| t.to_string()).collect();
+                return Err(format!(
+                    "{} has no flag for team {}, capture the flag needs one per team",
+                    map_path(&entry.map),
+                    missing.join(", ")
+                ));
+            }
+        }
+        if entry.mode.has_zones() && maps[layer].zones.is_empty() {
+            return Err(format!(
+                "{} has no zones, king of the hill needs at least one",
+                map_path(&entry.map)
+            ));
+        }
+        if entry.mode.is_coop() && maps[layer].hives.is_empty() {
+            return Err(format!(
+                "{} has no hives, survival needs at least one",
+                map_path(&entry.map)
+            ));
+        }
+        // a survival round goes on until everyone is dead
+        let duration = if entry.mode.is_coop() {
+            SURVIVAL_DURATION
+        } else {
+            entry.time_limit.unwrap_or(config.time_limit) * FPS as u32
+        };
+        rotation.rounds.push(Round {
+            layer,
+            mode: entry.mode,
+            duration,
+            reset_scores: entry.scores.unwrap_or(default_scores) == ScoreRule::Reset,
+        });
+    }
+    Ok(rotation)
+}
+
+#[allow(clippy::too_many_arguments)]
 fn setup(
     mut commands: Commands,
     mut rip: ResMut<RollbackIdProvider>,
+    config: Res<MatchConfig>,
     p2p_session: Option<Res<P2PSession<GGRSConfig>>>,
     synctest_session: Option<Res<SyncTestSession<GGRSConfig>>>,
     spectator_session: Option<Res<SpectatorSession<GGRSConfig>>>,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<ColorMaterial>>,
+    teams: Res<Teams>,
+    mut menu: ResMut<MenuState>,
+    mut state: ResMut<State<GameState>>,
 ) {
+    let rotation = match setup_rotation(&mut commands, &mut rip, &config, &teams) {
+        Ok(rotation) => rotation,
+        Err(e) => {
+            error!("{}", e);
+            menu.status = format!("can't load the map: {}", e);
+            state.set(GameState::MainMenu).unwrap();
+            return;
+        }
+    };
     let num_players = p2p_session
         .map(|s| s.num_players())
         .or_else(|| synctest_session.map(|s| s.num_players()))
         .or_else(|| spectator_session.map(|s| s.num_players()))
         .expect("No GGRS session found");
 
+    let team_play = rotation.team_play(0);
     for handle in 0..num_players {
+        let team = teams.team(handle).filter(|_| team_play);
         commands
-            .spawn_bundle(MaterialMesh2dBundle {
-                mesh: meshes.add(Mesh::from(shape::Circle::new(10.0))).into(),
+            .spawn_bundle(SpatialBundle {
                 transform: Transform {
-                    translation: Vec3::new((handle as f32) * 20.0, 0.0, 0.0),
+                    translation: rotation
+                        .spawn_points(0)
+                        .position(handle, 0, team)
+                        .extend(0.0),
                     scale: Vec3::splat(1.0),
                     ..default()
                 },
-                material: materials.add(ColorMaterial::from(Color::WHITE)).into(),
                 ..default()
             })
             .insert(Player {
                 handle,
                 speed: 1.0,
-                radius: 10.0,
+                radius: TANK_RADIUS,
+                aim: 0.0,
             })
             .insert(Rigidbody {
                 vel: Vec2::new(0.0, 0.0),
                 friction: 0.2,
             })
+            .insert(Health::full())
+            .insert(Arsenal::full())
+            .insert(Score::default())
             .insert(Rollback::new(rip.next_id()));
     }
 
-    setup_map(commands);
+    commands
+        .spawn()
+        .insert(MatchClock {
+            frame: 0,
+            duration: rotation.round(0).duration,
+            round: 0,
+            tick: 0,
+        })
+        .insert(Rollback::new(rip.next_id()));
+    spawn_hill_scores(&mut commands, &mut rip, &teams);
+    spawn_survival(&mut commands, &mut rip);
+
+    commands.insert_resource(rotation.bounds[0].clone());
+    commands.insert_resource(rotation);
 }
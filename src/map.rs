@@ -0,0 +1,1000 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::Path;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::mapgen::{generate_map, parse_generated};
+use crate::teams::MAX_TEAMS;
+use crate::TANK_RADIUS;
+
+pub const MAPS_DIR: &str = "assets/maps";
+pub const WALL_TYPES: i32 = 4;
+// cell size of the grid the reachability check drives tanks around on
+const REACH_STEP: i32 = 5;
+
+// walls are [x1, y1, x2, y2, type], lives are [x, y, team], flags are
+// [x, y, team] for capture the flag, zones are [x1, y1, x2, y2] for king of
+// the hill
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Map {
+    pub name: String,
+    #[serde(deserialize_with = "deserialize_walls")]
+    pub walls: Vec<Vec<i32>>,
+    pub hives: Vec<Hive>,
+    pub lives: Vec<Vec<i32>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub flags: Vec<Vec<i32>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub zones: Vec<Vec<i32>>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Hive {
+    pub enemy_type: i32,
+    pub max_enemies: i32,
+    pub pos: Vec<i32>,
+    pub radius: i32,
+    pub respawn_time: i32,
+}
+
+impl Hive {
+    // what SWAM's hives look like
+    pub fn new(pos: Vec<i32>) -> Self {
+        Self {
+            enemy_type: 0,
+            max_enemies: 5,
+            pos,
+            radius: 50,
+            respawn_time: 600,
+        }
+    }
+}
+
+// some maps store the wall type as a bool
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MapNumber {
+    Int(i32),
+    Bool(bool),
+}
+
+fn deserialize_walls<'de, D>(deserializer: D) -> Result<Vec<Vec<i32>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let walls: Vec<Vec<MapNumber>> = Deserialize::deserialize(deserializer)?;
+    Ok(walls
+        .into_iter()
+        .map(|wall| {
+            wall.into_iter()
+                .map(|n| match n {
+                    MapNumber::Int(i) => i,
+                    MapNumber::Bool(b) => b as i32,
+                })
+                .collect()
+        })
+        .collect())
+}
+
+pub fn map_path(name: &str) -> String {
+    format!("{}/{}.txt", MAPS_DIR, name)
+}
+
+pub fn read_map(path: &Path) -> io::Result<Map> {
+    let file = File::open(path)?;
+    serde_json::from_reader(BufReader::new(file))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// generated maps are built from their name, there's no file to go out of date
+pub fn load_map(name: &str) -> io::Result<Map> {
+    if let Some((style, seed, hives)) = parse_generated(name) {
+        return Ok(generate_map(style, seed, hives));
+    }
+    read_map(Path::new(&map_path(name)))
+}
+
+// a map that setup_map can build, and its walls merged
+pub fn load_playable_map(name: &str) -> Result<(Map, Vec<MergedWall>), String> {
+    let path = map_path(name);
+    let map = load_map(name).map_err(|e| format!("{}: {}", path, e))?;
+    let problems = validate_map(&map);
+    if let Some(error) = problems.iter().find(|p| p.severity == Severity::Error) {
+        return Err(format!("{}: {}", path, error));
+    }
+    if !problems.is_empty() {
+        warn!(
+            "{} has {} warnings, see `tanks validate-map {}`",
+            path,
+            problems.len(),
+            path
+        );
+    }
+    let merged = merge_walls(&map.walls);
+    info!(
+        "{}: {} walls, drawn as {} and checked as {}",
+        path,
+        map.walls.len(),
+        merged.iter().map(|wall| wall.rects.len()).sum::<usize>(),
+        merged.len()
+    );
+    Ok((map, merged))
+}
+
+pub fn save_map(map: &Map, name: &str) -> io::Result<()> {
+    fs::create_dir_all(MAPS_DIR)?;
+    let file = File::create(map_path(name))?;
+    serde_json::to_writer_pretty(file, map).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+}
+
+// fnv-1a over the file, what peers compare maps by
+pub fn map_hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// by the type in wall[4]
+pub fn wall_color(kind: i32) -> Color {
+    match kind {
+        1 => Color::rgba(0.7, 0.2, 0.0, 1.0),
+        2 => Color::rgba(0.15, 0.4, 0.03, 1.0),
+        3 => Color::rgba(0.4, 0.4, 0.4, 1.0),
+        _ => Color::rgba(1.0, 0.4, 0.03, 1.0),
+    }
+}
+
+impl Map {
+    // the rectangle around all the walls
+    pub fn bounds(&self) -> Option<(IVec2, IVec2)> {
+        let min_x = self.walls.iter().map(|w| w[0]).min()?;
+        let min_y = self.walls.iter().map(|w| w[1]).min()?;
+        let max_x = self.walls.iter().map(|w| w[2]).max()?;
+        let max_y = self.walls.iter().map(|w| w[3]).max()?;
+        Some((IVec2::new(min_x, min_y), IVec2::new(max_x, max_y)))
+    }
+}
+
+// walls of the map of the same type that touch, drawn as fewer rectangles
+// where their union allows it and looked at as one by the collision code
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MergedWall {
+    // cover exactly what the pieces cover
+    pub rects: Vec<Vec<i32>>,
+    // by their index in the map
+    pub pieces: Vec<usize>,
+}
+
+impl MergedWall {
+    // the rectangle around all the pieces
+    pub fn bounds(&self) -> Vec<i32> {
+        let mut bounds = self.rects[0].clone();
+        for rect in &self.rects[1..] {
+            bounds[0] = bounds[0].min(rect[0]);
+            bounds[1] = bounds[1].min(rect[1]);
+            bounds[2] = bounds[2].max(rect[2]);
+            bounds[3] = bounds[3].max(rect[3]);
+        }
+        bounds
+    }
+}
+
+// a rectangle and the walls of the map it was merged from
+struct Merged {
+    rect: Vec<i32>,
+    pieces: Vec<usize>,
+}
+
+// joins walls of the same type whose union is still a rectangle: one inside
+// the other, or the same span on one axis and touching or overlapping on the
+// other, then groups the walls of the same type that still touch. there are
+// fewer sprites and fewer walls for every tank and bullet to check, but a
+// tank in an overlap or along a seam bounces off both walls, so collisions
+// still go through the walls of the map in map order, see
+// collide_circle_walls
+pub fn merge_walls(walls: &[Vec<i32>]) -> Vec<MergedWall> {
+    let mut merged: Vec<Merged> = walls
+        .iter()
+        .enumerate()
+        .map(|(i, wall)| Merged {
+            rect: wall.clone(),
+            pieces: vec![i],
+        })
+        .collect();
+    loop {
+        let before = merged.len();
+        absorb_contained(&mut merged);
+        // rows first, then columns, until nothing changes
+        merge_along(&mut merged, 0);
+        merge_along(&mut merged, 1);
+        if merged.len() == before {
+            break;
+        }
+    }
+    for rect in &mut merged {
+        rect.pieces.sort_unstable();
+    }
+    merged.sort_by_key(|rect| rect.pieces[0]);
+    group_touching(merged)
+}
+
+fn touch(a: &[i32], b: &[i32]) -> bool {
+    a[4] == b[4] && a[0] <= b[2] && b[0] <= a[2] && a[1] <= b[3] && b[1] <= a[3]
+}
+
+// the first rectangle of the group of rectangle i
+fn root(group: &mut [usize], mut i: usize) -> usize {
+    while group[i] != i {
+        group[i] = group[group[i]];
+        i = group[i];
+    }
+    i
+}
+
+// in map order, by the first piece of every group and rectangle
+fn group_touching(merged: Vec<Merged>) -> Vec<MergedWall> {
+    // the group of every rectangle, by the first rectangle in it
+    let mut group: Vec<usize> = (0..merged.len()).collect();
+    for i in 0..merged.len() {
+        for j in i + 1..merged.len() {
+            if touch(&merged[i].rect, &merged[j].rect) {
+                let (a, b) = (root(&mut group, i), root(&mut group, j));
+                group[a.max(b)] = a.min(b);
+            }
+        }
+    }
+    let mut walls: Vec<MergedWall> = Vec::new();
+    let mut index: Vec<Option<usize>> = vec![None; merged.len()];
+    for (i, rect) in merged.into_iter().enumerate() {
+        let first = root(&mut group, i);
+        let wall = match index[first] {
+            Some(wall) => wall,
+            None => {
+                walls.push(MergedWall {
+                    rects: Vec::new(),
+                    pieces: Vec::new(),
+                });
+                index[first] = Some(walls.len() - 1);
+                walls.len() - 1
+            }
+        };
+        walls[wall].rects.push(rect.rect);
+        walls[wall].pieces.extend(rect.pieces);
+    }
+    for wall in &mut walls {
+        wall.pieces.sort_unstable();
+    }
+    walls
+}
+
+fn contains(outer: &[i32], inner: &[i32]) -> bool {
+    outer[4] == inner[4]
+        && outer[0] <= inner[0]
+        && outer[1] <= inner[1]
+        && inner[2] <= outer[2]
+        && inner[3] <= outer[3]
+}
+
+fn absorb_contained(merged: &mut Vec<Merged>) {
+    let mut i = 0;
+    while i < merged.len() {
+        let outer =
+            (0..merged.len()).find(|&j| j != i && contains(&merged[j].rect, &merged[i].rect));
+        match outer {
+            Some(j) => {
+                let inner = merged.remove(i);
+                let j = if j > i { j - 1 } else { j };
+                merged[j].pieces.extend(inner.pieces);
+            }
+            None => i += 1,
+        }
+    }
+}
+
+// axis 0 joins walls side by side, 1 on top of each other
+fn merge_along(merged: &mut Vec<Merged>, axis: usize) {
+    let other = 1 - axis;
+    // neighbours end up next to each other, so one pass joins whole rows
+    merged.sort_by_key(|m| {
+        let r = &m.rect;
+        (r[4], r[other], r[other + 2], r[axis], r[axis + 2])
+    });
+    let mut joined: Vec<Merged> = Vec::with_capacity(merged.len());
+    for next in merged.drain(..) {
+        if let Some(last) = joined.last_mut() {
+            let same_span = last.rect[4] == next.rect[4]
+                && last.rect[other] == next.rect[other]
+                && last.rect[other + 2] == next.rect[other + 2];
+            if same_span && next.rect[axis] <= last.rect[axis + 2] {
+                last.rect[axis + 2] = last.rect[axis + 2].max(next.rect[axis + 2]);
+                last.pieces.extend(next.pieces);
+                continue;
+            }
+        }
+        joined.push(next);
+    }
+    *merged = joined;
+}
+
+// errors keep the game from building the map at all, warnings are for maps
+// that load but don't play right
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+pub struct MapProblem {
+    pub severity: Severity,
+    // which entry, like walls[3]
+    pub location: String,
+    pub message: String,
+}
+
+impl MapProblem {
+    fn error(location: String, message: String) -> Self {
+        Self {
+            severity: Severity::Error,
+            location,
+            message,
+        }
+    }
+
+    fn warning(location: String, message: String) -> Self {
+        Self {
+            severity: Severity::Warning,
+            location,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for MapProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}: {}: {}", severity, self.location, self.message)
+    }
+}
+
+struct Rect {
+    min: IVec2,
+    max: IVec2,
+}
+
+impl Rect {
+    fn distance_squared(&self, point: Vec2) -> f32 {
+        let outside = (self.min.as_vec2() - point)
+            .max(point - self.max.as_vec2())
+            .max(Vec2::ZERO);
+        outside.length_squared()
+    }
+
+    fn overlaps(&self, other: &Rect) -> bool {
+        self.min.x < other.max.x
+            && other.min.x < self.max.x
+            && self.min.y < other.max.y
+            && other.min.y < self.max.y
+    }
+}
+
+fn point_string(point: Vec2) -> String {
+    format!("({}, {})", point.x, point.y)
+}
+
+pub fn validate_map(map: &Map) -> Vec<MapProblem> {
+    let mut problems = Vec::new();
+
+    // (index, rectangle) of the walls that make sense
+    let mut walls = Vec::new();
+    for (i, wall) in map.walls.iter().enumerate() {
+        let location = format!("walls[{}]", i);
+        if wall.len() != 5 {
+            problems.push(MapProblem::error(
+                location,
+                format!(
+                    "expected [x1, y1, x2, y2, type], found {} values",
+                    wall.len()
+                ),
+            ));
+            continue;
+        }
+        if wall[0] >= wall[2] || wall[1] >= wall[3] {
+            problems.push(MapProblem::error(
+                location,
+                format!(
+                    "({}, {})-({}, {}) needs x1 < x2 and y1 < y2",
+                    wall[0], wall[1], wall[2], wall[3]
+                ),
+            ));
+            continue;
+        }
+        if !(0..WALL_TYPES).contains(&wall[4]) {
+            problems.push(MapProblem::warning(
+                location,
+                format!("unknown type {}, it looks like type 0", wall[4]),
+            ));
+        }
+        walls.push((
+            i,
+            Rect {
+                min: IVec2::new(wall[0], wall[1]),
+                max: IVec2::new(wall[2], wall[3]),
+            },
+        ));
+    }
+    let mut spawns = Vec::new();
+    for (i, life) in map.lives.iter().enumerate() {
+        if life.len() < 2 {
+            problems.push(MapProblem::error(
+                format!("lives[{}]", i),
+                format!("expected [x, y, team], found {} values", life.len()),
+            ));
+            continue;
+        }
+        // 0 is anyone, n is team n
+        if let Some(&team) = life.get(2) {
+            if team < 0 || team > MAX_TEAMS as i32 {
+                problems.push(MapProblem::warning(
+                    format!("lives[{}]", i),
+                    format!(
+                        "team {} isn't between 0 and {}, anyone spawns there",
+                        team, MAX_TEAMS
+                    ),
+                ));
+            }
+        }
+        spawns.push((i, Vec2::new(life[0] as f32, life[1] as f32)));
+    }
+    let mut hives = Vec::new();
+    for (i, hive) in map.hives.iter().enumerate() {
+        if hive.pos.len() != 2 {
+            problems.push(MapProblem::error(
+                format!("hives[{}].pos", i),
+                format!("expected [x, y], found {} values", hive.pos.len()),
+            ));
+            continue;
+        }
+        hives.push((i, Vec2::new(hive.pos[0] as f32, hive.pos[1] as f32)));
+    }
+    let mut flags = Vec::new();
+    for (i, flag) in map.flags.iter().enumerate() {
+        let location = format!("flags[{}]", i);
+        if flag.len() != 3 {
+            problems.push(MapProblem::error(
+                location,
+                format!("expected [x, y, team], found {} values", flag.len()),
+            ));
+            continue;
+        }
+        if !(1..=MAX_TEAMS as i32).contains(&flag[2]) {
+            problems.push(MapProblem::warning(
+                location,
+                format!(
+                    "team {} isn't between 1 and {}, the flag is left out",
+                    flag[2], MAX_TEAMS
+                ),
+            ));
+            continue;
+        }
+        if let Some(j) = map.flags[..i]
+            .iter()
+            .position(|f| f.get(2) == Some(&flag[2]))
+        {
+            problems.push(MapProblem::warning(
+                location,
+                format!("team {} has its flag at flags[{}] already", flag[2], j),
+            ));
+            continue;
+        }
+        flags.push((i, Vec2::new(flag[0] as f32, flag[1] as f32)));
+    }
+    let mut zones = Vec::new();
+    for (i, zone) in map.zones.iter().enumerate() {
+        let location = format!("zones[{}]", i);
+        if zone.len() != 4 {
+            problems.push(MapProblem::error(
+                location,
+                format!("expected [x1, y1, x2, y2], found {} values", zone.len()),
+            ));
+            continue;
+        }
+        if zone[0] >= zone[2] || zone[1] >= zone[3] {
+            problems.push(MapProblem::error(
+                location,
+                format!(
+                    "({}, {})-({}, {}) needs x1 < x2 and y1 < y2",
+                    zone[0], zone[1], zone[2], zone[3]
+                ),
+            ));
+            continue;
+        }
+        zones.push((
+            i,
+            Rect {
+                min: IVec2::new(zone[0], zone[1]),
+                max: IVec2::new(zone[2], zone[3]),
+            },
+        ));
+    }
+
+    if map.walls.is_empty() {
+        problems.push(MapProblem::error(
+            "walls".to_string(),
+            "no walls, the map has no bounds".to_string(),
+        ));
+    }
+    if problems.iter().any(|p| p.severity == Severity::Error) {
+        return problems;
+    }
+    let (min, max) = map.bounds().unwrap_or_default();
+    let bounds = Rect { min, max };
+    let bounds_string = format!("({}, {})-({}, {})", min.x, min.y, max.x, max.y);
+
+    for (a, (i, wall)) in walls.iter().enumerate() {
+        for (j, other) in &walls[a + 1..] {
+            if wall.overlaps(other) {
+                problems.push(MapProblem::warning(
+                    format!("walls[{}]", i),
+                    format!("overlaps walls[{}]", j),
+                ));
+            }
+        }
+    }
+
+    if spawns.is_empty() {
+        problems.push(MapProblem::warning(
+            "lives".to_string(),
+            "no spawn points, tanks start in a row at the origin".to_string(),
+        ));
+    }
+    let radius_squared = TANK_RADIUS * TANK_RADIUS;
+    // the spawn points a tank fits on
+    let mut open_spawns = Vec::new();
+    for (i, position) in &spawns {
+        let location = format!("lives[{}]", i);
+        if bounds.distance_squared(*position) > 0.0 {
+            problems.push(MapProblem::warning(
+                location,
+                format!(
+                    "{} is outside the map bounds {}",
+                    point_string(*position),
+                    bounds_string
+                ),
+            ));
+        } else if let Some((j, _)) = walls
+            .iter()
+            .find(|(_, wall)| wall.distance_squared(*position) < radius_squared)
+        {
+            problems.push(MapProblem::warning(
+                location,
+                format!("{} is inside walls[{}]", point_string(*position), j),
+            ));
+        } else {
+            open_spawns.push((*i, *position));
+        }
+    }
+    for (i, position) in &hives {
+        if bounds.distance_squared(*position) > 0.0 {
+            problems.push(MapProblem::warning(
+                format!("hives[{}]", i),
+                format!(
+                    "{} is outside the map bounds {}",
+                    point_string(*position),
+                    bounds_string
+                ),
+            ));
+        }
+    }
+
+    for (i, position) in &flags {
+        let location = format!("flags[{}]", i);
+        if bounds.distance_squared(*position) > 0.0 {
+            problems.push(MapProblem::warning(
+                location,
+                format!(
+                    "{} is outside the map bounds {}",
+                    point_string(*position),
+                    bounds_string
+                ),
+            ));
+        } else if let Some((j, _)) = walls
+            .iter()
+            .find(|(_, wall)| wall.distance_squared(*position) < radius_squared)
+        {
+            problems.push(MapProblem::warning(
+                location,
+                format!("{} is inside walls[{}]", point_string(*position), j),
+            ));
+        }
+    }
+
+    for (i, zone) in &zones {
+        if !zone.overlaps(&bounds) {
+            problems.push(MapProblem::warning(
+                format!("zones[{}]", i),
+                format!("is outside the map bounds {}", bounds_string),
+            ));
+        }
+    }
+
+    problems.extend(unreachable_spawns(&bounds, &walls, &open_spawns));
+    problems
+}
+
+// spawn points a tank can't drive to from most of the others
+fn unreachable_spawns(
+    bounds: &Rect,
+    walls: &[(usize, Rect)],
+    spawns: &[(usize, Vec2)],
+) -> Vec<MapProblem> {
+    if spawns.len() < 2 {
+        return Vec::new();
+    }
+    let size = (bounds.max - bounds.min) / REACH_STEP + IVec2::ONE;
+    let index = |cell: IVec2| (cell.y * size.x + cell.x) as usize;
+    let cell_position = |cell: IVec2| (bounds.min + cell * REACH_STEP).as_vec2();
+    let radius_squared = TANK_RADIUS * TANK_RADIUS;
+    let reach = TANK_RADIUS.ceil() as i32;
+
+    // cells a tank centered on them would touch a wall from
+    let mut blocked = vec![false; (size.x * size.y) as usize];
+    for (_, wall) in walls {
+        let low = ((wall.min - bounds.min - IVec2::splat(reach)) / REACH_STEP).max(IVec2::ZERO);
+        let high = ((wall.max - bounds.min + IVec2::splat(reach)) / REACH_STEP + IVec2::ONE)
+            .min(size - IVec2::ONE);
+        for x in low.x..=high.x {
+            for y in low.y..=high.y {
+                let cell = IVec2::new(x, y);
+                if wall.distance_squared(cell_position(cell)) < radius_squared {
+                    blocked[index(cell)] = true;
+                }
+            }
+        }
+    }
+
+    // flood fill from every spawn point that isn't in a region yet
+    let mut region = vec![usize::MAX; blocked.len()];
+    let mut spawn_regions = Vec::new();
+    let mut regions = 0;
+    for (_, position) in spawns {
+        let corner = ((*position - bounds.min.as_vec2()) / REACH_STEP as f32)
+            .floor()
+            .as_ivec2();
+        let start = [IVec2::ZERO, IVec2::X, IVec2::Y, IVec2::ONE]
+            .iter()
+            .map(|offset| corner + *offset)
+            .filter(|cell| cell.cmpge(IVec2::ZERO).all() && cell.cmplt(size).all())
+            .find(|cell| !blocked[index(*cell)]);
+        let start = match start {
+            Some(start) => start,
+            // right up against a wall, no telling
+            None => {
+                spawn_regions.push(None);
+                continue;
+            }
+        };
+        if region[index(start)] == usize::MAX {
+            region[index(start)] = regions;
+            let mut stack = vec![start];
+            while let Some(cell) = stack.pop() {
+                for step in [IVec2::X, -IVec2::X, IVec2::Y, -IVec2::Y] {
+                    let next = cell + step;
+                    if next.cmpge(IVec2::ZERO).all()
+                        && next.cmplt(size).all()
+                        && !blocked[index(next)]
+                        && region[index(next)] == usize::MAX
+                    {
+                        region[index(next)] = regions;
+                        stack.push(next);
+                    }
+                }
+            }
+            regions += 1;
+        }
+        spawn_regions.push(Some(region[index(start)]));
+    }
+
+    // the region with the most spawn points is where the match happens
+    let mut counts = vec![0; regions];
+    for r in spawn_regions.iter().flatten() {
+        counts[*r] += 1;
+    }
+    let main = match (0..regions).max_by_key(|r| (counts[*r], usize::MAX - r)) {
+        Some(main) => main,
+        None => return Vec::new(),
+    };
+    let first = spawn_regions
+        .iter()
+        .position(|r| *r == Some(main))
+        .unwrap_or(0);
+    spawns
+        .iter()
+        .zip(&spawn_regions)
+        .filter(|(_, r)| matches!(r, Some(r) if *r != main))
+        .map(|((i, position), _)| {
+            MapProblem::warning(
+                format!("lives[{}]", i),
+                format!(
+                    "{} can't be reached from lives[{}]",
+                    point_string(*position),
+                    spawns[first].0
+                ),
+            )
+        })
+        .collect()
+}
+
+// for `tanks validate-map`, false if the map has errors
+pub fn validate_map_file(path: &Path) -> bool {
+    let map = match read_map(path) {
+        Ok(map) => map,
+        Err(e) => {
+            println!("{}: error: {}", path.display(), e);
+            return false;
+        }
+    };
+    let problems = validate_map(&map);
+    for problem in &problems {
+        println!("{}: {}", path.display(), problem);
+    }
+    let errors = problems
+        .iter()
+        .filter(|p| p.severity == Severity::Error)
+        .count();
+    println!(
+        "{}: {} errors, {} warnings",
+        path.display(),
+        errors,
+        problems.len() - errors
+    );
+    if errors == 0 {
+        let merged = merge_walls(&map.walls);
+        println!(
+            "{}: {} walls, drawn as {} and checked as {}",
+            path.display(),
+            map.walls.len(),
+            merged.iter().map(|wall| wall.rects.len()).sum::<usize>(),
+            merged.len()
+        );
+    }
+    errors == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bullet_hits_wall, bullet_wall_hit, collide_circle_wall, collide_circle_walls,
+        wall_transform, WallPieces,
+    };
+
+    const SAMPLES: usize = 20000;
+    // faster than anything in the game, cannon rounds go 10 a frame
+    const MAX_SPEED: f32 = 12.0;
+
+    // the same samples on every run
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn range(&mut self, min: f32, max: f32) -> f32 {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            min + (max - min) * ((self.0 >> 40) as f32 / (1u64 << 24) as f32)
+        }
+
+        fn pick(&mut self, len: usize) -> usize {
+            (self.range(0.0, len as f32) as usize).min(len - 1)
+        }
+
+        fn velocity(&mut self) -> Vec2 {
+            Vec2::new(
+                self.range(-MAX_SPEED, MAX_SPEED),
+                self.range(-MAX_SPEED, MAX_SPEED),
+            )
+        }
+
+        // somewhere around a wall, in world space
+        fn near(&mut self, wall: &Transform, margin: f32) -> Vec3 {
+            let half = wall.scale.truncate() / 2.0 + margin;
+            let center = wall.translation.truncate();
+            Vec3::new(
+                self.range(center.x - half.x, center.x + half.x),
+                self.range(center.y - half.y, center.y + half.y),
+                0.0,
+            )
+        }
+    }
+
+    // the playable maps that ship with the game
+    fn shipped_maps() -> Vec<(String, Map)> {
+        let mut maps: Vec<(String, Map)> = fs::read_dir(MAPS_DIR)
+            .expect("no maps directory")
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().map_or(false, |e| e == "txt"))
+            .filter_map(|path| Some((path.display().to_string(), read_map(&path).ok()?)))
+            .filter(|(_, map)| {
+                let problems = validate_map(map);
+                !problems.iter().any(|p| p.severity == Severity::Error)
+            })
+            .collect();
+        maps.sort_by(|a, b| a.0.cmp(&b.0));
+        assert!(!maps.is_empty(), "no maps in {}", MAPS_DIR);
+        maps
+    }
+
+    fn origin(map: &Map) -> Vec3 {
+        let (min, max) = map.bounds().unwrap_or_default();
+        (max - min).as_vec2().extend(0.0)
+    }
+
+    // the walls of the map where the game would put them unmerged, and the
+    // merged walls as setup_map spawns them
+    fn place(map: &Map) -> (Vec<Transform>, Vec<WallPieces>) {
+        let origin = origin(map);
+        let walls = map
+            .walls
+            .iter()
+            .map(|w| wall_transform(w, origin))
+            .collect();
+        let merged = merge_walls(&map.walls)
+            .iter()
+            .map(|merged| WallPieces {
+                bounds: wall_transform(&merged.bounds(), origin),
+                pieces: merged
+                    .pieces
+                    .iter()
+                    .map(|i| (*i, wall_transform(&map.walls[*i], origin)))
+                    .collect(),
+            })
+            .collect();
+        (walls, merged)
+    }
+
+    // what the systems pass to the collision code, the index of the merged
+    // wall stands in for its entity
+    fn borrow(merged: &[WallPieces]) -> Vec<(usize, &WallPieces)> {
+        merged.iter().enumerate().collect()
+    }
+
+    #[test]
+    fn merging_shrinks_term_and_swam() {
+        for name in ["TERM", "SWAM"] {
+            let map = read_map(Path::new(&map_path(name))).unwrap();
+            let merged = merge_walls(&map.walls);
+            assert!(
+                merged.len() < map.walls.len(),
+                "{}: {} walls, {} merged",
+                name,
+                map.walls.len(),
+                merged.len()
+            );
+        }
+        // SWAM only has corners, nothing to draw as one
+        let map = read_map(Path::new(&map_path("TERM"))).unwrap();
+        let drawn: usize = merge_walls(&map.walls).iter().map(|m| m.rects.len()).sum();
+        assert!(drawn < map.walls.len(), "TERM: {} drawn", drawn);
+    }
+
+    #[test]
+    fn merged_walls_cover_exactly_their_pieces() {
+        for (path, map) in shipped_maps() {
+            let merged = merge_walls(&map.walls);
+            let mut pieces: Vec<usize> = merged.iter().flat_map(|m| m.pieces.clone()).collect();
+            pieces.sort_unstable();
+            assert_eq!(pieces, (0..map.walls.len()).collect::<Vec<_>>(), "{}", path);
+            for wall in &merged {
+                let pieces: Vec<&Vec<i32>> = wall.pieces.iter().map(|i| &map.walls[*i]).collect();
+                let kind = pieces[0][4];
+                assert!(wall.rects.iter().all(|r| r[4] == kind), "{}", path);
+                assert!(pieces.iter().all(|p| p[4] == kind), "{}", path);
+                // every cell between the edges is drawn if and only if it is
+                // in one of the pieces
+                let edges = |axis: usize| -> Vec<i32> {
+                    let mut edges: Vec<i32> = pieces
+                        .iter()
+                        .copied()
+                        .chain(&wall.rects)
+                        .flat_map(|r| [r[axis], r[axis + 2]])
+                        .collect();
+                    edges.sort_unstable();
+                    edges.dedup();
+                    edges
+                };
+                let (xs, ys) = (edges(0), edges(1));
+                let inside = |r: &Vec<i32>, x: &[i32], y: &[i32]| {
+                    r[0] <= x[0] && x[1] <= r[2] && r[1] <= y[0] && y[1] <= r[3]
+                };
+                for x in xs.windows(2) {
+                    for y in ys.windows(2) {
+                        assert_eq!(
+                            wall.rects.iter().any(|r| inside(r, x, y)),
+                            pieces.iter().any(|p| inside(p, x, y)),
+                            "{}: {:?} at {:?}",
+                            path,
+                            wall.rects,
+                            (x, y)
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    // everywhere around the walls, overlaps and seams included
+    #[test]
+    fn merging_keeps_tank_collisions() {
+        for (path, map) in shipped_maps() {
+            let (walls, merged) = place(&map);
+            let merged = borrow(&merged);
+            let mut rng = Lcg(1);
+            let mut collisions = 0;
+            for _ in 0..SAMPLES {
+                let wall = &walls[rng.pick(walls.len())];
+                let pos = rng.near(wall, TANK_RADIUS + MAX_SPEED);
+                let vel = rng.velocity();
+                // move_players before the walls were merged
+                let moved = walls.iter().fold((pos, vel), |(pos, vel), wall| {
+                    collide_circle_wall(pos, vel, TANK_RADIUS, wall)
+                });
+                assert_eq!(
+                    moved,
+                    collide_circle_walls(&merged, pos, vel, TANK_RADIUS),
+                    "{}: a tank at {} moving by {}",
+                    path,
+                    pos,
+                    vel
+                );
+                if moved != (pos, vel) {
+                    collisions += 1;
+                }
+            }
+            assert!(collisions > 0, "{}: no tank hit a wall", path);
+        }
+    }
+
+    #[test]
+    fn merging_keeps_bullet_collisions() {
+        for (path, map) in shipped_maps() {
+            let (walls, merged) = place(&map);
+            let merged = borrow(&merged);
+            let mut rng = Lcg(2);
+            let mut hits = 0;
+            for _ in 0..SAMPLES {
+                let wall = &walls[rng.pick(walls.len())];
+                let pos = rng.near(wall, MAX_SPEED);
+                let vel = rng.velocity();
+                // move_bullets before the walls were merged, the first wall
+                // hit makes the sparks
+                let first = walls.iter().position(|w| bullet_hits_wall(pos, vel, w));
+                let expected = first.map(|first| {
+                    merged
+                        .iter()
+                        .position(|(_, wall)| wall.pieces.iter().any(|(i, _)| *i == first))
+                        .unwrap()
+                });
+                assert_eq!(
+                    expected,
+                    bullet_wall_hit(&merged, pos, vel),
+                    "{}: a bullet at {} moving by {}",
+                    path,
+                    pos,
+                    vel
+                );
+                if first.is_some() {
+                    hits += 1;
+                }
+            }
+            assert!(hits > 0, "{}: no bullet hit a wall", path);
+        }
+    }
+}
@@ -0,0 +1,444 @@
+use bevy::prelude::*;
+
+use crate::map::{Hive, Map};
+
+// generated maps go by names like gen-maze-1234 or gen-rooms-99-hives, every
+// peer that gets the name builds the same walls from it
+const PREFIX: &str = "gen-";
+const HIVES_SUFFIX: &str = "-hives";
+const HIVE_COUNT: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MapStyle {
+    // corridors like MAZE
+    Maze,
+    // rooms and doors like OFFC
+    Rooms,
+    // mirrored both ways, fair for two or four players
+    Arena,
+}
+
+pub const MAP_STYLES: [MapStyle; 3] = [MapStyle::Maze, MapStyle::Rooms, MapStyle::Arena];
+
+impl MapStyle {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MapStyle::Maze => "maze",
+            MapStyle::Rooms => "rooms",
+            MapStyle::Arena => "arena",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        MAP_STYLES.iter().copied().find(|s| s.name() == name)
+    }
+}
+
+pub fn generated_name(style: MapStyle, seed: u64, hives: bool) -> String {
+    format!(
+        "{}{}-{}{}",
+        PREFIX,
+        style.name(),
+        seed,
+        if hives { HIVES_SUFFIX } else { "" }
+    )
+}
+
+pub fn parse_generated(name: &str) -> Option<(MapStyle, u64, bool)> {
+    let rest = name.strip_prefix(PREFIX)?;
+    let (rest, hives) = match rest.strip_suffix(HIVES_SUFFIX) {
+        Some(rest) => (rest, true),
+        None => (rest, false),
+    };
+    let (style, seed) = rest.split_once('-')?;
+    Some((MapStyle::from_name(style)?, seed.parse().ok()?, hives))
+}
+
+// the name a generated map is saved under, without the prefix: a file by
+// the generated name would never be read, load_map builds the map from the
+// seed instead
+pub fn editable_name(name: &str) -> String {
+    match parse_generated(name) {
+        Some(_) => name[PREFIX.len()..].to_string(),
+        None => name.to_string(),
+    }
+}
+
+// integers only from here on, so that every platform gets the same map
+struct MapRng(u64);
+
+impl MapRng {
+    // splitmix64
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // in [0, n)
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    // in [min, max]
+    fn range(&mut self, min: i32, max: i32) -> i32 {
+        min + self.below((max - min + 1) as usize) as i32
+    }
+
+    fn chance(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+
+    fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            items.swap(i, self.below(i + 1));
+        }
+    }
+}
+
+pub fn generate_map(style: MapStyle, seed: u64, hives: bool) -> Map {
+    let mut rng = MapRng(seed);
+    let (walls, mut spots) = match style {
+        MapStyle::Maze => maze(&mut rng),
+        MapStyle::Rooms => rooms(&mut rng),
+        MapStyle::Arena => arena(&mut rng),
+    };
+    // spawn points first, what is left over may get a hive
+    let count = spots.spawns.min(spots.positions.len());
+    let hive_positions = spots.positions.split_off(count);
+    let mut map = Map {
+        name: format!("{} {}", style.name(), seed),
+        walls,
+        hives: Vec::new(),
+        lives: spots.positions.iter().map(|p| vec![p.x, p.y, 0]).collect(),
+        flags: Vec::new(),
+        zones: Vec::new(),
+    };
+    if hives {
+        map.hives = hive_positions
+            .iter()
+            .take(HIVE_COUNT)
+            .map(|p| Hive::new(vec![p.x, p.y]))
+            .collect();
+    }
+    map
+}
+
+// free places to put things on, best ones for spawn points first
+struct Spots {
+    positions: Vec<IVec2>,
+    spawns: usize,
+}
+
+fn wall(min: IVec2, max: IVec2, kind: i32) -> Vec<i32> {
+    vec![min.x, min.y, max.x, max.y, kind]
+}
+
+// outer walls around [0, size], thickness inwards
+fn border(size: IVec2, thickness: i32) -> Vec<Vec<i32>> {
+    vec![
+        wall(IVec2::ZERO, IVec2::new(size.x, thickness), 1),
+        wall(IVec2::new(0, size.y - thickness), size, 1),
+        wall(IVec2::ZERO, IVec2::new(thickness, size.y), 1),
+        wall(IVec2::new(size.x - thickness, 0), size, 1),
+    ]
+}
+
+// a spanning tree over a grid, as the open passages between neighbors
+struct GridTree {
+    size: IVec2,
+    // passage to the right of / above each cell
+    right: Vec<bool>,
+    up: Vec<bool>,
+}
+
+impl GridTree {
+    fn index(&self, cell: IVec2) -> usize {
+        (cell.y * self.size.x + cell.x) as usize
+    }
+
+    // recursive backtracker, then some extra passages so there are loops
+    fn new(rng: &mut MapRng, size: IVec2, loops: usize) -> Self {
+        let count = (size.x * size.y) as usize;
+        let mut tree = Self {
+            size,
+            right: vec![false; count],
+            up: vec![false; count],
+        };
+        let mut visited = vec![false; count];
+        let start = IVec2::new(rng.below(size.x as usize) as i32, 0);
+        visited[tree.index(start)] = true;
+        let mut stack = vec![start];
+        while let Some(&cell) = stack.last() {
+            let mut next: Vec<IVec2> = [IVec2::X, -IVec2::X, IVec2::Y, -IVec2::Y]
+                .iter()
+                .map(|step| cell + *step)
+                .filter(|n| n.cmpge(IVec2::ZERO).all() && n.cmplt(size).all())
+                .filter(|n| !visited[tree.index(*n)])
+                .collect();
+            if next.is_empty() {
+                stack.pop();
+                continue;
+            }
+            rng.shuffle(&mut next);
+            let n = next[0];
+            tree.open(cell, n);
+            visited[tree.index(n)] = true;
+            stack.push(n);
+        }
+        for i in 0..count {
+            let cell = IVec2::new(i as i32 % size.x, i as i32 / size.x);
+            if cell.x + 1 < size.x && rng.chance(loops) {
+                tree.right[i] = true;
+            }
+            if cell.y + 1 < size.y && rng.chance(loops) {
+                tree.up[i] = true;
+            }
+        }
+        tree
+    }
+
+    fn open(&mut self, a: IVec2, b: IVec2) {
+        let low = a.min(b);
+        let i = self.index(low);
+        if a.x != b.x {
+            self.right[i] = true;
+        } else {
+            self.up[i] = true;
+        }
+    }
+}
+
+fn maze(rng: &mut MapRng) -> (Vec<Vec<i32>>, Spots) {
+    const CELL: i32 = 80;
+    const THICKNESS: i32 = 11;
+    let cells = IVec2::new(11, 8);
+    let tree = GridTree::new(rng, cells, 15);
+    let size = cells * CELL + IVec2::splat(THICKNESS);
+    let mut walls = border(size, THICKNESS);
+
+    // runs of closed edges along every grid line become one wall each
+    for y in 1..cells.y {
+        let mut run: Option<i32> = None;
+        for x in 0..=cells.x {
+            let closed = x < cells.x && !tree.up[tree.index(IVec2::new(x, y - 1))];
+            match (closed, run) {
+                (true, None) => run = Some(x),
+                (false, Some(start)) => {
+                    walls.push(wall(
+                        IVec2::new(start * CELL, y * CELL),
+                        IVec2::new(x * CELL + THICKNESS, y * CELL + THICKNESS),
+                        0,
+                    ));
+                    run = None;
+                }
+                _ => {}
+            }
+        }
+    }
+    for x in 1..cells.x {
+        let mut run: Option<i32> = None;
+        for y in 0..=cells.y {
+            let closed = y < cells.y && !tree.right[tree.index(IVec2::new(x - 1, y))];
+            match (closed, run) {
+                (true, None) => run = Some(y),
+                (false, Some(start)) => {
+                    walls.push(wall(
+                        IVec2::new(x * CELL, start * CELL),
+                        IVec2::new(x * CELL + THICKNESS, y * CELL + THICKNESS),
+                        0,
+                    ));
+                    run = None;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let center = |cell: IVec2| cell * CELL + IVec2::splat((CELL + THICKNESS) / 2);
+    // the corners, then the rest of the cells in any order
+    let last = cells - IVec2::ONE;
+    let corners = [
+        IVec2::ZERO,
+        last,
+        IVec2::new(last.x, 0),
+        IVec2::new(0, last.y),
+    ];
+    let mut others: Vec<IVec2> = (0..cells.x * cells.y)
+        .map(|i| IVec2::new(i % cells.x, i / cells.x))
+        .filter(|c| !corners.contains(c))
+        .collect();
+    rng.shuffle(&mut others);
+    let positions = corners.iter().chain(&others).map(|c| center(*c)).collect();
+    (
+        walls,
+        Spots {
+            positions,
+            spawns: 6,
+        },
+    )
+}
+
+fn rooms(rng: &mut MapRng) -> (Vec<Vec<i32>>, Spots) {
+    const THICKNESS: i32 = 31;
+    const DOOR: i32 = 70;
+    // furniture keeps this far from the walls of its room
+    const CLEARANCE: i32 = 50;
+    let cells = IVec2::new(rng.range(3, 5), rng.range(3, 4));
+    let tree = GridTree::new(rng, cells, 25);
+
+    // grid lines, rooms of different sizes
+    let mut xs = vec![0];
+    for _ in 0..cells.x {
+        let last = *xs.last().unwrap();
+        xs.push(last + rng.range(180, 300));
+    }
+    let mut ys = vec![0];
+    for _ in 0..cells.y {
+        let last = *ys.last().unwrap();
+        ys.push(last + rng.range(160, 260));
+    }
+    let size = IVec2::new(xs[cells.x as usize], ys[cells.y as usize]) + IVec2::splat(THICKNESS);
+    let mut walls = border(size, THICKNESS);
+
+    // a wall between two rooms, with a door in it if they are connected
+    let mut split = |walls: &mut Vec<Vec<i32>>, min: IVec2, max: IVec2, door: bool| {
+        let vertical = max.y - min.y > max.x - min.x;
+        let length = if vertical {
+            max.y - min.y
+        } else {
+            max.x - min.x
+        };
+        if !door {
+            walls.push(wall(min, max, 1));
+            return;
+        }
+        // clear of the walls crossing at either end
+        let offset = rng.range(THICKNESS + 10, length - THICKNESS - DOOR - 10);
+        let axis = if vertical { IVec2::Y } else { IVec2::X };
+        let across = IVec2::ONE - axis;
+        walls.push(wall(min, (min + axis * offset) * axis + max * across, 1));
+        walls.push(wall(min + axis * (offset + DOOR), max, 1));
+    };
+    for y in 1..cells.y {
+        for x in 0..cells.x {
+            let open = tree.up[tree.index(IVec2::new(x, y - 1))];
+            let min = IVec2::new(xs[x as usize], ys[y as usize]);
+            let max = IVec2::new(xs[x as usize + 1] + THICKNESS, ys[y as usize] + THICKNESS);
+            split(&mut walls, min, max, open);
+        }
+    }
+    for x in 1..cells.x {
+        for y in 0..cells.y {
+            let open = tree.right[tree.index(IVec2::new(x - 1, y))];
+            let min = IVec2::new(xs[x as usize], ys[y as usize]);
+            let max = IVec2::new(xs[x as usize] + THICKNESS, ys[y as usize + 1] + THICKNESS);
+            split(&mut walls, min, max, open);
+        }
+    }
+
+    let mut positions = Vec::new();
+    let mut centers = Vec::new();
+    for y in 0..cells.y as usize {
+        for x in 0..cells.x as usize {
+            let min = IVec2::new(xs[x] + THICKNESS, ys[y] + THICKNESS);
+            let max = IVec2::new(xs[x + 1], ys[y + 1]);
+            // a desk in the middle of some rooms, with room to drive around it
+            let space = max - min - IVec2::splat(2 * CLEARANCE);
+            if space.min_element() >= 40 && rng.chance(60) {
+                let desk = IVec2::new(rng.range(20, space.x.min(120)), rng.range(20, space.y / 2));
+                let corner = min + (max - min - desk) / 2;
+                walls.push(wall(corner, corner + desk, 0));
+            }
+            // spawn in a corner of the room, away from the desk
+            positions.push(min + IVec2::splat(30));
+            centers.push(IVec2::new(min.x + 30, max.y - 30));
+        }
+    }
+    rng.shuffle(&mut positions);
+    rng.shuffle(&mut centers);
+    let spawns = positions.len().min(8);
+    positions.extend(centers);
+    (walls, Spots { positions, spawns })
+}
+
+fn arena(rng: &mut MapRng) -> (Vec<Vec<i32>>, Spots) {
+    const THICKNESS: i32 = 21;
+    // between any two blocks, so every open spot connects to every other
+    const GAP: i32 = 50;
+    let size = IVec2::new(1000, 700);
+    let half = size / 2;
+    let mut walls = border(size, THICKNESS);
+
+    // spawn corners and the middle stay open
+    let spawn = IVec2::splat(THICKNESS + 40);
+    let keep_clear = [
+        (IVec2::ZERO, spawn + IVec2::splat(GAP)),
+        (half - IVec2::splat(60), half),
+    ];
+    let overlaps = |min: IVec2, max: IVec2, other: &(IVec2, IVec2)| {
+        min.x < other.1.x && other.0.x < max.x && min.y < other.1.y && other.0.y < max.y
+    };
+
+    // blocks in the bottom left quarter, mirrored into the others
+    let mut blocks: Vec<(IVec2, IVec2)> = Vec::new();
+    for _ in 0..300 {
+        if blocks.len() >= 7 {
+            break;
+        }
+        let block = if rng.chance(50) {
+            IVec2::new(rng.range(20, 40), rng.range(60, 180))
+        } else {
+            IVec2::new(rng.range(60, 180), rng.range(20, 40))
+        };
+        let low = IVec2::splat(THICKNESS + GAP);
+        // half a gap from the mirror lines, the copy is on the other side
+        let high = half - IVec2::splat(GAP / 2) - block;
+        if high.cmplt(low).any() {
+            continue;
+        }
+        let min = IVec2::new(rng.range(low.x, high.x), rng.range(low.y, high.y));
+        let max = min + block;
+        let (grown_min, grown_max) = (min - IVec2::splat(GAP), max + IVec2::splat(GAP));
+        if blocks
+            .iter()
+            .chain(&keep_clear)
+            .any(|other| overlaps(grown_min, grown_max, other))
+        {
+            continue;
+        }
+        blocks.push((min, max));
+    }
+    let kind = rng.below(2) as i32;
+    for (min, max) in &blocks {
+        let mirror_x = |v: IVec2| IVec2::new(size.x - v.x, v.y);
+        let mirror_y = |v: IVec2| IVec2::new(v.x, size.y - v.y);
+        walls.push(wall(*min, *max, kind));
+        let (a, b) = (mirror_x(*max), mirror_x(*min));
+        walls.push(wall(IVec2::new(a.x, b.y), IVec2::new(b.x, a.y), kind));
+        let (a, b) = (mirror_y(*max), mirror_y(*min));
+        walls.push(wall(IVec2::new(b.x, a.y), IVec2::new(a.x, b.y), kind));
+        walls.push(wall(size - *max, size - *min, kind));
+    }
+
+    // opposite corners first, so two players face each other
+    let positions = vec![
+        spawn,
+        size - spawn,
+        IVec2::new(size.x - spawn.x, spawn.y),
+        IVec2::new(spawn.x, size.y - spawn.y),
+        IVec2::new(half.x, spawn.y),
+        IVec2::new(half.x, size.y - spawn.y),
+        IVec2::new(spawn.x, half.y),
+        IVec2::new(size.x - spawn.x, half.y),
+    ];
+    (
+        walls,
+        Spots {
+            positions,
+            spawns: 4,
+        },
+    )
+}
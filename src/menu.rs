@@ -0,0 +1,647 @@
+use std::fs;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bevy::app::AppExit;
+use bevy::prelude::*;
+use ggrs::P2PSession;
+
+use crate::controls::ControlsMenu;
+use crate::koth::{add_hill_scores, HillScore};
+use crate::lobby::{LanClient, LanHost, PendingLobby};
+use crate::mapgen::{generated_name, MAP_STYLES};
+use crate::playlist::{
+    list_playlists, load_playlist, GameMode, Rotation, GAME_MODES, INTERMISSION,
+};
+use crate::sound::AudioSettings;
+use crate::survival::Survival;
+use crate::teams::{team_color, Teams, MAX_TEAMS};
+use crate::{
+    insert_session, session_running, start_session, GGRSConfig, GameState, MatchClock, MatchConfig,
+    Player, Score, SessionConfig, FPS,
+};
+
+const TIME_LIMITS: [u32; 5] = [60, 120, 180, 300, 600];
+const MAX_PLAYERS: usize = 4;
+// how long the round over banner stays up before the results
+const ROUND_END_TIME: Duration = Duration::from_secs(3);
+
+pub struct MenuPlugin;
+
+impl Plugin for MenuPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(MenuState {
+            selected: 0,
+            lan_players: 2,
+            local_players: 1,
+            maps: list_maps(),
+            playlists: list_playlists(),
+            map_style: 0,
+            status: String::new(),
+        })
+        .insert_resource(RoundEndTimer(Timer::new(ROUND_END_TIME, false)))
+        .add_system_set(
+            SystemSet::on_enter(GameState::MainMenu)
+                .with_system(spawn_screen)
+                .with_system(refresh_maps),
+        )
+        .add_system_set(SystemSet::on_update(GameState::MainMenu).with_system(main_menu))
+        .add_system_set(SystemSet::on_exit(GameState::MainMenu).with_system(despawn_screen))
+        .add_system_set(SystemSet::on_enter(GameState::Lobby).with_system(spawn_screen))
+        .add_system_set(SystemSet::on_update(GameState::Lobby).with_system(poll_lobby))
+        .add_system_set(SystemSet::on_exit(GameState::Lobby).with_system(despawn_screen))
+        .add_system_set(SystemSet::on_enter(GameState::Loading).with_system(spawn_screen))
+        .add_system_set(SystemSet::on_update(GameState::Loading).with_system(wait_for_peers))
+        .add_system_set(SystemSet::on_exit(GameState::Loading).with_system(despawn_screen))
+        .add_system_set(SystemSet::on_update(GameState::InGame).with_system(end_round))
+        .add_system_set(SystemSet::on_enter(GameState::RoundEnd).with_system(spawn_screen))
+        .add_system_set(SystemSet::on_update(GameState::RoundEnd).with_system(round_end))
+        .add_system_set(SystemSet::on_exit(GameState::RoundEnd).with_system(despawn_screen))
+        .add_system_set(SystemSet::on_enter(GameState::Results).with_system(spawn_screen))
+        .add_system_set(SystemSet::on_update(GameState::Results).with_system(results))
+        .add_system_set(SystemSet::on_exit(GameState::Results).with_system(despawn_screen));
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum MenuItem {
+    HostLan,
+    JoinLan,
+    LocalGame,
+    Map,
+    GenerateMap,
+    Playlist,
+    EditMap,
+    TimeLimit,
+    Mode,
+    Teams,
+    FriendlyFire,
+    FogOfWar,
+    MasterVolume,
+    MusicVolume,
+    EffectsVolume,
+    Quit,
+}
+
+const MENU_ITEMS: [MenuItem; 16] = [
+    MenuItem::HostLan,
+    MenuItem::JoinLan,
+    MenuItem::LocalGame,
+    MenuItem::Map,
+    MenuItem::GenerateMap,
+    MenuItem::Playlist,
+    MenuItem::EditMap,
+    MenuItem::TimeLimit,
+    MenuItem::Mode,
+    MenuItem::Teams,
+    MenuItem::FriendlyFire,
+    MenuItem::FogOfWar,
+    MenuItem::MasterVolume,
+    MenuItem::MusicVolume,
+    MenuItem::EffectsVolume,
+    MenuItem::Quit,
+];
+const VOLUME_STEP: f32 = 0.1;
+
+pub struct MenuState {
+    selected: usize,
+    lan_players: usize,
+    // split screen players for a local game
+    local_players: usize,
+    maps: Vec<String>,
+    playlists: Vec<String>,
+    // index in MAP_STYLES
+    map_style: usize,
+    // why the last attempt to start a game failed
+    pub status: String,
+}
+
+struct RoundEndTimer(Timer);
+
+// every screen is a single text over a dark background
+#[derive(Component)]
+struct MenuScreen;
+
+#[derive(Component)]
+struct MenuText;
+
+// the names of the maps in assets/maps
+fn list_maps() -> Vec<String> {
+    let mut maps: Vec<String> = fs::read_dir("assets/maps")
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| {
+                    let path = entry.path();
+                    if path.extension()? != "txt" {
+                        return None;
+                    }
+                    Some(path.file_stem()?.to_str()?.to_string())
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    maps.sort();
+    maps
+}
+
+// the editor may have saved a new one
+fn refresh_maps(mut menu: ResMut<MenuState>) {
+    menu.maps = list_maps();
+    menu.playlists = list_playlists();
+}
+
+fn spawn_screen(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    state: Res<State<GameState>>,
+) {
+    // the round end banner leaves the match visible
+    let background = if *state.current() == GameState::RoundEnd {
+        Color::NONE
+    } else {
+        Color::rgba(0.0, 0.0, 0.0, 0.8)
+    };
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                ..default()
+            },
+            color: background.into(),
+            ..default()
+        })
+        .insert(MenuScreen)
+        .with_children(|parent| {
+            parent
+                .spawn_bundle(TextBundle::from_section(
+                    "",
+                    TextStyle {
+                        font: asset_server.load("fonts/DejaVuSans.ttf"),
+                        font_size: 24.0,
+                        color: Color::WHITE,
+                    },
+                ))
+                .insert(MenuText);
+        });
+}
+
+fn despawn_screen(mut commands: Commands, screen_query: Query<Entity, With<MenuScreen>>) {
+    for entity in &screen_query {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+fn set_menu_text(text_query: &mut Query<&mut Text, With<MenuText>>, value: String) {
+    for mut text in text_query.iter_mut() {
+        if text.sections[0].value != value {
+            text.sections[0].value = value.clone();
+        }
+    }
+}
+
+fn format_time(seconds: u32) -> String {
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+fn volume_label(name: &str, volume: f32) -> String {
+    format!("{}: {:.0}%", name, volume * 100.0)
+}
+
+// up and down select, left and right change a value, enter starts
+#[allow(clippy::too_many_arguments)]
+fn main_menu(
+    mut commands: Commands,
+    keyboard_input: Res<Input<KeyCode>>,
+    controls_menu: Res<ControlsMenu>,
+    session_config: Res<SessionConfig>,
+    mut menu: ResMut<MenuState>,
+    mut config: ResMut<MatchConfig>,
+    mut audio_settings: ResMut<AudioSettings>,
+    mut state: ResMut<State<GameState>>,
+    mut exit: EventWriter<AppExit>,
+    mut text_query: Query<&mut Text, With<MenuText>>,
+) {
+    if controls_menu.open {
+        return;
+    }
+    let count = MENU_ITEMS.len();
+    if keyboard_input.just_pressed(KeyCode::Down) {
+        menu.selected = (menu.selected + 1) % count;
+    }
+    if keyboard_input.just_pressed(KeyCode::Up) {
+        menu.selected = (menu.selected + count - 1) % count;
+    }
+    let mut step: i32 = 0;
+    if keyboard_input.just_pressed(KeyCode::Right) {
+        step += 1;
+    }
+    if keyboard_input.just_pressed(KeyCode::Left) {
+        step -= 1;
+    }
+    let activate = keyboard_input.just_pressed(KeyCode::Return);
+
+    let item = MENU_ITEMS[menu.selected];
+    match item {
+        MenuItem::HostLan => {
+            menu.lan_players =
+                (menu.lan_players as i32 + step).clamp(2, MAX_PLAYERS as i32) as usize;
+        }
+        MenuItem::LocalGame => {
+            menu.local_players =
+                (menu.local_players as i32 + step).clamp(1, MAX_PLAYERS as i32) as usize;
+        }
+        MenuItem::Map if step != 0 && !menu.maps.is_empty() => {
+            let len = menu.maps.len() as i32;
+            let current = menu.maps.iter().position(|m| *m == config.map).unwrap_or(0) as i32;
+            config.map = menu.maps[(current + step).rem_euclid(len) as usize].clone();
+            config.playlist = None;
+        }
+        MenuItem::GenerateMap => {
+            let len = MAP_STYLES.len() as i32;
+            menu.map_style = (menu.map_style as i32 + step).rem_euclid(len) as usize;
+        }
+        // none comes before the first one
+        MenuItem::Playlist if step != 0 => {
+            let len = menu.playlists.len() as i32 + 1;
+            let current = config.playlist.as_ref().map_or(0, |p| {
+                menu.playlists
+                    .iter()
+                    .position(|name| *name == p.name)
+                    .map_or(0, |i| i as i32 + 1)
+            });
+            let next = (current + step).rem_euclid(len) as usize;
+            config.playlist = None;
+            if next > 0 {
+                match load_playlist(&menu.playlists[next - 1]) {
+                    Ok(playlist) => {
+                        config.map = playlist.entries[0].map.clone();
+                        config.playlist = Some(playlist);
+                    }
+                    Err(e) => menu.status = format!("can't load the playlist: {}", e),
+                }
+            }
+        }
+        MenuItem::TimeLimit if step != 0 => {
+            let current = TIME_LIMITS
+                .iter()
+                .position(|t| *t >= config.time_limit)
+                .unwrap_or(TIME_LIMITS.len() - 1) as i32;
+            let index = (current + step).clamp(0, TIME_LIMITS.len() as i32 - 1);
+            config.time_limit = TIME_LIMITS[index as usize];
+        }
+        MenuItem::Mode if step != 0 => {
+            let len = GAME_MODES.len() as i32;
+            let current = GAME_MODES
+                .iter()
+                .position(|m| *m == config.mode)
+                .unwrap_or(0) as i32;
+            config.mode = GAME_MODES[(current + step).rem_euclid(len) as usize];
+        }
+        MenuItem::Teams => {
+            config.teams = (config.teams as i32 + step).clamp(2, MAX_TEAMS as i32) as usize;
+        }
+        MenuItem::FriendlyFire if step != 0 || activate => {
+            config.friendly_fire = !config.friendly_fire;
+        }
+        MenuItem::FogOfWar if step != 0 || activate => {
+            config.fog_of_war = !config.fog_of_war;
+        }
+        MenuItem::MasterVolume | MenuItem::MusicVolume | MenuItem::EffectsVolume if step != 0 => {
+            let volume = match item {
+                MenuItem::MasterVolume => &mut audio_settings.master,
+                MenuItem::MusicVolume => &mut audio_settings.music,
+                _ => &mut audio_settings.effects,
+            };
+            *volume = (*volume + step as f32 * VOLUME_STEP).clamp(0.0, 1.0);
+            audio_settings.save();
+        }
+        _ => {}
+    }
+
+    if activate {
+        match item {
+            MenuItem::HostLan => {
+                match LanHost::new(session_config.local_port, menu.lan_players, &config) {
+                    Ok(host) => {
+                        commands.insert_resource(PendingLobby(Some(Box::new(host))));
+                        state.set(GameState::Lobby).unwrap();
+                    }
+                    Err(e) => menu.status = format!("can't host: {}", e),
+                }
+            }
+            MenuItem::JoinLan => match LanClient::new(session_config.local_port) {
+                Ok(client) => {
+                    commands.insert_resource(PendingLobby(Some(Box::new(client))));
+                    state.set(GameState::Lobby).unwrap();
+                }
+                Err(e) => menu.status = format!("can't join: {}", e),
+            },
+            MenuItem::LocalGame => {
+                let players = vec!["localhost".to_string(); menu.local_players];
+                match start_session(&players, &session_config) {
+                    Ok(sess) => {
+                        insert_session(&mut commands, sess, &config);
+                        state.set(GameState::Loading).unwrap();
+                    }
+                    Err(e) => menu.status = format!("can't start: {}", e),
+                }
+            }
+            MenuItem::GenerateMap => {
+                // only has to differ from the last one, the peers get the name
+                let seed = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_micros() as u64 % 1_000_000);
+                config.map = generated_name(MAP_STYLES[menu.map_style], seed, false);
+                config.playlist = None;
+            }
+            MenuItem::EditMap => state.set(GameState::Editor).unwrap(),
+            MenuItem::Quit => exit.send(AppExit),
+            _ => {}
+        }
+    }
+
+    let mut value = "TANKS!\n\n".to_string();
+    for (i, item) in MENU_ITEMS.iter().enumerate() {
+        let label = match item {
+            MenuItem::HostLan => format!("host a lan game for {} players", menu.lan_players),
+            MenuItem::JoinLan => "join a lan game".to_string(),
+            MenuItem::LocalGame => format!("local game for {} players", menu.local_players),
+            MenuItem::Map => format!("map: {}", config.map),
+            MenuItem::GenerateMap => {
+                format!("generate a map: {}", MAP_STYLES[menu.map_style].name())
+            }
+            MenuItem::Playlist => format!(
+                "playlist: {}",
+                config.playlist.as_ref().map_or("none", |p| p.name.as_str())
+            ),
+            MenuItem::EditMap => "edit the map".to_string(),
+            MenuItem::TimeLimit => format!("time limit: {}", format_time(config.time_limit)),
+            MenuItem::Mode => format!("mode: {}", config.mode.name().replace('_', " ")),
+            MenuItem::Teams => format!("teams: {}", config.teams),
+            MenuItem::FriendlyFire => format!(
+                "friendly fire: {}",
+                if config.friendly_fire { "on" } else { "off" }
+            ),
+            MenuItem::FogOfWar => format!(
+                "fog of war: {}",
+                if config.fog_of_war { "on" } else { "off" }
+            ),
+            MenuItem::MasterVolume => volume_label("volume", audio_settings.master),
+            MenuItem::MusicVolume => volume_label("music", audio_settings.music),
+            MenuItem::EffectsVolume => volume_label("effects", audio_settings.effects),
+            MenuItem::Quit => "quit".to_string(),
+        };
+        let marker = if i == menu.selected { ">" } else { " " };
+        value += &format!("{} {}\n", marker, label);
+    }
+    value += "\nup/down to select, left/right to change, enter to start\nF1 for the controls";
+    if !menu.status.is_empty() {
+        value += &format!("\n\n{}", menu.status);
+    }
+    set_menu_text(&mut text_query, value);
+}
+
+fn poll_lobby(
+    mut commands: Commands,
+    keyboard_input: Res<Input<KeyCode>>,
+    session_config: Res<SessionConfig>,
+    mut pending: ResMut<PendingLobby>,
+    mut menu: ResMut<MenuState>,
+    mut config: ResMut<MatchConfig>,
+    mut state: ResMut<State<GameState>>,
+    mut text_query: Query<&mut Text, With<MenuText>>,
+) {
+    let lobby = match pending.0.as_mut() {
+        Some(lobby) => lobby,
+        None => return,
+    };
+    if keyboard_input.just_pressed(KeyCode::Escape) {
+        pending.0 = None;
+        menu.status.clear();
+        state.set(GameState::MainMenu).unwrap();
+        return;
+    }
+
+    match lobby.poll() {
+        Ok(Some(outcome)) => {
+            // the session binds the port of the lobby
+            pending.0 = None;
+            match start_session(&outcome.players, &session_config) {
+                Ok(sess) => {
+                    config.map = outcome.map;
+                    config.playlist = outcome.playlist;
+                    if let Some(settings) = outcome.settings {
+                        settings.apply(&mut config);
+                    }
+                    insert_session(&mut commands, sess, &config);
+                    menu.status.clear();
+                    state.set(GameState::Loading).unwrap();
+                }
+                Err(e) => {
+                    menu.status = format!("can't start: {}", e);
+                    state.set(GameState::MainMenu).unwrap();
+                }
+            }
+        }
+        Ok(None) => {
+            let value = format!("{}\n\nescape to cancel", lobby.status());
+            set_menu_text(&mut text_query, value);
+        }
+        Err(e) => {
+            pending.0 = None;
+            menu.status = format!("lobby failed: {}", e);
+            state.set(GameState::MainMenu).unwrap();
+        }
+    }
+}
+
+fn wait_for_peers(
+    keyboard_input: Res<Input<KeyCode>>,
+    config: Res<MatchConfig>,
+    sess: Option<Res<P2PSession<GGRSConfig>>>,
+    mut state: ResMut<State<GameState>>,
+    mut text_query: Query<&mut Text, With<MenuText>>,
+) {
+    if session_running(sess.as_deref()) {
+        state.set(GameState::InGame).unwrap();
+        return;
+    }
+    if keyboard_input.just_pressed(KeyCode::Escape) {
+        state.set(GameState::MainMenu).unwrap();
+        return;
+    }
+    let value = format!(
+        "map {}\n\nwaiting for the other players...\n\nescape to cancel",
+        config.map
+    );
+    set_menu_text(&mut text_query, value);
+}
+
+// escape leaves the match for good
+fn end_round(
+    keyboard_input: Res<Input<KeyCode>>,
+    controls_menu: Res<ControlsMenu>,
+    clock_query: Query<&MatchClock>,
+    mut timer: ResMut<RoundEndTimer>,
+    mut state: ResMut<State<GameState>>,
+) {
+    if !controls_menu.open && keyboard_input.just_pressed(KeyCode::Escape) {
+        state.set(GameState::MainMenu).unwrap();
+    } else if clock_query.iter().any(|c| c.expired()) {
+        timer.0.reset();
+        state.set(GameState::RoundEnd).unwrap();
+    }
+}
+
+// the session keeps running meanwhile, so that the last frames get confirmed
+fn round_end(
+    time: Res<Time>,
+    rotation: Option<Res<Rotation>>,
+    clock_query: Query<&MatchClock>,
+    survival_query: Query<&Survival>,
+    mut timer: ResMut<RoundEndTimer>,
+    mut state: ResMut<State<GameState>>,
+    mut text_query: Query<&mut Text, With<MenuText>>,
+) {
+    let banner = match (
+        rotation,
+        clock_query.get_single(),
+        survival_query.get_single(),
+    ) {
+        (Some(rotation), Ok(clock), Ok(survival)) if rotation.round(clock.round).mode.is_coop() => {
+            format!("game over, wave {}", survival.wave)
+        }
+        _ => "round over".to_string(),
+    };
+    set_menu_text(&mut text_query, banner);
+    if timer.0.tick(time.delta()).finished() {
+        state.set(GameState::Results).unwrap();
+    }
+}
+
+// with a playlist the next round starts by itself, escape leaves
+#[allow(clippy::too_many_arguments)]
+fn results(
+    keyboard_input: Res<Input<KeyCode>>,
+    rotation: Option<Res<Rotation>>,
+    clock_query: Query<&MatchClock>,
+    teams: Res<Teams>,
+    player_query: Query<(&Player, &Score)>,
+    hill_query: Query<&HillScore>,
+    survival_query: Query<&Survival>,
+    mut state: ResMut<State<GameState>>,
+    mut text_query: Query<&mut Text, With<MenuText>>,
+) {
+    let next = match (&rotation, clock_query.get_single()) {
+        (Some(rotation), Ok(clock)) if rotation.advances => Some((rotation, clock)),
+        _ => None,
+    };
+    if keyboard_input.just_pressed(KeyCode::Escape)
+        || (next.is_none() && keyboard_input.just_pressed(KeyCode::Return))
+    {
+        state.set(GameState::MainMenu).unwrap();
+        return;
+    }
+    if let Some((_, clock)) = next {
+        if !clock.expired() {
+            state.set(GameState::InGame).unwrap();
+            return;
+        }
+    }
+    let footer = match next {
+        Some((rotation, clock)) => {
+            let round = rotation.round(clock.round + 1);
+            let seconds = (clock.duration + INTERMISSION).saturating_sub(clock.frame) as usize;
+            format!(
+                "\nnext: {} {} in {} s, scores {}\nescape to leave",
+                rotation.map(clock.round + 1),
+                round.mode.name().replace('_', " "),
+                (seconds + FPS - 1) / FPS,
+                if round.reset_scores {
+                    "reset"
+                } else {
+                    "carry over"
+                }
+            )
+        }
+        None => "\nenter to go back to the menu".to_string(),
+    };
+
+    let mode = match (&rotation, clock_query.get_single()) {
+        (Some(rotation), Ok(clock)) => rotation.round(clock.round).mode,
+        _ => GameMode::Deathmatch,
+    };
+    let mut scores: Vec<(&Player, &Score)> = player_query.iter().collect();
+    scores.sort_by_key(|(p, s)| (-mode.team_score(s), -s.kills, s.deaths, p.handle));
+    let mut team_scores: Vec<(usize, i32)> = if mode.has_teams() {
+        let points = scores.iter().map(|(p, s)| (p.handle, mode.team_score(s)));
+        let mut points = teams.scores(points);
+        if mode.has_zones() {
+            add_hill_scores(&mut points, &hill_query);
+        }
+        points.into_iter().enumerate().collect()
+    } else {
+        Vec::new()
+    };
+    team_scores.sort_by_key(|(team, score)| (-score, *team));
+    for mut text in &mut text_query {
+        if text.sections.len() > 1 {
+            let last = text.sections.len() - 1;
+            if text.sections[last].value != footer {
+                text.sections[last].value = footer.clone();
+            }
+            continue;
+        }
+        let style = text.sections[0].style.clone();
+        text.sections[0].value = match survival_query.get_single() {
+            Ok(survival) if mode.is_coop() => format!("results, wave {}\n\n", survival.wave),
+            _ => "results\n\n".to_string(),
+        };
+        for (team, score) in &team_scores {
+            text.sections.push(TextSection {
+                value: format!("team {}  {}\n", team + 1, score),
+                style: TextStyle {
+                    color: team_color(*team),
+                    ..style.clone()
+                },
+            });
+        }
+        if !team_scores.is_empty() {
+            text.sections.push(TextSection {
+                value: "\n".to_string(),
+                style: style.clone(),
+            });
+        }
+        for (player, score) in &scores {
+            text.sections.push(TextSection {
+                value: if mode.has_flags() {
+                    format!(
+                        "P{}  captures {}  kills {}  deaths {}\n",
+                        player.handle + 1,
+                        score.captures,
+                        score.kills,
+                        score.deaths
+                    )
+                } else {
+                    format!(
+                        "P{}  kills {}  deaths {}\n",
+                        player.handle + 1,
+                        score.kills,
+                        score.deaths
+                    )
+                },
+                style: TextStyle {
+                    color: teams.color(player.handle),
+                    ..style.clone()
+                },
+            });
+        }
+        text.sections.push(TextSection {
+            value: footer.clone(),
+            style,
+        });
+    }
+}
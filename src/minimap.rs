@@ -0,0 +1,247 @@
+use bevy::core_pipeline::clear_color::ClearColorConfig;
+use bevy::prelude::*;
+use bevy::render::camera::{ScalingMode, Viewport};
+use bevy::render::view::RenderLayers;
+
+use crate::controls::ControlsMenu;
+use crate::teams::Teams;
+use crate::{LocalPlayers, MapBounds, MapLayer, MatchConfig, MatchEntity, Player, Wall};
+
+const MINIMAP_LAYER: u8 = 1;
+// fraction of the window height
+const MINIMAP_HEIGHT: f32 = 0.25;
+const MINIMAP_MAX_WIDTH: f32 = 0.35;
+const MINIMAP_MARGIN: u32 = 10;
+// in minimap pixels, whatever the map size
+const MARKER_SIZE: f32 = 6.0;
+// with fog of war, other players show up only this close to a local one
+const FOG_RADIUS: f32 = 300.0;
+
+pub struct MinimapPlugin;
+
+impl Plugin for MinimapPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Minimap { visible: true })
+            .add_system(spawn_minimap_camera)
+            .add_system(fit_minimap_to_map)
+            .add_system(spawn_minimap_walls)
+            .add_system(spawn_minimap_markers)
+            .add_system(toggle_minimap)
+            .add_system(update_minimap_viewport)
+            .add_system_to_stage(CoreStage::PostUpdate, update_minimap_markers);
+    }
+}
+
+pub struct Minimap {
+    pub visible: bool,
+}
+
+#[derive(Component)]
+struct MinimapCamera;
+
+#[derive(Component)]
+struct MinimapBackground;
+
+#[derive(Component)]
+struct MinimapMarker {
+    handle: usize,
+}
+
+fn spawn_minimap_camera(mut commands: Commands, bounds: Option<Res<MapBounds>>) {
+    let bounds = match bounds {
+        Some(bounds) if bounds.is_added() => bounds,
+        _ => return,
+    };
+    let center = (bounds.min + bounds.max) / 2.0;
+    let size = bounds.max - bounds.min;
+
+    let mut camera = Camera2dBundle::default();
+    camera.projection.scaling_mode = ScalingMode::WindowSize;
+    camera.transform = Transform::from_xyz(center.x, center.y, 100.0);
+    // above the player cameras, below the ui
+    camera.camera.priority = 50;
+    // clearing would wipe the whole window, not just the viewport
+    camera.camera_2d.clear_color = ClearColorConfig::None;
+    commands
+        .spawn_bundle(camera)
+        .insert(RenderLayers::layer(MINIMAP_LAYER))
+        .insert(UiCameraConfig { show_ui: false })
+        .insert(MinimapCamera)
+        .insert(MatchEntity);
+
+    // background
+    commands
+        .spawn_bundle(SpriteBundle {
+            transform: Transform {
+                translation: center.extend(-10.0),
+                scale: size.extend(1.0),
+                ..default()
+            },
+            sprite: Sprite {
+                color: Color::rgba(0.05, 0.05, 0.05, 0.85),
+                ..default()
+            },
+            ..default()
+        })
+        .insert(RenderLayers::layer(MINIMAP_LAYER))
+        .insert(MinimapBackground)
+        .insert(MatchEntity);
+}
+
+// the next map of a playlist
+fn fit_minimap_to_map(
+    bounds: Option<Res<MapBounds>>,
+    mut camera_query: Query<&mut Transform, With<MinimapCamera>>,
+    mut background_query: Query<&mut Transform, (With<MinimapBackground>, Without<MinimapCamera>)>,
+) {
+    let bounds = match bounds {
+        Some(bounds) if bounds.is_changed() && !bounds.is_added() => bounds,
+        _ => return,
+    };
+    let center = (bounds.min + bounds.max) / 2.0;
+    for mut transform in &mut camera_query {
+        transform.translation = center.extend(transform.translation.z);
+    }
+    for mut transform in &mut background_query {
+        transform.translation = center.extend(transform.translation.z);
+        transform.scale = (bounds.max - bounds.min).extend(1.0);
+    }
+}
+
+// they follow the visibility of their wall, see show_round_map
+fn spawn_minimap_walls(
+    mut commands: Commands,
+    wall_query: Query<(&Transform, &Sprite, &Visibility, &MapLayer), Added<Wall>>,
+) {
+    for (transform, sprite, visibility, layer) in &wall_query {
+        commands
+            .spawn_bundle(SpriteBundle {
+                transform: *transform,
+                sprite: Sprite {
+                    color: sprite.color,
+                    ..default()
+                },
+                visibility: visibility.clone(),
+                ..default()
+            })
+            .insert(RenderLayers::layer(MINIMAP_LAYER))
+            .insert(MapLayer(layer.0))
+            .insert(MatchEntity);
+    }
+}
+
+fn spawn_minimap_markers(
+    mut commands: Commands,
+    teams: Res<Teams>,
+    player_query: Query<&Player, Added<Player>>,
+) {
+    for player in &player_query {
+        commands
+            .spawn_bundle(SpriteBundle {
+                sprite: Sprite {
+                    color: teams.color(player.handle),
+                    ..default()
+                },
+                ..default()
+            })
+            .insert(RenderLayers::layer(MINIMAP_LAYER))
+            .insert(MinimapMarker {
+                handle: player.handle,
+            })
+            .insert(MatchEntity);
+    }
+}
+
+fn toggle_minimap(
+    keyboard_input: Res<Input<KeyCode>>,
+    menu: Res<ControlsMenu>,
+    mut minimap: ResMut<Minimap>,
+) {
+    if !menu.open && keyboard_input.just_pressed(KeyCode::M) {
+        minimap.visible = !minimap.visible;
+    }
+}
+
+// top right corner, keeping the aspect ratio of the map
+fn update_minimap_viewport(
+    windows: Res<Windows>,
+    minimap: Res<Minimap>,
+    bounds: Option<Res<MapBounds>>,
+    mut camera_query: Query<(&mut Camera, &mut OrthographicProjection), With<MinimapCamera>>,
+) {
+    let (window, bounds) = match (windows.get_primary(), bounds) {
+        (Some(window), Some(bounds)) => (window, bounds),
+        _ => return,
+    };
+    let map_size = bounds.max - bounds.min;
+    let window_size = Vec2::new(
+        window.physical_width() as f32,
+        window.physical_height() as f32,
+    );
+    let mut size = Vec2::new(
+        window_size.y * MINIMAP_HEIGHT * map_size.x / map_size.y,
+        window_size.y * MINIMAP_HEIGHT,
+    );
+    if size.x > window_size.x * MINIMAP_MAX_WIDTH {
+        size *= window_size.x * MINIMAP_MAX_WIDTH / size.x;
+    }
+    let size = size.as_uvec2();
+    let margin = MINIMAP_MARGIN.min(window.physical_width().saturating_sub(size.x));
+    let position = UVec2::new(
+        window.physical_width().saturating_sub(size.x + margin),
+        margin,
+    );
+    // the projection works in logical pixels
+    let scale = map_size.x / (size.x as f32 / window.scale_factor() as f32);
+
+    for (mut camera, mut projection) in &mut camera_query {
+        camera.is_active = minimap.visible && size.x > 0 && size.y > 0;
+        let up_to_date = camera.viewport.as_ref().map_or(false, |v| {
+            v.physical_position == position && v.physical_size == size
+        });
+        if !up_to_date && size.x > 0 && size.y > 0 {
+            camera.viewport = Some(Viewport {
+                physical_position: position,
+                physical_size: size,
+                ..default()
+            });
+        }
+        if projection.scale != scale {
+            projection.scale = scale;
+        }
+    }
+}
+
+fn update_minimap_markers(
+    config: Res<MatchConfig>,
+    local_players: Res<LocalPlayers>,
+    player_query: Query<(&Player, &Transform), Without<MinimapMarker>>,
+    camera_query: Query<&OrthographicProjection, With<MinimapCamera>>,
+    mut marker_query: Query<(&MinimapMarker, &mut Transform, &mut Visibility)>,
+) {
+    let scale = match camera_query.get_single() {
+        Ok(projection) => projection.scale,
+        Err(_) => return,
+    };
+    let local_positions: Vec<Vec3> = player_query
+        .iter()
+        .filter(|(p, _)| local_players.0.contains(&p.handle))
+        .map(|(_, t)| t.translation)
+        .collect();
+
+    for (marker, mut transform, mut visibility) in &mut marker_query {
+        let position = match player_query.iter().find(|(p, _)| p.handle == marker.handle) {
+            Some((_, player_transform)) => player_transform.translation,
+            None => continue,
+        };
+        transform.translation = position.truncate().extend(10.0);
+        transform.scale = Vec3::new(MARKER_SIZE * scale, MARKER_SIZE * scale, 1.0);
+
+        let hidden = config.fog_of_war
+            && !local_players.0.contains(&marker.handle)
+            && !local_positions
+                .iter()
+                .any(|p| (*p - position).length_squared() < FOG_RADIUS * FOG_RADIUS);
+        visibility.is_visible = !hidden;
+    }
+}
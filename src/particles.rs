@@ -0,0 +1,375 @@
+use bevy::prelude::*;
+
+use crate::camera::PlayerCamera;
+use crate::events::{PlayEvent, SimEvent};
+use crate::survival::enemy_stats;
+use crate::teams::Teams;
+use crate::{Health, MatchEntity, Player, MAX_HP, WEAPON_SHELL};
+
+// past this many particles new ones are dropped
+const MAX_PARTICLES: usize = 1500;
+const PARTICLE_Z: f32 = 5.0;
+// tanks below this much health start smoking
+const SMOKE_HEALTH: f32 = 0.5;
+// smoke puffs per second at zero health
+const SMOKE_RATE: f32 = 30.0;
+// explosions further away than this don't shake a camera
+const SHAKE_DISTANCE: f32 = 500.0;
+
+pub struct ParticlesPlugin;
+
+impl Plugin for ParticlesPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(ParticleRng(0x2545_f491))
+            .add_system(spawn_event_particles)
+            .add_system(spawn_smoke)
+            .add_system(update_particles);
+    }
+}
+
+// nothing here feeds back into the simulation, so a plain xorshift will do
+struct ParticleRng(u32);
+
+impl ParticleRng {
+    // in [0, 1)
+    fn next(&mut self) -> f32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        (x >> 8) as f32 / (1 << 24) as f32
+    }
+
+    fn range(&mut self, min: f32, max: f32) -> f32 {
+        min + (max - min) * self.next()
+    }
+
+    // a direction within spread radians of the given one
+    fn direction(&mut self, direction: Vec2, spread: f32) -> Vec2 {
+        let angle = direction.y.atan2(direction.x) + self.range(-spread, spread);
+        Vec2::new(angle.cos(), angle.sin())
+    }
+}
+
+#[derive(Component)]
+struct Particle {
+    // per second
+    vel: Vec2,
+    // fraction of the velocity lost per second
+    drag: f32,
+    life: f32,
+    max_life: f32,
+    start_size: f32,
+    end_size: f32,
+    color: Color,
+}
+
+// a burst of particles flying out of a point
+struct Burst {
+    position: Vec2,
+    direction: Vec2,
+    spread: f32,
+    count: usize,
+    speed: (f32, f32),
+    life: (f32, f32),
+    size: (f32, f32),
+    drag: f32,
+    color: Color,
+}
+
+impl Default for Burst {
+    fn default() -> Self {
+        Self {
+            position: Vec2::ZERO,
+            direction: Vec2::X,
+            spread: std::f32::consts::PI,
+            count: 8,
+            speed: (50.0, 150.0),
+            life: (0.2, 0.5),
+            size: (3.0, 0.0),
+            drag: 3.0,
+            color: Color::WHITE,
+        }
+    }
+}
+
+fn spawn_burst(
+    commands: &mut Commands,
+    rng: &mut ParticleRng,
+    particle_count: &mut usize,
+    burst: Burst,
+) {
+    for _ in 0..burst.count {
+        if *particle_count >= MAX_PARTICLES {
+            return;
+        }
+        *particle_count += 1;
+        let vel =
+            rng.direction(burst.direction, burst.spread) * rng.range(burst.speed.0, burst.speed.1);
+        let life = rng.range(burst.life.0, burst.life.1);
+        commands
+            .spawn_bundle(SpriteBundle {
+                transform: Transform {
+                    translation: burst.position.extend(PARTICLE_Z),
+                    scale: Vec3::new(burst.size.0, burst.size.0, 1.0),
+                    ..default()
+                },
+                sprite: Sprite {
+                    color: burst.color,
+                    ..default()
+                },
+                ..default()
+            })
+            .insert(Particle {
+                vel,
+                drag: burst.drag,
+                life,
+                max_life: life,
+                start_size: burst.size.0,
+                end_size: burst.size.1,
+                color: burst.color,
+            })
+            .insert(MatchEntity);
+    }
+}
+
+// played events are deduplicated already, a cancelled one has mostly faded
+// by the time the rollback tells
+fn spawn_event_particles(
+    mut commands: Commands,
+    mut rng: ResMut<ParticleRng>,
+    teams: Res<Teams>,
+    mut events: EventReader<PlayEvent>,
+    wall_query: Query<&Sprite>,
+    particle_query: Query<(), With<Particle>>,
+    mut camera_query: Query<(&mut PlayerCamera, &Transform)>,
+) {
+    let mut count = particle_query.iter().count();
+    for PlayEvent(event) in events.iter() {
+        let mut shake = None;
+        match event {
+            SimEvent::Shot {
+                weapon,
+                position,
+                direction,
+                ..
+            } => {
+                // muzzle flash
+                let shell = *weapon == WEAPON_SHELL;
+                spawn_burst(
+                    &mut commands,
+                    &mut rng,
+                    &mut count,
+                    Burst {
+                        position: *position,
+                        direction: *direction,
+                        spread: 0.4,
+                        count: if shell { 10 } else { 4 },
+                        speed: (80.0, 250.0),
+                        life: (0.05, 0.12),
+                        size: (if shell { 7.0 } else { 4.0 }, 1.0),
+                        drag: 8.0,
+                        color: Color::rgb(1.0, 0.9, 0.5),
+                    },
+                );
+            }
+            SimEvent::WallHit { position, wall, .. } => {
+                // sparks in the color of the wall
+                let color = wall_query.get(*wall).map_or(Color::WHITE, |s| s.color);
+                spawn_burst(
+                    &mut commands,
+                    &mut rng,
+                    &mut count,
+                    Burst {
+                        position: *position,
+                        count: 6,
+                        size: (2.5, 0.5),
+                        color,
+                        ..default()
+                    },
+                );
+            }
+            SimEvent::TankHit { position, .. } => {
+                spawn_burst(
+                    &mut commands,
+                    &mut rng,
+                    &mut count,
+                    Burst {
+                        position: *position,
+                        count: 10,
+                        speed: (80.0, 200.0),
+                        size: (2.5, 0.5),
+                        color: Color::rgb(1.0, 0.7, 0.3),
+                        ..default()
+                    },
+                );
+            }
+            SimEvent::Explosion {
+                weapon, position, ..
+            } if *weapon == WEAPON_SHELL => {
+                spawn_explosion(&mut commands, &mut rng, &mut count, *position, 1.0, None);
+                shake = Some((*position, 0.4));
+            }
+            SimEvent::Kill {
+                victim, position, ..
+            } => {
+                let debris = teams.color(*victim);
+                spawn_explosion(
+                    &mut commands,
+                    &mut rng,
+                    &mut count,
+                    *position,
+                    1.6,
+                    Some(debris),
+                );
+                shake = Some((*position, 0.7));
+            }
+            SimEvent::EnemyKilled { kind, position, .. } => {
+                let debris = enemy_stats(*kind).color;
+                spawn_explosion(
+                    &mut commands,
+                    &mut rng,
+                    &mut count,
+                    *position,
+                    0.8,
+                    Some(debris),
+                );
+            }
+            _ => {}
+        }
+
+        if let Some((position, trauma)) = shake {
+            for (mut camera, transform) in &mut camera_query {
+                let distance = transform.translation.truncate().distance(position);
+                let falloff = (1.0 - distance / SHAKE_DISTANCE).clamp(0.0, 1.0);
+                camera.trauma = (camera.trauma + trauma * falloff).min(1.0);
+            }
+        }
+    }
+}
+
+// fire, smoke and, for tanks, debris in their color
+fn spawn_explosion(
+    commands: &mut Commands,
+    rng: &mut ParticleRng,
+    count: &mut usize,
+    position: Vec2,
+    scale: f32,
+    debris: Option<Color>,
+) {
+    spawn_burst(
+        commands,
+        rng,
+        count,
+        Burst {
+            position,
+            count: (16.0 * scale) as usize,
+            speed: (30.0 * scale, 160.0 * scale),
+            life: (0.2, 0.5),
+            size: (10.0 * scale, 2.0),
+            drag: 4.0,
+            color: Color::rgb(1.0, 0.6, 0.1),
+            ..default()
+        },
+    );
+    spawn_burst(
+        commands,
+        rng,
+        count,
+        Burst {
+            position,
+            count: (10.0 * scale) as usize,
+            speed: (10.0, 60.0 * scale),
+            life: (0.6, 1.2),
+            size: (6.0 * scale, 16.0 * scale),
+            drag: 2.0,
+            color: Color::rgba(0.3, 0.3, 0.3, 0.6),
+            ..default()
+        },
+    );
+    if let Some(color) = debris {
+        spawn_burst(
+            commands,
+            rng,
+            count,
+            Burst {
+                position,
+                count: 12,
+                speed: (60.0, 220.0),
+                life: (0.5, 1.0),
+                size: (4.0, 3.0),
+                drag: 2.5,
+                color,
+                ..default()
+            },
+        );
+    }
+}
+
+// damaged tanks trail smoke, the more the worse they are hit
+fn spawn_smoke(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut rng: ResMut<ParticleRng>,
+    player_query: Query<(&Player, &Transform, &Health)>,
+    particle_query: Query<(), With<Particle>>,
+    mut accumulated: Local<Vec<f32>>,
+) {
+    let mut count = particle_query.iter().count();
+    for (player, transform, health) in &player_query {
+        if accumulated.len() <= player.handle {
+            accumulated.resize(player.handle + 1, 0.0);
+        }
+        let damage = 1.0 - health.hp / (MAX_HP * SMOKE_HEALTH);
+        if !health.is_alive() || damage <= 0.0 {
+            continue;
+        }
+        accumulated[player.handle] += damage * SMOKE_RATE * time.delta_seconds();
+        while accumulated[player.handle] >= 1.0 {
+            accumulated[player.handle] -= 1.0;
+            let gray = rng.range(0.15, 0.35);
+            spawn_burst(
+                &mut commands,
+                &mut rng,
+                &mut count,
+                Burst {
+                    position: transform.translation.truncate(),
+                    direction: Vec2::Y,
+                    spread: 0.6,
+                    count: 1,
+                    speed: (10.0, 30.0),
+                    life: (0.6, 1.2),
+                    size: (4.0, 12.0),
+                    drag: 1.0,
+                    color: Color::rgba(gray, gray, gray, 0.5),
+                },
+            );
+        }
+    }
+}
+
+fn update_particles(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut particle_query: Query<(Entity, &mut Particle, &mut Transform, &mut Sprite)>,
+) {
+    let dt = time.delta_seconds();
+    for (entity, mut particle, mut transform, mut sprite) in &mut particle_query {
+        particle.life -= dt;
+        if particle.life <= 0.0 {
+            commands.entity(entity).despawn();
+            continue;
+        }
+        let vel = particle.vel;
+        transform.translation += (vel * dt).extend(0.0);
+        particle.vel = vel * (1.0 - particle.drag * dt).max(0.0);
+
+        let t = 1.0 - particle.life / particle.max_life;
+        let size = particle.start_size + (particle.end_size - particle.start_size) * t;
+        transform.scale = Vec3::new(size, size, 1.0);
+        let mut color = particle.color;
+        color.set_a(particle.color.a() * (1.0 - t));
+        sprite.color = color;
+    }
+}
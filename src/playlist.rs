@@ -0,0 +1,349 @@
+use std::fs;
+use std::io;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::survival::Enemy;
+use crate::teams::Teams;
+use crate::{
+    Arsenal, Bullet, GameState, Health, MapBounds, MapLayer, MatchClock, MatchConfig, Player,
+    Rigidbody, Score, SpawnPoints, FPS,
+};
+
+pub const PLAYLISTS_DIR: &str = "assets/playlists";
+// from the end of a round to the start of the next, covers the round over
+// banner and the results
+pub const INTERMISSION: u32 = 10 * FPS as u32;
+
+pub struct PlaylistPlugin;
+
+impl Plugin for PlaylistPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set(SystemSet::on_update(GameState::InGame).with_system(show_round_map))
+            .add_system_set(SystemSet::on_update(GameState::RoundEnd).with_system(show_round_map))
+            .add_system_set(SystemSet::on_update(GameState::Results).with_system(show_round_map));
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GameMode {
+    #[default]
+    Deathmatch,
+    TeamDeathmatch,
+    CaptureTheFlag,
+    KingOfTheHill,
+    Survival,
+}
+
+pub const GAME_MODES: [GameMode; 5] = [
+    GameMode::Deathmatch,
+    GameMode::TeamDeathmatch,
+    GameMode::CaptureTheFlag,
+    GameMode::KingOfTheHill,
+    GameMode::Survival,
+];
+
+impl GameMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            GameMode::Deathmatch => "deathmatch",
+            GameMode::TeamDeathmatch => "team_deathmatch",
+            GameMode::CaptureTheFlag => "capture_the_flag",
+            GameMode::KingOfTheHill => "king_of_the_hill",
+            GameMode::Survival => "survival",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        GAME_MODES.iter().copied().find(|m| m.name() == name)
+    }
+
+    pub fn has_teams(&self) -> bool {
+        match self {
+            GameMode::Deathmatch | GameMode::Survival => false,
+            GameMode::TeamDeathmatch | GameMode::CaptureTheFlag | GameMode::KingOfTheHill => true,
+        }
+    }
+
+    pub fn has_flags(&self) -> bool {
+        *self == GameMode::CaptureTheFlag
+    }
+
+    pub fn has_zones(&self) -> bool {
+        *self == GameMode::KingOfTheHill
+    }
+
+    // everyone against the hives, see Survival
+    pub fn is_coop(&self) -> bool {
+        *self == GameMode::Survival
+    }
+
+    // what a player adds to the score of its team, zones score for the
+    // team itself, see HillScore
+    pub fn team_score(&self, score: &Score) -> i32 {
+        match self {
+            GameMode::CaptureTheFlag => score.captures,
+            GameMode::KingOfTheHill => 0,
+            _ => score.kills,
+        }
+    }
+}
+
+// the match settings every peer has to agree on, the host sends its own
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MatchSettings {
+    pub mode: GameMode,
+    pub teams: usize,
+    pub friendly_fire: bool,
+    pub time_limit: u32,
+}
+
+impl MatchSettings {
+    pub fn of(config: &MatchConfig) -> Self {
+        Self {
+            mode: config.mode,
+            teams: config.teams,
+            friendly_fire: config.friendly_fire,
+            time_limit: config.time_limit,
+        }
+    }
+
+    pub fn apply(&self, config: &mut MatchConfig) {
+        config.mode = self.mode;
+        config.teams = self.teams;
+        config.friendly_fire = self.friendly_fire;
+        config.time_limit = self.time_limit;
+    }
+}
+
+// what happens to the scores when the next round starts
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScoreRule {
+    #[default]
+    Reset,
+    Carry,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlaylistEntry {
+    pub map: String,
+    #[serde(default)]
+    pub mode: GameMode,
+    // in seconds, the match setting when missing
+    #[serde(default)]
+    pub time_limit: Option<u32>,
+    // the playlist rule when missing
+    #[serde(default)]
+    pub scores: Option<ScoreRule>,
+}
+
+// assets/playlists/<name>.json, played in order and over again
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Playlist {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub scores: ScoreRule,
+    pub entries: Vec<PlaylistEntry>,
+}
+
+pub fn playlist_path(name: &str) -> String {
+    format!("{}/{}.json", PLAYLISTS_DIR, name)
+}
+
+pub fn parse_playlist(json: &str) -> io::Result<Playlist> {
+    let playlist: Playlist =
+        serde_json::from_str(json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if playlist.entries.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the playlist has no entries",
+        ));
+    }
+    Ok(playlist)
+}
+
+pub fn load_playlist(name: &str) -> io::Result<Playlist> {
+    let path = playlist_path(name);
+    let json = fs::read_to_string(&path)?;
+    let playlist =
+        parse_playlist(&json).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+    Ok(Playlist {
+        name: name.to_string(),
+        ..playlist
+    })
+}
+
+pub fn list_playlists() -> Vec<String> {
+    let mut playlists: Vec<String> = fs::read_dir(PLAYLISTS_DIR)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| {
+                    let path = entry.path();
+                    if path.extension()? != "json" {
+                        return None;
+                    }
+                    Some(path.file_stem()?.to_str()?.to_string())
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    playlists.sort();
+    playlists
+}
+
+// the rounds a match plays, a single one without a playlist
+pub fn match_entries(config: &MatchConfig) -> Vec<PlaylistEntry> {
+    match &config.playlist {
+        Some(playlist) => playlist.entries.clone(),
+        None => vec![PlaylistEntry {
+            map: config.map.clone(),
+            mode: config.mode,
+            time_limit: None,
+            scores: None,
+        }],
+    }
+}
+
+pub struct Round {
+    // index of the map in Rotation::maps
+    pub layer: usize,
+    pub mode: GameMode,
+    // in frames
+    pub duration: u32,
+    pub reset_scores: bool,
+}
+
+// the rounds of the match and the maps they are played on. every map is
+// spawned at setup, so that a new round only has to pick another layer and
+// never loads anything the other peers could disagree on
+pub struct Rotation {
+    pub rounds: Vec<Round>,
+    // by layer
+    pub maps: Vec<String>,
+    pub spawn_points: Vec<SpawnPoints>,
+    pub bounds: Vec<MapBounds>,
+    // a match without a playlist ends after its round
+    pub advances: bool,
+}
+
+impl Rotation {
+    pub fn round(&self, round: u32) -> &Round {
+        &self.rounds[round as usize % self.rounds.len()]
+    }
+
+    pub fn layer(&self, round: u32) -> usize {
+        self.round(round).layer
+    }
+
+    pub fn spawn_points(&self, round: u32) -> &SpawnPoints {
+        &self.spawn_points[self.layer(round)]
+    }
+
+    pub fn map(&self, round: u32) -> &str {
+        &self.maps[self.layer(round)]
+    }
+
+    // whether teams count in the round, see Teams
+    pub fn team_play(&self, round: u32) -> bool {
+        self.round(round).mode.has_teams()
+    }
+}
+
+// the layer whose walls are in play
+pub fn current_layer(rotation: &Rotation, clock_query: &Query<&MatchClock>) -> usize {
+    clock_query
+        .iter()
+        .next()
+        .map_or(0, |clock| rotation.layer(clock.round))
+}
+
+// once the intermission is over everyone starts the next round on its map,
+// on the same frame since it only depends on the clock
+#[allow(clippy::type_complexity)]
+pub fn advance_round(
+    mut commands: Commands,
+    rotation: Res<Rotation>,
+    mut clock_query: Query<&mut MatchClock>,
+    mut player_query: Query<(
+        &Player,
+        &mut Health,
+        &mut Arsenal,
+        &mut Score,
+        &mut Transform,
+        &mut Rigidbody,
+    )>,
+    leftover_query: Query<Entity, Or<(With<Bullet>, With<Enemy>)>>,
+    teams: Res<Teams>,
+) {
+    if !rotation.advances {
+        return;
+    }
+    for mut clock in &mut clock_query {
+        if clock.frame < clock.duration + INTERMISSION {
+            continue;
+        }
+        clock.round += 1;
+        // only the frame of the round starts over, events go by the tick
+        clock.frame = 0;
+        let round = rotation.round(clock.round);
+        clock.duration = round.duration;
+
+        let spawn_points = rotation.spawn_points(clock.round);
+        for (player, mut health, mut arsenal, mut score, mut transform, mut rb) in &mut player_query
+        {
+            *health = Health::full();
+            *arsenal = Arsenal::full();
+            if round.reset_scores {
+                *score = Score::default();
+            }
+            let team = teams.team(player.handle).filter(|_| round.mode.has_teams());
+            let spawn = spawn_points.position(player.handle, 0, team);
+            transform.translation = spawn.extend(transform.translation.z);
+            rb.vel = Vec2::ZERO;
+        }
+        // bullets and enemies don't make it into the next round
+        for entity in &leftover_query {
+            commands.entity(entity).despawn();
+        }
+    }
+}
+
+// shows the walls of the current round. the round changes on a frame that
+// only depends on the clock, so no rollback takes it back
+fn show_round_map(
+    rotation: Option<Res<Rotation>>,
+    clock_query: Query<&MatchClock>,
+    mut config: ResMut<MatchConfig>,
+    mut bounds: Option<ResMut<MapBounds>>,
+    mut layer_query: Query<(&MapLayer, &mut Visibility)>,
+    mut shown: Local<Option<usize>>,
+) {
+    let rotation = match rotation {
+        Some(rotation) => rotation,
+        None => return,
+    };
+    let layer = current_layer(&rotation, &clock_query);
+    if rotation.is_added() {
+        *shown = Some(0);
+    }
+    if *shown == Some(layer) {
+        return;
+    }
+    *shown = Some(layer);
+
+    for (map_layer, mut visibility) in &mut layer_query {
+        visibility.is_visible = map_layer.0 == layer;
+    }
+    if let Some(bounds) = &mut bounds {
+        **bounds = rotation.bounds[layer].clone();
+    }
+    // for the music
+    if config.map != rotation.maps[layer] {
+        config.map = rotation.maps[layer].clone();
+    }
+}
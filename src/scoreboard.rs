@@ -0,0 +1,382 @@
+use bevy::prelude::*;
+use ggrs::P2PSession;
+
+use crate::controls::ControlsMenu;
+use crate::ctf::FlagAction;
+use crate::events::{ConfirmedEvent, SimEvent};
+use crate::playlist::Rotation;
+use crate::survival::enemy_stats;
+use crate::teams::{team_color, team_name, Teams};
+use crate::{GGRSConfig, GameState, MatchClock, MatchEntity, Player, WEAPONS};
+
+// how long a kill or flag stays in the feed, in seconds
+const FEED_TIME: f64 = 5.0;
+const FEED_LINES: usize = 5;
+const FONT_SIZE: f32 = 18.0;
+const COLUMNS: [&str; 5] = ["player", "team", "kills", "deaths", "ping"];
+
+pub struct ScoreboardPlugin;
+
+impl Plugin for ScoreboardPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<KillFeed>()
+            .add_system_set(
+                SystemSet::on_enter(GameState::Loading)
+                    .with_system(reset_kill_feed)
+                    .with_system(spawn_kill_feed),
+            )
+            .add_system(reset_kill_counts)
+            .add_system(confirm_kills.after(reset_kill_counts))
+            .add_system(update_kill_feed.after(confirm_kills))
+            .add_system(toggle_scoreboard)
+            .add_system(update_scoreboard.after(toggle_scoreboard));
+    }
+}
+
+struct FeedEntry {
+    // a kill or a flag
+    event: SimEvent,
+    // when it got confirmed
+    time: f64,
+}
+
+// the confirmed kills and flags, these never change once they are in
+#[derive(Default)]
+struct KillFeed {
+    entries: Vec<FeedEntry>,
+    // by handle
+    kills: Vec<i32>,
+    deaths: Vec<i32>,
+}
+
+impl KillFeed {
+    fn count(counts: &mut Vec<i32>, handle: usize, amount: i32) {
+        if counts.len() <= handle {
+            counts.resize(handle + 1, 0);
+        }
+        counts[handle] += amount;
+    }
+}
+
+#[derive(Component)]
+struct KillFeedText;
+
+#[derive(Component)]
+struct ScoreboardRoot;
+
+#[derive(Component)]
+struct ScoreboardColumn(usize);
+
+fn reset_kill_feed(mut feed: ResMut<KillFeed>) {
+    *feed = KillFeed::default();
+}
+
+// when a round of a playlist starts over from zero
+fn reset_kill_counts(
+    rotation: Option<Res<Rotation>>,
+    clock_query: Query<&MatchClock>,
+    mut feed: ResMut<KillFeed>,
+    mut last_round: Local<u32>,
+) {
+    let (rotation, clock) = match (rotation, clock_query.get_single()) {
+        (Some(rotation), Ok(clock)) => (rotation, clock),
+        _ => return,
+    };
+    if clock.round > *last_round && rotation.round(clock.round).reset_scores {
+        feed.kills.clear();
+        feed.deaths.clear();
+    }
+    // a new match starts at round 0 again
+    *last_round = clock.round;
+}
+
+// a kill is only shown once no rollback can take it back anymore
+fn confirm_kills(
+    time: Res<Time>,
+    mut events: EventReader<ConfirmedEvent>,
+    mut feed: ResMut<KillFeed>,
+) {
+    for ConfirmedEvent(event) in events.iter() {
+        match event {
+            SimEvent::Kill {
+                killer,
+                victim,
+                teamkill,
+                ..
+            } => {
+                KillFeed::count(&mut feed.deaths, *victim, 1);
+                if let Some(killer) = killer {
+                    // the same as handle_deaths does to the score
+                    KillFeed::count(&mut feed.kills, *killer, if *teamkill { -1 } else { 1 });
+                }
+            }
+            SimEvent::Flag { .. } => {}
+            SimEvent::EnemyKilled { killer, .. } => {
+                KillFeed::count(&mut feed.kills, *killer, 1);
+            }
+            _ => continue,
+        }
+        feed.entries.push(FeedEntry {
+            event: event.clone(),
+            time: time.seconds_since_startup(),
+        });
+    }
+}
+
+fn spawn_kill_feed(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands
+        .spawn_bundle(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    left: Val::Px(10.0),
+                    top: Val::Px(10.0),
+                    ..default()
+                },
+                ..default()
+            },
+            text: Text::from_section(
+                "",
+                TextStyle {
+                    font: asset_server.load("fonts/DejaVuSans.ttf"),
+                    font_size: FONT_SIZE,
+                    color: Color::WHITE,
+                },
+            ),
+            ..default()
+        })
+        .insert(KillFeedText)
+        .insert(MatchEntity);
+}
+
+fn update_kill_feed(
+    time: Res<Time>,
+    feed: Res<KillFeed>,
+    teams: Res<Teams>,
+    mut text_query: Query<&mut Text, With<KillFeedText>>,
+) {
+    let now = time.seconds_since_startup();
+    let recent: Vec<&FeedEntry> = feed
+        .entries
+        .iter()
+        .rev()
+        .take_while(|e| now - e.time < FEED_TIME)
+        .take(FEED_LINES)
+        .collect();
+
+    for mut text in &mut text_query {
+        let style = text.sections[0].style.clone();
+        let colored = |value: String, color: Color| TextSection {
+            value,
+            style: TextStyle {
+                color,
+                ..style.clone()
+            },
+        };
+        let mut sections = Vec::new();
+        for entry in recent.iter().rev() {
+            match &entry.event {
+                SimEvent::Kill {
+                    killer: Some(killer),
+                    victim,
+                    weapon,
+                    teamkill,
+                    ..
+                } => {
+                    sections.push(colored(format!("P{}", killer + 1), teams.color(*killer)));
+                    sections.push(colored(" destroyed ".to_string(), Color::WHITE));
+                    sections.push(colored(format!("P{}", victim + 1), teams.color(*victim)));
+                    let weapon = WEAPONS[*weapon as usize].name;
+                    let teamkill = if *teamkill { ", teamkill" } else { "" };
+                    sections.push(colored(
+                        format!(" ({}{})\n", weapon, teamkill),
+                        Color::WHITE,
+                    ));
+                }
+                SimEvent::Kill { victim, .. } => {
+                    sections.push(colored(format!("P{}", victim + 1), teams.color(*victim)));
+                    sections.push(colored(" was destroyed\n".to_string(), Color::WHITE));
+                }
+                SimEvent::Flag {
+                    handle,
+                    team,
+                    action,
+                    ..
+                } => {
+                    let verb = match action {
+                        FlagAction::Taken => "took",
+                        FlagAction::Dropped => "dropped",
+                        FlagAction::Returned => "returned",
+                        FlagAction::Captured => "captured",
+                    };
+                    match handle {
+                        Some(handle) => {
+                            sections
+                                .push(colored(format!("P{}", handle + 1), teams.color(*handle)));
+                            sections.push(colored(format!(" {} the ", verb), Color::WHITE));
+                        }
+                        None => sections.push(colored("the ".to_string(), Color::WHITE)),
+                    }
+                    sections.push(colored(team_name(*team).to_string(), team_color(*team)));
+                    let rest = if handle.is_some() {
+                        " flag\n"
+                    } else {
+                        " flag went home\n"
+                    };
+                    sections.push(colored(rest.to_string(), Color::WHITE));
+                }
+                SimEvent::EnemyKilled { killer, kind, .. } => {
+                    let stats = enemy_stats(*kind);
+                    sections.push(colored(format!("P{}", killer + 1), teams.color(*killer)));
+                    sections.push(colored(" destroyed a ".to_string(), Color::WHITE));
+                    sections.push(colored(format!("{}\n", stats.name), stats.color));
+                }
+                _ => {}
+            }
+        }
+        // keep a section around for the style
+        if sections.is_empty() {
+            sections.push(colored(String::new(), Color::WHITE));
+        }
+        let unchanged = sections.len() == text.sections.len()
+            && sections
+                .iter()
+                .zip(text.sections.iter())
+                .all(|(a, b)| a.value == b.value);
+        if !unchanged {
+            text.sections = sections;
+        }
+    }
+}
+
+// shown while tab is held down
+fn toggle_scoreboard(
+    mut commands: Commands,
+    keyboard_input: Res<Input<KeyCode>>,
+    asset_server: Res<AssetServer>,
+    controls_menu: Res<ControlsMenu>,
+    state: Res<State<GameState>>,
+    root_query: Query<Entity, With<ScoreboardRoot>>,
+) {
+    let in_match = matches!(state.current(), GameState::InGame | GameState::RoundEnd);
+    let show = in_match && !controls_menu.open && keyboard_input.pressed(KeyCode::Tab);
+    let shown = !root_query.is_empty();
+    if show == shown {
+        return;
+    }
+    if !show {
+        for entity in &root_query {
+            commands.entity(entity).despawn_recursive();
+        }
+        return;
+    }
+
+    let font = asset_server.load("fonts/DejaVuSans.ttf");
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                ..default()
+            },
+            color: Color::NONE.into(),
+            ..default()
+        })
+        .insert(ScoreboardRoot)
+        .insert(MatchEntity)
+        .with_children(|parent| {
+            parent
+                .spawn_bundle(NodeBundle {
+                    style: Style {
+                        padding: UiRect::all(Val::Px(12.0)),
+                        ..default()
+                    },
+                    color: Color::rgba(0.0, 0.0, 0.0, 0.8).into(),
+                    ..default()
+                })
+                .with_children(|table| {
+                    for column in 0..COLUMNS.len() {
+                        table
+                            .spawn_bundle(TextBundle {
+                                style: Style {
+                                    margin: UiRect {
+                                        left: Val::Px(12.0),
+                                        right: Val::Px(12.0),
+                                        ..default()
+                                    },
+                                    ..default()
+                                },
+                                text: Text::from_section(
+                                    "",
+                                    TextStyle {
+                                        font: font.clone(),
+                                        font_size: FONT_SIZE,
+                                        color: Color::WHITE,
+                                    },
+                                ),
+                                ..default()
+                            })
+                            .insert(ScoreboardColumn(column));
+                    }
+                });
+        });
+}
+
+fn update_scoreboard(
+    feed: Res<KillFeed>,
+    teams: Res<Teams>,
+    sess: Option<Res<P2PSession<GGRSConfig>>>,
+    player_query: Query<&Player>,
+    mut column_query: Query<(&ScoreboardColumn, &mut Text)>,
+) {
+    if column_query.is_empty() {
+        return;
+    }
+    let count = |counts: &Vec<i32>, handle: usize| counts.get(handle).copied().unwrap_or(0);
+    let mut handles: Vec<usize> = player_query.iter().map(|p| p.handle).collect();
+    // teammates stay together
+    handles.sort_by_key(|h| {
+        let team = teams.team(*h);
+        (team, -count(&feed.kills, *h), count(&feed.deaths, *h), *h)
+    });
+
+    for (column, mut text) in &mut column_query {
+        let style = text.sections[0].style.clone();
+        let mut sections = vec![TextSection {
+            value: format!("{}\n", COLUMNS[column.0]),
+            style: TextStyle {
+                color: Color::GRAY,
+                ..style.clone()
+            },
+        }];
+        for handle in &handles {
+            let value = match column.0 {
+                0 => format!("P{}", handle + 1),
+                1 => teams
+                    .team(*handle)
+                    .map_or("-".to_string(), |team| format!("T{}", team + 1)),
+                2 => count(&feed.kills, *handle).to_string(),
+                3 => count(&feed.deaths, *handle).to_string(),
+                _ => match &sess {
+                    Some(sess) if sess.local_player_handles().contains(handle) => {
+                        "local".to_string()
+                    }
+                    Some(sess) => sess
+                        .network_stats(*handle)
+                        .map_or("-".to_string(), |s| format!("{} ms", s.ping)),
+                    None => "-".to_string(),
+                },
+            };
+            sections.push(TextSection {
+                value: value + "\n",
+                style: TextStyle {
+                    color: teams.color(*handle),
+                    ..style.clone()
+                },
+            });
+        }
+        text.sections = sections;
+    }
+}
@@ -0,0 +1,83 @@
+// wire format shared by the game and the signaling server binary
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_SERVER_PORT: u16 = 3536;
+
+// the largest payload of a udp datagram, receive buffers are this big
+pub const MAX_DATAGRAM: usize = 65507;
+
+const SIGNALING_MAGIC: u32 = 0x7369676e;
+
+// the map of a match, hash and size of the file so peers that don't have it
+// can fetch it from the host. generated maps have no hash. the match
+// settings and a playlist come along as json, with the other maps of the
+// playlist described the same way as the first one
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MapInfo {
+    pub name: String,
+    pub hash: Option<u64>,
+    pub size: usize,
+    pub settings: Option<String>,
+    pub playlist: Option<String>,
+    // each once, without the first map
+    pub others: Vec<MapFile>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MapFile {
+    pub name: String,
+    pub hash: Option<u64>,
+    pub size: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Packet {
+    magic: u32,
+    message: SignalingMessage,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum SignalingMessage {
+    // client to server, resent until the room starts. the first join
+    // decides the room size and the map
+    Join {
+        room: String,
+        num_players: usize,
+        map: MapInfo,
+    },
+    StartAck {
+        room: String,
+    },
+    // server to client
+    Waiting {
+        handle: usize,
+        joined: usize,
+        num_players: usize,
+    },
+    // the addresses of every player as seen by the server, in handle order
+    Start {
+        handle: usize,
+        players: Vec<SocketAddr>,
+        map: MapInfo,
+    },
+    Rejected {
+        reason: String,
+    },
+}
+
+pub fn encode(message: SignalingMessage) -> Vec<u8> {
+    bincode::serialize(&Packet {
+        magic: SIGNALING_MAGIC,
+        message,
+    })
+    .expect("signaling message serialization failed")
+}
+
+pub fn decode(bytes: &[u8]) -> Option<SignalingMessage> {
+    match bincode::deserialize::<Packet>(bytes) {
+        Ok(packet) if packet.magic == SIGNALING_MAGIC => Some(packet.message),
+        _ => None,
+    }
+}
@@ -0,0 +1,171 @@
+// the rooms of the signaling server, in a module of its own so that the
+// lobby tests can run one
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::signaling_protocol::{decode, encode, MapInfo, SignalingMessage, MAX_DATAGRAM};
+
+// rooms nobody talked to for this long are dropped
+const ROOM_TIMEOUT: Duration = Duration::from_secs(60);
+
+struct Room {
+    num_players: usize,
+    map: MapInfo,
+    members: Vec<SocketAddr>,
+    acked: Vec<bool>,
+    last_seen: Instant,
+}
+
+impl Room {
+    fn is_full(&self) -> bool {
+        self.members.len() >= self.num_players
+    }
+}
+
+pub struct Server {
+    socket: UdpSocket,
+    rooms: HashMap<String, Room>,
+}
+
+impl Server {
+    // the socket should have a read timeout, rooms time out in between
+    pub fn new(socket: UdpSocket) -> Self {
+        Self {
+            socket,
+            rooms: HashMap::new(),
+        }
+    }
+
+    fn send(&self, addr: SocketAddr, message: SignalingMessage) {
+        // one unreachable client shouldn't take the server down
+        if let Err(e) = self.socket.send_to(&encode(message), addr) {
+            warn!("failed to send to {}: {}", addr, e);
+        }
+    }
+
+    fn handle(&mut self, addr: SocketAddr, message: SignalingMessage) {
+        match message {
+            SignalingMessage::Join {
+                room: name,
+                num_players,
+                map,
+            } => {
+                if num_players == 0 {
+                    let reason = "a room needs at least one player".to_string();
+                    self.send(addr, SignalingMessage::Rejected { reason });
+                    return;
+                }
+                let room = self.rooms.entry(name.clone()).or_insert_with(|| {
+                    info!(
+                        "room {} created for {} players on {}",
+                        name, num_players, map.name
+                    );
+                    Room {
+                        num_players,
+                        map,
+                        members: Vec::new(),
+                        acked: Vec::new(),
+                        last_seen: Instant::now(),
+                    }
+                });
+                room.last_seen = Instant::now();
+
+                if room.num_players != num_players {
+                    let reason = format!(
+                        "room {} is for {} players, not {}",
+                        name, room.num_players, num_players
+                    );
+                    self.send(addr, SignalingMessage::Rejected { reason });
+                    return;
+                }
+                if !room.members.contains(&addr) {
+                    if room.is_full() {
+                        let reason = format!("room {} is full", name);
+                        self.send(addr, SignalingMessage::Rejected { reason });
+                        return;
+                    }
+                    info!("{} joined room {}", addr, name);
+                    room.members.push(addr);
+                    room.acked.push(false);
+                }
+
+                let handle = room.members.iter().position(|m| *m == addr).unwrap();
+                let reply = if room.is_full() {
+                    SignalingMessage::Start {
+                        handle,
+                        players: room.members.clone(),
+                        map: room.map.clone(),
+                    }
+                } else {
+                    SignalingMessage::Waiting {
+                        handle,
+                        joined: room.members.len(),
+                        num_players: room.num_players,
+                    }
+                };
+                let start = room.is_full();
+                let members = room.members.clone();
+                self.send(addr, reply);
+
+                // let everyone else know right away instead of waiting for
+                // their next join
+                if start {
+                    let map = self.rooms[&name].map.clone();
+                    for (i, member) in members.iter().enumerate() {
+                        if *member != addr {
+                            self.send(
+                                *member,
+                                SignalingMessage::Start {
+                                    handle: i,
+                                    players: members.clone(),
+                                    map: map.clone(),
+                                },
+                            );
+                        }
+                    }
+                }
+            }
+            SignalingMessage::StartAck { room: name } => {
+                if let Some(room) = self.rooms.get_mut(&name) {
+                    if let Some(i) = room.members.iter().position(|m| *m == addr) {
+                        room.acked[i] = true;
+                    }
+                    if room.is_full() && room.acked.iter().all(|a| *a) {
+                        info!("room {} started", name);
+                        self.rooms.remove(&name);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut buffer = [0u8; MAX_DATAGRAM];
+        loop {
+            match self.socket.recv_from(&mut buffer) {
+                Ok((len, addr)) => {
+                    if let Some(message) = decode(&buffer[..len]) {
+                        self.handle(addr, message);
+                    }
+                }
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut
+                        || e.kind() == io::ErrorKind::ConnectionReset => {}
+                Err(e) => return Err(e),
+            }
+            self.rooms.retain(|name, room| {
+                let alive = room.last_seen.elapsed() < ROOM_TIMEOUT;
+                if !alive {
+                    info!("room {} timed out", name);
+                }
+                alive
+            });
+        }
+    }
+}
@@ -0,0 +1,265 @@
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::Path;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::camera::PlayerCamera;
+use crate::events::{PlayEvent, SimEvent};
+use crate::{GameState, Health, MatchConfig, Player, Rigidbody, WEAPON_SHELL};
+
+const AUDIO_PATH: &str = "settings/audio.json";
+const DEFAULT_MUSIC: &str = "music/default.wav";
+// a sound this far from every camera is silent
+const HEARING_DISTANCE: f32 = 900.0;
+// the engine hum is loudest at this speed
+const ENGINE_TOP_SPEED: f32 = 4.0;
+const ENGINE_IDLE: f32 = 0.2;
+const ENGINE_VOLUME: f32 = 0.3;
+
+pub struct SoundPlugin;
+
+impl Plugin for SoundPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(AudioSettings::load())
+            .init_resource::<EngineSounds>()
+            .init_resource::<MusicPlayer>()
+            .add_startup_system(load_sounds)
+            .add_system(play_event_sounds)
+            .add_system(update_engine_sounds)
+            .add_system(update_music)
+            .add_system_set(
+                SystemSet::on_enter(GameState::MainMenu).with_system(stop_engine_sounds),
+            );
+    }
+}
+
+// volumes from 0 to 1, the music and effects ones are scaled by the master
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct AudioSettings {
+    pub master: f32,
+    pub music: f32,
+    pub effects: f32,
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        Self {
+            master: 0.8,
+            music: 0.5,
+            effects: 0.8,
+        }
+    }
+}
+
+impl AudioSettings {
+    pub fn load() -> Self {
+        let file = match File::open(AUDIO_PATH) {
+            Ok(file) => file,
+            Err(_) => return Self::default(),
+        };
+        match serde_json::from_reader(BufReader::new(file)) {
+            Ok(settings) => settings,
+            Err(e) => {
+                warn!("ignoring {}: {}", AUDIO_PATH, e);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save(&self) {
+        let result = fs::create_dir_all("settings")
+            .and_then(|_| File::create(AUDIO_PATH))
+            .map_err(|e| e.to_string())
+            .and_then(|file| serde_json::to_writer_pretty(file, self).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            warn!("failed to save {}: {}", AUDIO_PATH, e);
+        }
+    }
+
+    fn music_volume(&self) -> f32 {
+        self.master * self.music
+    }
+
+    fn effects_volume(&self) -> f32 {
+        self.master * self.effects
+    }
+}
+
+struct Sounds {
+    // by weapon
+    shots: [Handle<AudioSource>; 2],
+    wall_hit: Handle<AudioSource>,
+    tank_hit: Handle<AudioSource>,
+    explosion: Handle<AudioSource>,
+    engine: Handle<AudioSource>,
+}
+
+// one looping hum per tank, by handle
+#[derive(Default)]
+struct EngineSounds(Vec<(usize, Handle<AudioSink>)>);
+
+#[derive(Default)]
+struct MusicPlayer {
+    track: Option<String>,
+    sink: Option<Handle<AudioSink>>,
+}
+
+fn load_sounds(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(Sounds {
+        shots: [
+            asset_server.load("sounds/shot_cannon.wav"),
+            asset_server.load("sounds/shot_shell.wav"),
+        ],
+        wall_hit: asset_server.load("sounds/wall_hit.wav"),
+        tank_hit: asset_server.load("sounds/tank_hit.wav"),
+        explosion: asset_server.load("sounds/explosion.wav"),
+        engine: asset_server.load("sounds/engine.wav"),
+    });
+}
+
+// fades out with the distance to the closest camera
+fn attenuation(position: Vec2, listeners: &[Vec2]) -> f32 {
+    let distance = listeners
+        .iter()
+        .map(|l| l.distance(position))
+        .fold(f32::INFINITY, f32::min);
+    if distance.is_infinite() {
+        return 1.0;
+    }
+    (1.0 - distance / HEARING_DISTANCE).clamp(0.0, 1.0)
+}
+
+// the events are deduplicated across rollbacks already, and the sounds are
+// too short for a cancelled one to be worth stopping
+fn play_event_sounds(
+    audio: Res<Audio>,
+    sounds: Res<Sounds>,
+    settings: Res<AudioSettings>,
+    mut events: EventReader<PlayEvent>,
+    camera_query: Query<&Transform, (With<PlayerCamera>, Without<Player>)>,
+) {
+    let listeners: Vec<Vec2> = camera_query
+        .iter()
+        .map(|t| t.translation.truncate())
+        .collect();
+    for PlayEvent(event) in events.iter() {
+        let (sound, position, volume) = match event {
+            SimEvent::Shot {
+                weapon, position, ..
+            } => (&sounds.shots[*weapon as usize], position, 0.5),
+            SimEvent::WallHit { position, .. } => (&sounds.wall_hit, position, 0.4),
+            SimEvent::TankHit { position, .. } => (&sounds.tank_hit, position, 0.7),
+            // cannon rounds already made their impact sound
+            SimEvent::Explosion {
+                weapon, position, ..
+            } if *weapon == WEAPON_SHELL => (&sounds.explosion, position, 0.6),
+            SimEvent::Kill { position, .. } => (&sounds.explosion, position, 1.0),
+            SimEvent::EnemyKilled { position, .. } => (&sounds.explosion, position, 0.5),
+            _ => continue,
+        };
+        let volume = volume * settings.effects_volume() * attenuation(*position, &listeners);
+        if volume > 0.0 {
+            audio.play_with_settings(sound.clone(), PlaybackSettings::ONCE.with_volume(volume));
+        }
+    }
+}
+
+fn update_engine_sounds(
+    audio: Res<Audio>,
+    sounds: Res<Sounds>,
+    settings: Res<AudioSettings>,
+    sinks: Res<Assets<AudioSink>>,
+    mut engines: ResMut<EngineSounds>,
+    player_query: Query<(&Player, &Rigidbody, &Transform, &Health)>,
+    camera_query: Query<&Transform, (With<PlayerCamera>, Without<Player>)>,
+) {
+    let listeners: Vec<Vec2> = camera_query
+        .iter()
+        .map(|t| t.translation.truncate())
+        .collect();
+    for (player, rb, transform, health) in &player_query {
+        let sink = match engines.0.iter().find(|(h, _)| *h == player.handle) {
+            Some((_, sink)) => sink.clone(),
+            None => {
+                let sink = audio.play_with_settings(
+                    sounds.engine.clone(),
+                    PlaybackSettings::LOOP.with_volume(0.0),
+                );
+                // the sink stays around only while something holds on to it
+                let sink = sinks.get_handle(sink);
+                engines.0.push((player.handle, sink.clone()));
+                sink
+            }
+        };
+        let sink = match sinks.get(&sink) {
+            Some(sink) => sink,
+            None => continue,
+        };
+        let speed = (rb.vel.length() / ENGINE_TOP_SPEED).clamp(0.0, 1.0);
+        let volume = if health.is_alive() {
+            (ENGINE_IDLE + (1.0 - ENGINE_IDLE) * speed)
+                * ENGINE_VOLUME
+                * settings.effects_volume()
+                * attenuation(transform.translation.truncate(), &listeners)
+        } else {
+            0.0
+        };
+        sink.set_volume(volume);
+        sink.set_speed(0.8 + 0.6 * speed);
+    }
+}
+
+fn stop_engine_sounds(sinks: Res<Assets<AudioSink>>, mut engines: ResMut<EngineSounds>) {
+    for (_, sink) in engines.0.drain(..) {
+        if let Some(sink) = sinks.get(&sink) {
+            sink.pause();
+        }
+    }
+}
+
+// assets/music/<map>.ogg or .wav, if the map has a track of its own
+fn map_music(map: &str) -> String {
+    for extension in ["ogg", "wav"] {
+        let track = format!("music/{}.{}", map, extension);
+        if Path::new("assets").join(&track).exists() {
+            return track;
+        }
+    }
+    DEFAULT_MUSIC.to_string()
+}
+
+fn update_music(
+    audio: Res<Audio>,
+    asset_server: Res<AssetServer>,
+    settings: Res<AudioSettings>,
+    config: Res<MatchConfig>,
+    state: Res<State<GameState>>,
+    sinks: Res<Assets<AudioSink>>,
+    mut music: ResMut<MusicPlayer>,
+) {
+    if state.is_changed() || config.is_changed() || music.track.is_none() {
+        let track = match state.current() {
+            GameState::MainMenu | GameState::Lobby => DEFAULT_MUSIC.to_string(),
+            _ => map_music(&config.map),
+        };
+        if music.track.as_ref() != Some(&track) {
+            if let Some(sink) = music.sink.take().and_then(|s| sinks.get(&s)) {
+                sink.pause();
+            }
+            let sink = audio.play_with_settings(
+                asset_server.load(&track),
+                PlaybackSettings::LOOP.with_volume(settings.music_volume()),
+            );
+            music.sink = Some(sinks.get_handle(sink));
+            music.track = Some(track);
+        }
+    }
+
+    if settings.is_changed() {
+        if let Some(sink) = music.sink.as_ref().and_then(|s| sinks.get(s)) {
+            sink.set_volume(settings.music_volume());
+        }
+    }
+}
@@ -0,0 +1,411 @@
+use bevy::prelude::*;
+use bevy_ggrs::{Rollback, RollbackIdProvider};
+
+use crate::events::{SimEvent, SimEvents};
+use crate::map::Map;
+use crate::playlist::Rotation;
+use crate::{
+    collide_circle_walls, intersect_segment_circle, Bullet, Fuse, Health, MapLayer, MatchClock,
+    Player, Rigidbody, Score, Wall, WallPieces, FPS, WEAPONS,
+};
+
+// survival rounds end when everyone is dead, not on time
+pub const SURVIVAL_DURATION: u32 = 24 * 60 * 60 * FPS as u32;
+const LIVES_PER_PLAYER: i32 = 3;
+// between two waves, and before the first one
+const WAVE_BREAK: u32 = 5 * FPS as u32;
+// the next wave doesn't wait any longer for the last enemies of this one
+const WAVE_TIME: u32 = 60 * FPS as u32;
+// enemies get this much tougher with every wave
+const HP_PER_WAVE: f32 = 0.1;
+// in frames, however late the wave
+const MIN_SPAWN_INTERVAL: u32 = FPS as u32 / 2;
+// enemies farther than that from every tank go back to their hive
+const ENEMY_SIGHT: f32 = 500.0;
+
+pub struct EnemyStats {
+    pub name: &'static str,
+    pub hp: f32,
+    pub speed: f32,
+    pub radius: f32,
+    // per second of contact with a tank
+    pub damage: f32,
+    pub color: Color,
+}
+
+// by the enemyType of the hives
+pub const ENEMY_TYPES: [EnemyStats; 2] = [
+    EnemyStats {
+        name: "crawler",
+        hp: 20.0,
+        speed: 0.5,
+        radius: 7.0,
+        damage: 25.0,
+        color: Color::rgb(0.55, 0.75, 0.2),
+    },
+    EnemyStats {
+        name: "brute",
+        hp: 80.0,
+        speed: 0.3,
+        radius: 12.0,
+        damage: 50.0,
+        color: Color::rgb(0.5, 0.3, 0.15),
+    },
+];
+
+pub fn enemy_stats(kind: usize) -> &'static EnemyStats {
+    &ENEMY_TYPES[kind % ENEMY_TYPES.len()]
+}
+
+// the waves of a survival round, on its own rollback entity
+#[derive(Component, Default, Reflect)]
+pub struct Survival {
+    // 0 until the first one
+    pub wave: u32,
+    // shared by everyone, a dead tank needs one to come back
+    pub lives: i32,
+    // frames since the wave started
+    pub wave_frame: u32,
+    // frames until the next wave, 0 while one is going on
+    pub pause: u32,
+}
+
+impl Survival {
+    fn start(num_players: usize) -> Self {
+        Self {
+            wave: 0,
+            lives: LIVES_PER_PLAYER * num_players as i32,
+            wave_frame: 0,
+            pause: WAVE_BREAK,
+        }
+    }
+}
+
+// a hive of the map, on its own rollback entity
+#[derive(Component, Default, Reflect)]
+pub struct HiveSpawner {
+    // see MapLayer
+    pub layer: usize,
+    // in the hives of the map, enemies remember it
+    pub index: usize,
+    pub position: Vec2,
+    pub radius: f32,
+    pub kind: usize,
+    // alive at once
+    pub max_enemies: u32,
+    // in frames, the time between two enemies in the first wave
+    pub respawn_time: u32,
+    // still to come in this wave
+    pub left: u32,
+    // frames until the next enemy
+    pub timer: u32,
+    pub spawned: u32,
+}
+
+impl HiveSpawner {
+    // half the hive's max enemies more with every wave, coming faster
+    fn wave_size(&self, wave: u32) -> u32 {
+        (self.max_enemies * wave + 1) / 2
+    }
+
+    fn spawn_interval(&self, wave: u32) -> u32 {
+        (self.respawn_time * 2 / (wave + 1)).max(MIN_SPAWN_INTERVAL)
+    }
+}
+
+#[derive(Component, Default, Reflect)]
+pub struct Enemy {
+    pub kind: usize,
+    pub hive: usize,
+    pub home: Vec2,
+    pub hp: f32,
+}
+
+// the map has been validated
+pub fn spawn_hives(commands: &mut Commands, rip: &mut RollbackIdProvider, map: &Map, layer: usize) {
+    let (min, max) = map.bounds().unwrap_or_default();
+    let origin = (max - min).as_vec2();
+    for (index, hive) in map.hives.iter().enumerate() {
+        commands
+            .spawn()
+            .insert(HiveSpawner {
+                layer,
+                index,
+                position: Vec2::new(hive.pos[0] as f32, hive.pos[1] as f32) - origin / 2.0,
+                radius: hive.radius.max(0) as f32,
+                kind: hive.enemy_type.rem_euclid(ENEMY_TYPES.len() as i32) as usize,
+                max_enemies: hive.max_enemies.max(1) as u32,
+                respawn_time: hive.respawn_time.max(1) as u32,
+                left: 0,
+                timer: 0,
+                spawned: 0,
+            })
+            .insert(Rollback::new(rip.next_id()));
+    }
+}
+
+pub fn spawn_survival(commands: &mut Commands, rip: &mut RollbackIdProvider) {
+    commands
+        .spawn()
+        .insert(Survival::default())
+        .insert(Rollback::new(rip.next_id()));
+}
+
+// enemies go for the closest tank and hurt it on contact, bullets hurt them.
+// everything goes in rollback id order, so that every peer adds the damage
+// up the same way
+#[allow(clippy::type_complexity)]
+pub fn move_enemies(
+    mut commands: Commands,
+    rotation: Res<Rotation>,
+    clock_query: Query<&MatchClock>,
+    mut enemy_query: Query<
+        (
+            Entity,
+            &mut Enemy,
+            &mut Transform,
+            &mut Rigidbody,
+            &Rollback,
+        ),
+        (Without<Player>, Without<Bullet>, Without<Wall>),
+    >,
+    mut player_query: Query<(&Player, &Transform, &mut Health, &mut Score), Without<Enemy>>,
+    mut bullet_query: Query<
+        (&Transform, &Rigidbody, &mut Fuse, &Bullet, &Rollback),
+        Without<Enemy>,
+    >,
+    wall_query: Query<(&WallPieces, &MapLayer), With<Wall>>,
+    mut events: ResMut<SimEvents>,
+) {
+    let clock = match clock_query.iter().next() {
+        Some(clock) => clock,
+        None => return,
+    };
+    let round = rotation.round(clock.round);
+    if !round.mode.is_coop() || clock.expired() {
+        return;
+    }
+    let dt = 1.0 / FPS as f32;
+    let walls: Vec<_> = wall_query
+        .iter()
+        .filter(|(.., wall_layer)| wall_layer.0 == round.layer)
+        .map(|(wall, _)| ((), wall))
+        .collect();
+
+    let mut tanks: Vec<(usize, Vec2, f32)> = player_query
+        .iter()
+        .filter(|(_, _, health, _)| health.is_alive())
+        .map(|(p, t, ..)| (p.handle, t.translation.truncate(), p.radius))
+        .collect();
+    tanks.sort_by_key(|(handle, ..)| *handle);
+    let mut enemies: Vec<_> = enemy_query.iter_mut().collect();
+    enemies.sort_by_key(|(.., rollback)| rollback.id());
+
+    let mut damage: Vec<f32> = Vec::new();
+    for (_, enemy, transform, rb, _) in &mut enemies {
+        let stats = enemy_stats(enemy.kind);
+        let position = transform.translation.truncate();
+        // the first of the closest, tanks are in handle order
+        let target = tanks
+            .iter()
+            .map(|(_, p, _)| (*p, p.distance(position)))
+            .filter(|(_, distance)| *distance < ENEMY_SIGHT)
+            .min_by(|a, b| a.1.total_cmp(&b.1));
+        let goal = target.map_or(enemy.home, |(p, _)| p);
+        if goal.distance(position) > stats.radius {
+            rb.vel += (goal - position).normalize_or_zero() * stats.speed;
+        }
+        let (pos, vel) = collide_circle_walls(&walls, transform.translation, rb.vel, stats.radius);
+        transform.translation = pos;
+        rb.vel = vel;
+        transform.translation.x += rb.vel.x;
+        transform.translation.y += rb.vel.y;
+        let friction = rb.friction;
+        rb.vel *= 1.0 - friction;
+
+        let position = transform.translation.truncate();
+        for (handle, p, radius) in &tanks {
+            if p.distance(position) < radius + stats.radius {
+                if damage.len() <= *handle {
+                    damage.resize(handle + 1, 0.0);
+                }
+                damage[*handle] += stats.damage * dt;
+            }
+        }
+    }
+    for (player, _, mut health, _) in &mut player_query {
+        let amount = damage.get(player.handle).copied().unwrap_or(0.0);
+        if amount > 0.0 && health.is_alive() {
+            health.hp = (health.hp - amount).max(0.0);
+            // nobody gets the kill
+            health.last_hit_by = -1;
+        }
+    }
+
+    // move_bullets leaves the ones that hit something here alone
+    let mut bullets: Vec<_> = bullet_query
+        .iter_mut()
+        .filter(|(_, _, fuse, ..)| !(fuse.lit && fuse.timeleft <= 0.0))
+        .collect();
+    bullets.sort_by_key(|(.., rollback)| rollback.id());
+    let mut kills = Vec::new();
+    for (bullet_tr, bullet_rb, fuse, bullet, _) in &mut bullets {
+        for (entity, enemy, transform, ..) in &mut enemies {
+            if enemy.hp <= 0.0 {
+                continue;
+            }
+            let stats = enemy_stats(enemy.kind);
+            if !intersect_segment_circle(
+                bullet_tr.translation.truncate().extend(0.0),
+                bullet_rb.vel.extend(0.0),
+                transform.translation.truncate().extend(0.0),
+                stats.radius,
+            ) {
+                continue;
+            }
+            fuse.timeleft = 0.0;
+            fuse.lit = true;
+            enemy.hp -= WEAPONS[bullet.weapon as usize].damage;
+            if enemy.hp <= 0.0 {
+                commands.entity(*entity).despawn();
+                kills.push(bullet.owner);
+                events.emit(SimEvent::EnemyKilled {
+                    killer: bullet.owner,
+                    kind: enemy.kind,
+                    position: transform.translation.truncate(),
+                });
+            }
+            // one enemy per bullet
+            break;
+        }
+    }
+    for killer in kills {
+        for (player, _, _, mut score) in &mut player_query {
+            if player.handle == killer {
+                score.kills += 1;
+            }
+        }
+    }
+}
+
+// the hives send out their enemies wave after wave, and the round is lost
+// once every tank is dead with no lives left
+#[allow(clippy::type_complexity)]
+pub fn update_waves(
+    mut commands: Commands,
+    mut rip: ResMut<RollbackIdProvider>,
+    rotation: Res<Rotation>,
+    mut clock_query: Query<&mut MatchClock>,
+    mut survival_query: Query<&mut Survival>,
+    mut hive_query: Query<&mut HiveSpawner>,
+    enemy_query: Query<&Enemy>,
+    player_query: Query<&Health, With<Player>>,
+) {
+    let (mut clock, mut survival) = match (
+        clock_query.iter_mut().next(),
+        survival_query.iter_mut().next(),
+    ) {
+        (Some(clock), Some(survival)) => (clock, survival),
+        _ => return,
+    };
+    let round = rotation.round(clock.round);
+    // every round starts from scratch, advance_round took the enemies away
+    if clock.frame == 0 {
+        *survival = Survival::start(player_query.iter().count());
+        for mut hive in &mut hive_query {
+            hive.left = 0;
+            hive.timer = 0;
+            hive.spawned = 0;
+        }
+    }
+    if !round.mode.is_coop() || clock.expired() {
+        return;
+    }
+    if survival.lives <= 0 && player_query.iter().all(|health| !health.is_alive()) {
+        // game over, the round ends right now
+        clock.duration = clock.frame;
+        return;
+    }
+
+    let mut hives: Vec<_> = hive_query
+        .iter_mut()
+        .filter(|hive| hive.layer == round.layer)
+        .collect();
+    hives.sort_by_key(|hive| hive.index);
+    if survival.pause > 0 {
+        survival.pause -= 1;
+        if survival.pause == 0 {
+            survival.wave += 1;
+            survival.wave_frame = 0;
+            for hive in &mut hives {
+                hive.left = hive.wave_size(survival.wave);
+                hive.timer = 0;
+            }
+        }
+        return;
+    }
+    survival.wave_frame += 1;
+
+    let mut alive = vec![0; hives.iter().map(|h| h.index + 1).max().unwrap_or(0)];
+    for enemy in &enemy_query {
+        if let Some(count) = alive.get_mut(enemy.hive) {
+            *count += 1;
+        }
+    }
+    let wave = survival.wave;
+    let mut spawned = false;
+    for hive in &mut hives {
+        if hive.left == 0 {
+            continue;
+        }
+        if hive.timer > 0 {
+            hive.timer -= 1;
+            continue;
+        }
+        if alive[hive.index] >= hive.max_enemies {
+            continue;
+        }
+        // around the hive, each one a bit further along
+        let angle = hive.spawned as f32 * 2.4;
+        let position = hive.position + Vec2::new(angle.cos(), angle.sin()) * hive.radius * 0.5;
+        let stats = enemy_stats(hive.kind);
+        commands
+            .spawn_bundle(SpriteBundle {
+                transform: Transform {
+                    translation: position.extend(0.0),
+                    rotation: Quat::from_rotation_z(std::f32::consts::FRAC_PI_4),
+                    scale: Vec3::new(stats.radius * 1.6, stats.radius * 1.6, 1.0),
+                },
+                sprite: Sprite {
+                    color: stats.color,
+                    ..default()
+                },
+                ..default()
+            })
+            .insert(Enemy {
+                kind: hive.kind,
+                hive: hive.index,
+                home: hive.position,
+                hp: stats.hp * (1.0 + HP_PER_WAVE * (wave - 1) as f32),
+            })
+            .insert(Rigidbody {
+                vel: Vec2::ZERO,
+                friction: 0.2,
+            })
+            .insert(Rollback::new(rip.next_id()));
+        hive.left -= 1;
+        hive.spawned += 1;
+        hive.timer = hive.spawn_interval(wave);
+        spawned = true;
+    }
+
+    let all_out = !spawned && hives.iter().all(|hive| hive.left == 0);
+    let cleared = all_out && enemy_query.is_empty();
+    if cleared || (all_out && survival.wave_frame >= WAVE_TIME) {
+        survival.pause = WAVE_BREAK;
+        // a wave beaten down to the last enemy gives a life back
+        if cleared {
+            survival.lives += 1;
+        }
+    }
+}
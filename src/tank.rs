@@ -0,0 +1,121 @@
+use bevy::prelude::*;
+use bevy::sprite::MaterialMesh2dBundle;
+
+use crate::teams::Teams;
+use crate::{Health, Player, Rigidbody};
+
+const HULL_SIZE: Vec2 = Vec2::new(26.0, 20.0);
+const TURRET_RADIUS: f32 = 7.0;
+const BARREL_SIZE: Vec2 = Vec2::new(14.0, 4.0);
+const LABEL_OFFSET: f32 = 24.0;
+// below this speed the hull keeps facing where it was going
+const HULL_TURN_SPEED: f32 = 0.1;
+
+pub struct TankPlugin;
+
+impl Plugin for TankPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(spawn_tank_visuals)
+            .add_system_to_stage(CoreStage::PostUpdate, update_tank_visuals);
+    }
+}
+
+#[derive(Component)]
+struct Hull;
+
+#[derive(Component)]
+struct Turret;
+
+// the visuals are plain children of the rollback entity, they are derived
+// from its components every frame and never read back by the simulation
+fn spawn_tank_visuals(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    teams: Res<Teams>,
+    player_query: Query<(Entity, &Player), Added<Player>>,
+) {
+    for (entity, player) in &player_query {
+        let color = teams.color(player.handle);
+        let dark = Color::rgb(color.r() * 0.5, color.g() * 0.5, color.b() * 0.5);
+
+        commands.entity(entity).with_children(|parent| {
+            parent
+                .spawn_bundle(SpriteBundle {
+                    transform: Transform {
+                        scale: HULL_SIZE.extend(1.0),
+                        ..default()
+                    },
+                    sprite: Sprite {
+                        color: dark,
+                        ..default()
+                    },
+                    ..default()
+                })
+                .insert(Hull);
+
+            parent
+                .spawn_bundle(MaterialMesh2dBundle {
+                    mesh: meshes
+                        .add(Mesh::from(shape::Circle::new(TURRET_RADIUS)))
+                        .into(),
+                    transform: Transform::from_xyz(0.0, 0.0, 0.2),
+                    material: materials.add(ColorMaterial::from(color)),
+                    ..default()
+                })
+                .insert(Turret)
+                .with_children(|turret| {
+                    turret.spawn_bundle(SpriteBundle {
+                        transform: Transform {
+                            translation: Vec3::new(BARREL_SIZE.x / 2.0 + 3.0, 0.0, 0.1),
+                            scale: BARREL_SIZE.extend(1.0),
+                            ..default()
+                        },
+                        sprite: Sprite { color, ..default() },
+                        ..default()
+                    });
+                });
+
+            parent.spawn_bundle(Text2dBundle {
+                text: Text::from_section(
+                    format!("P{}", player.handle + 1),
+                    TextStyle {
+                        font: asset_server.load("fonts/DejaVuSans.ttf"),
+                        font_size: 12.0,
+                        color,
+                    },
+                )
+                .with_alignment(TextAlignment {
+                    vertical: VerticalAlign::Center,
+                    horizontal: HorizontalAlign::Center,
+                }),
+                transform: Transform::from_xyz(0.0, LABEL_OFFSET, 0.5),
+                ..default()
+            });
+        });
+    }
+}
+
+fn update_tank_visuals(
+    mut player_query: Query<(&Player, &Rigidbody, &Health, &Children, &mut Visibility)>,
+    mut hull_query: Query<&mut Transform, (With<Hull>, Without<Turret>)>,
+    mut turret_query: Query<&mut Transform, (With<Turret>, Without<Hull>)>,
+) {
+    for (player, rb, health, children, mut visibility) in &mut player_query {
+        // dead tanks wait for their respawn out of sight
+        if visibility.is_visible != health.is_alive() {
+            visibility.is_visible = health.is_alive();
+        }
+        for child in children.iter() {
+            if let Ok(mut transform) = hull_query.get_mut(*child) {
+                if rb.vel.length() > HULL_TURN_SPEED {
+                    transform.rotation = Quat::from_rotation_z(rb.vel.y.atan2(rb.vel.x));
+                }
+            }
+            if let Ok(mut transform) = turret_query.get_mut(*child) {
+                transform.rotation = Quat::from_rotation_z(player.aim);
+            }
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use bevy::prelude::*;
+
+use crate::playlist::match_entries;
+use crate::{player_color, MatchConfig};
+
+pub const MAX_TEAMS: usize = 4;
+
+const TEAM_COLORS: [Color; MAX_TEAMS] = [
+    Color::rgb(0.9, 0.25, 0.2),
+    Color::rgb(0.2, 0.45, 0.95),
+    Color::rgb(0.95, 0.8, 0.15),
+    Color::rgb(0.25, 0.8, 0.3),
+];
+
+pub fn team_color(team: usize) -> Color {
+    TEAM_COLORS[team % MAX_TEAMS]
+}
+
+const TEAM_NAMES: [&str; MAX_TEAMS] = ["red", "blue", "yellow", "green"];
+
+pub fn team_name(team: usize) -> &'static str {
+    TEAM_NAMES[team % MAX_TEAMS]
+}
+
+// which team every handle plays for, decided when the session is created and
+// fixed for the whole match. empty when no round of the match has teams,
+// friendly fire still counts in co-op rounds where everyone is on one side
+#[derive(Default)]
+pub struct Teams {
+    pub count: usize,
+    // by handle
+    pub of: Vec<usize>,
+    pub friendly_fire: bool,
+}
+
+impl Teams {
+    // round robin over the handles, so team sizes differ by one at most
+    pub fn for_match(num_players: usize, config: &MatchConfig) -> Self {
+        let has_teams = match_entries(config).iter().any(|e| e.mode.has_teams());
+        if !has_teams {
+            return Self {
+                friendly_fire: config.friendly_fire,
+                ..default()
+            };
+        }
+        let count = config.teams.clamp(2, MAX_TEAMS);
+        Self {
+            count,
+            of: (0..num_players).map(|handle| handle % count).collect(),
+            friendly_fire: config.friendly_fire,
+        }
+    }
+
+    pub fn team(&self, handle: usize) -> Option<usize> {
+        self.of.get(handle).copied()
+    }
+
+    // two different tanks on the same team
+    pub fn allies(&self, a: usize, b: usize) -> bool {
+        a != b && self.team(a).is_some() && self.team(a) == self.team(b)
+    }
+
+    // tanks, markers and names take the color of their team
+    pub fn color(&self, handle: usize) -> Color {
+        self.team(handle).map_or(player_color(handle), team_color)
+    }
+
+    // the kills of every team, from (handle, kills)
+    pub fn scores(&self, kills: impl Iterator<Item = (usize, i32)>) -> Vec<i32> {
+        let mut scores = vec![0; self.count];
+        for (handle, kills) in kills {
+            if let Some(team) = self.team(handle) {
+                scores[team] += kills;
+            }
+        }
+        scores
+    }
+}